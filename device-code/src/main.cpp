@@ -17,11 +17,15 @@
 
 #include <PubSubClient.h>
 
+ADC_MODE(ADC_VCC);  // ESP.getVcc() reads the supply voltage, there's nothing on the ADC pin
+
 const char* STASSID = "";
 const char* STAPSK = "";
 const char* NTP_SERVER = "uk.pool.ntp.org";
 const char* NTP_TZ = "BST0GMT,M3.2.0/2:00:00,M11.1.0/2:00:00";
 const uint64_t DEEPSLEEP_TIME = 6E8;  // in us, equal to 10 mins
+const int64_t EARLIEST_SYNCED_TIME = 1577836800;  // 2020-01-01, earlier times mean NTP hasn't synced
+const uint32_t NTP_WAIT_MS = 20000;  // how long to wait for NTP before giving up until the next wake
 
 //time_t now;
 //tm time_struct;
@@ -34,12 +38,50 @@ DHT_Unified dht(4, DHT22);  // Use GPIO pin 4
 WiFiClient mqttSocket;
 IPAddress mqtt_server_address(192, 168, 1, 126);
 PubSubClient mqttClient(mqtt_server_address, 1883, mqttSocket);
-const char* mqttCLientId = "WeatherStation";
+// Unique per station: the server files readings under the station named in the topic, and the broker
+// disconnects clients that share an ID
+#define STATION_ID "garden"
+const char* mqttCLientId = "WeatherStation-" STATION_ID;
+const char* MQTT_TOPIC = "weather/" STATION_ID "/reading";
+const char* MQTT_TELEMETRY_TOPIC = "weather/" STATION_ID "/telemetry";
+const char* FIRMWARE_VERSION = "0.4.0";  // at most 16 characters
+
+// Protocol versions are listed in interfaces.md, bump PROTOCOL_VERSION whenever the layout changes
+const uint32_t MAGIC_VALUE = 0x54414557;  // "WEAT" in little-endian
+const uint32_t TELEMETRY_MAGIC_VALUE = 0x4C455457;  // "WTEL" in little-endian
+const uint8_t PROTOCOL_VERSION = 3;
+const uint8_t FLAG_CRC32 = 0x01;  // message ends with a CRC-32 trailer
+
+// Bits of SensorPayload.valid, set when the sensor's readings can be trusted
+const uint8_t VALID_BME280 = 0x01;
+const uint8_t VALID_SGP30 = 0x02;
+const uint8_t VALID_DHT22_TEMPERATURE = 0x04;
+const uint8_t VALID_DHT22_HUMIDITY = 0x08;
 
 typedef struct __attribute__((packed)) sensor_message_header_t {
   uint32_t magic_value;
+  uint8_t version;
+  uint8_t flags;
+  uint32_t sequence;  // counts up with every message, the server drops repeats
 } SensorMessageHeader;
 
+// Kept in RTC memory, which survives deep sleep but not a power cycle
+typedef struct __attribute__((packed)) sequence_state_t {
+  uint32_t magic;
+  uint32_t sequence;
+} SequenceState;
+
+const uint32_t SEQUENCE_STATE_MAGIC = 0x53455131;  // "SEQ1"
+
+// Kept in RTC memory after the SequenceState
+typedef struct __attribute__((packed)) wake_state_t {
+  uint32_t magic;
+  uint32_t wake_count;
+} WakeState;
+
+const uint32_t WAKE_STATE_MAGIC = 0x57414b31;  // "WAK1"
+const uint32_t WAKE_STATE_OFFSET = sizeof(SequenceState) / 4;  // in 4 byte blocks
+
 typedef struct __attribute__((packed)) sensor_payload_t {
   int64_t posix_time;
   float bmeTemperature;
@@ -49,13 +91,30 @@ typedef struct __attribute__((packed)) sensor_payload_t {
   uint16_t TVOC;  // in ppb
   float DHT22Temperature;
   float DHT22Humidity;
+  uint8_t valid;  // VALID_* bitmask
 } SensorPayload;
 
 typedef struct __attribute__((packed)) message_t {
   SensorMessageHeader header;
   SensorPayload payload;
+  uint32_t crc32;  // of header and payload, present as FLAG_CRC32 is set
 } SensorMessage;
 
+typedef struct __attribute__((packed)) telemetry_payload_t {
+  int64_t posix_time;
+  uint16_t battery_millivolts;
+  int8_t wifi_rssi;  // in dBm
+  uint8_t reset_reason;  // rst_info.reason
+  uint32_t wake_count;  // since power on
+  char firmware_version[16];  // NUL padded
+} TelemetryPayload;
+
+typedef struct __attribute__((packed)) telemetry_message_t {
+  SensorMessageHeader header;  // magic_value = TELEMETRY_MAGIC_VALUE
+  TelemetryPayload payload;
+  uint32_t crc32;
+} TelemetryMessage;
+
 // Set the update timer for NTP
 uint32_t sntp_update_delay_MS_rfc_not_less_than_15000 () {
   return 5 * 60 * 1000UL; // 5 min
@@ -75,6 +134,91 @@ uint32_t getAbsoluteHumidity(float temperature, float humidity) {
     return absoluteHumidityScaled;
 }
 
+/// Calculate the CRC-32 (IEEE 802.3, as used by zlib) of a buffer
+uint32_t crc32_ieee(const uint8_t* data, size_t length) {
+  uint32_t crc = 0xFFFFFFFF;
+  for (size_t i = 0; i < length; i++) {
+    crc ^= data[i];
+    for (uint8_t bit = 0; bit < 8; bit++) {
+      crc = (crc >> 1) ^ (0xEDB88320 & (0 - (crc & 1)));
+    }
+  }
+  return ~crc;
+}
+
+/*!
+ * @brief Waits up to NTP_WAIT_MS for NTP to set the clock
+ * @returns True if the clock has synced
+*/
+bool wait_for_time_sync() {
+  const uint32_t start = millis();
+  while (get_posix_time() < EARLIEST_SYNCED_TIME) {
+    if (millis() - start > NTP_WAIT_MS) {
+      return false;
+    }
+    delay(100);
+  }
+  return true;
+}
+
+/*!
+ * @brief Whether the sequence counter survived in RTC memory, rather than being lost to a power cycle
+*/
+bool sequence_seeded() {
+  SequenceState state;
+  ESP.rtcUserMemoryRead(0, (uint32_t*)&state, sizeof(state));
+  return state.magic == SEQUENCE_STATE_MAGIC;
+}
+
+/*!
+ * @brief Returns the next message sequence number, saving it in RTC memory
+ *
+ * After a power cycle the RTC memory is lost, so the counter restarts from the current POSIX time.
+ * As the station sends far less than one message a second, that is always ahead of any sequence
+ * number it sent before. Only call this once the clock has synced (see `sequence_seeded`), or the
+ * counter restarts near 0 and the server drops every message as a stale replay.
+*/
+uint32_t next_sequence() {
+  SequenceState state;
+  ESP.rtcUserMemoryRead(0, (uint32_t*)&state, sizeof(state));
+  if (state.magic != SEQUENCE_STATE_MAGIC) {
+    state.magic = SEQUENCE_STATE_MAGIC;
+    state.sequence = (uint32_t) get_posix_time();
+  }
+  state.sequence++;
+  ESP.rtcUserMemoryWrite(0, (uint32_t*)&state, sizeof(state));
+  return state.sequence;
+}
+
+/*!
+ * @brief Counts this wake, returning the number of wakes since power on
+*/
+uint32_t count_wake() {
+  WakeState state;
+  ESP.rtcUserMemoryRead(WAKE_STATE_OFFSET, (uint32_t*)&state, sizeof(state));
+  if (state.magic != WAKE_STATE_MAGIC) {
+    state.magic = WAKE_STATE_MAGIC;
+    state.wake_count = 0;
+  }
+  state.wake_count++;
+  ESP.rtcUserMemoryWrite(WAKE_STATE_OFFSET, (uint32_t*)&state, sizeof(state));
+  return state.wake_count;
+}
+
+/*!
+ * @brief Reads the state of the ESP8266 itself into a TelemetryPayload
+*/
+TelemetryPayload measure_telemetry() {
+  TelemetryPayload payload = {};
+  payload.posix_time = get_posix_time();
+  payload.battery_millivolts = ESP.getVcc();
+  payload.wifi_rssi = (int8_t) WiFi.RSSI();
+  payload.reset_reason = (uint8_t) ESP.getResetInfoPtr()->reason;
+  payload.wake_count = count_wake();
+  strncpy(payload.firmware_version, FIRMWARE_VERSION, sizeof(payload.firmware_version));
+  return payload;
+}
+
 // Print the time
 void print_time() {
   time_t now;
@@ -257,6 +401,9 @@ SensorPayload measure_sensors() {
   payload.bmeTemperature = bme.readTemperature();  // in °C
   payload.bmeHumidity = bme.readHumidity();  // in %
   payload.bmePressure = bme.readPressure();  // in Pa
+  if(!isnan(payload.bmeTemperature) && !isnan(payload.bmeHumidity) && !isnan(payload.bmePressure)) {
+    payload.valid |= VALID_BME280;
+  }
 
   // SGP30 measurement
   sgp.setHumidity(getAbsoluteHumidity(payload.bmeTemperature, payload.bmeHumidity));
@@ -266,6 +413,7 @@ SensorPayload measure_sensors() {
   } else {
     payload.eCO2 = sgp.eCO2;
     payload.TVOC = sgp.TVOC;
+    payload.valid |= VALID_SGP30;
   }
 
   // DHT22 measurement
@@ -273,10 +421,12 @@ SensorPayload measure_sensors() {
   dht.temperature().getEvent(&dht_measurement);
   if(!isnan(dht_measurement.temperature)) {
     payload.DHT22Temperature = dht_measurement.temperature;
+    payload.valid |= VALID_DHT22_TEMPERATURE;
   }
   dht.humidity().getEvent(&dht_measurement);
   if(!isnan(dht_measurement.relative_humidity)) {
     payload.DHT22Humidity = dht_measurement.relative_humidity;
+    payload.valid |= VALID_DHT22_HUMIDITY;
   }
 
   payload.posix_time = get_posix_time();
@@ -323,9 +473,16 @@ void setup() {
   // for debugging
   // measure_print_sensors();
 
-  const SensorMessageHeader sensor_header = {0x12345678};  // A magic word
+  // The counter can only be reseeded after a power cycle once the clock is right
+  if (!sequence_seeded() && !wait_for_time_sync()) {
+    Serial.println("Clock hasn't synced, so the sequence number can't be seeded, retrying on next cycle");
+    start_sleep();
+  }
+
+  const SensorMessageHeader sensor_header = {MAGIC_VALUE, PROTOCOL_VERSION, FLAG_CRC32, next_sequence()};
   const SensorPayload sensor_values = measure_sensors();
-  const SensorMessage sensor_message = {sensor_header, sensor_values};
+  SensorMessage sensor_message = {sensor_header, sensor_values, 0};
+  sensor_message.crc32 = crc32_ieee((const uint8_t*)&sensor_message, sizeof(sensor_message) - sizeof(uint32_t));
   Serial.print("eCO2 value: ");
   Serial.println(sensor_values.eCO2);
   Serial.print("TVOC value: ");
@@ -334,11 +491,18 @@ void setup() {
 
   yield();
 
-  bool publish_success = mqttClient.publish("weather/test", (byte*)&sensor_message, sizeof(sensor_message));
+  bool publish_success = mqttClient.publish(MQTT_TOPIC, (byte*)&sensor_message, sizeof(sensor_message));
   if(!publish_success){
     Serial.println("MQTT publish unsuccessful");
   }
 
+  const SensorMessageHeader telemetry_header = {TELEMETRY_MAGIC_VALUE, PROTOCOL_VERSION, FLAG_CRC32, next_sequence()};
+  TelemetryMessage telemetry_message = {telemetry_header, measure_telemetry(), 0};
+  telemetry_message.crc32 = crc32_ieee((const uint8_t*)&telemetry_message, sizeof(telemetry_message) - sizeof(uint32_t));
+  if(!mqttClient.publish(MQTT_TELEMETRY_TOPIC, (byte*)&telemetry_message, sizeof(telemetry_message))){
+    Serial.println("MQTT telemetry publish unsuccessful");
+  }
+
   yield();  // Without this, the message isn't actually sent before sleep!
   digitalWrite(LED_BUILTIN, LOW);
 
@@ -0,0 +1,107 @@
+//! Compares ways of writing readings to the database. The database file is made in the package
+//! directory, so on the Pi it shows the cost of syncing to the SD card:
+//!
+//! ```text
+//! cargo bench --bench inserts -- 2000
+//! ```
+//!
+//! The argument is the number of readings written by each strategy, 1000 by default.
+
+use message_parser::clock::ClockConfig;
+use message_parser::database::{CommitPolicy, IngestSettings, WeatherDatabase};
+use message_parser::mqtt_message::SensorMessagePayload;
+use std::time::{Duration, Instant};
+
+const BENCH_DATABASE: &str = "bench-inserts.db";
+
+/// Writes `count` readings with `insert`, on a new database, returning how long it took
+fn time_inserts(count: u32, mut insert: impl FnMut(&IngestSettings, u32)) -> Duration {
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", BENCH_DATABASE, suffix));
+    }
+    WeatherDatabase::new(BENCH_DATABASE)
+        .unwrap()
+        .migrate("bench")
+        .unwrap();
+
+    let clock = ClockConfig::default();
+    let settings = IngestSettings {
+        altitude: Some(50.0),
+        clock: &clock,
+    };
+    let start = Instant::now();
+    for sequence in 0..count {
+        insert(&settings, sequence);
+    }
+    start.elapsed()
+}
+
+/// What `on_message` did for each message: open a connection, insert, and record the sequence
+fn connection_per_message(settings: &IngestSettings, sequence: u32) {
+    let mut database = WeatherDatabase::new(BENCH_DATABASE).unwrap();
+    database
+        .insert_sensor_data("bench", settings, &SensorMessagePayload::create_dummy())
+        .unwrap();
+    database.set_last_sequence("bench", sequence).unwrap();
+}
+
+fn main() {
+    let count = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(1000);
+    let payload = SensorMessagePayload::create_dummy();
+
+    let report = |name: &str, elapsed: Duration| {
+        println!(
+            "{:<32} {:>8.1} ms total {:>8.1} µs/reading",
+            name,
+            elapsed.as_secs_f64() * 1e3,
+            elapsed.as_secs_f64() * 1e6 / count as f64
+        );
+    };
+
+    report(
+        "connection per message",
+        time_inserts(count, connection_per_message),
+    );
+
+    let mut database = None;
+    report(
+        "one connection, commit each",
+        time_inserts(count, |settings, sequence| {
+            let database =
+                database.get_or_insert_with(|| WeatherDatabase::new(BENCH_DATABASE).unwrap());
+            database
+                .insert_sensor_data("bench", settings, &payload)
+                .unwrap();
+            database.set_last_sequence("bench", sequence).unwrap();
+        }),
+    );
+    drop(database);
+
+    let mut database = None;
+    report(
+        "one connection, batched commits",
+        time_inserts(count, |settings, sequence| {
+            let database = database.get_or_insert_with(|| {
+                WeatherDatabase::new(BENCH_DATABASE)
+                    .unwrap()
+                    .with_commit_policy(CommitPolicy::default())
+            });
+            database
+                .insert_sensor_data("bench", settings, &payload)
+                .unwrap();
+            database.set_last_sequence("bench", sequence).unwrap();
+        }) + {
+            // The final commit is part of the cost
+            let start = Instant::now();
+            database.take().unwrap().flush().unwrap();
+            start.elapsed()
+        },
+    );
+
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", BENCH_DATABASE, suffix));
+    }
+}
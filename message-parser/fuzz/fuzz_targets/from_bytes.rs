@@ -0,0 +1,13 @@
+//! Feeds arbitrary bytes to the binary message decoder. Run with `cargo fuzz run from_bytes`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use message_parser::mqtt_message::MqttMessage;
+
+fuzz_target!(|data: &[u8]| {
+    // Decoding must never panic, and anything that decodes must encode back to the same bytes
+    if let Ok(message) = MqttMessage::from_bytes(data) {
+        assert_eq!(message.to_bytes(), data);
+    }
+});
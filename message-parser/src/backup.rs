@@ -0,0 +1,347 @@
+//! Snapshots of the database on a schedule, so a dead SD card doesn't take every reading with it.
+//!
+//! Backups are taken with SQLite's online backup API on a thread of their own, so the parser
+//! keeps storing readings meanwhile. Each is checked with `PRAGMA integrity_check` before it is
+//! kept, then older backups are thinned out to the newest of each recent day, week and month.
+
+use crate::database::{self, WeatherDatabase};
+use crate::migrations::{self, SCHEMA_VERSION};
+use crate::timestamp::{civil_from_days, now, parse_iso8601};
+use rusqlite::{Connection, OpenFlags};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+pub const USAGE: &str = "Usage: message_parser restore BACKUP
+
+Replaces the database at the config's database_path with the backup file BACKUP, once the backup
+has passed an integrity check. Stop the parser first, or it may go on writing to the database it
+had open. Backups are taken every interval_hours into the [backup] directory, or straight away with
+`message_parser --backup [DIRECTORY]`.";
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/// Backups that failed are tried again after this long, rather than after `interval_hours`
+const RETRY_SECONDS: i64 = 3600;
+
+/// Start and end of a backup's file name, around its time as e.g. `20250315T201932Z`
+const NAME_PREFIX: &str = "weather-";
+const NAME_SUFFIX: &str = ".db";
+
+/// Where to keep backups, how often to take them, and how many to keep
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BackupConfig {
+    /// Directory the backups are written to, ideally on another drive. Without one, backups are
+    /// only taken by `--backup`, into the directory given.
+    pub directory: Option<String>,
+    /// Time between backups
+    pub interval_hours: u64,
+    /// Days, ISO weeks and calendar months whose newest backup is kept, counting back from the
+    /// newest backup
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            interval_hours: 24,
+            daily: 7,
+            weekly: 4,
+            monthly: 12,
+        }
+    }
+}
+
+/// Why a backup couldn't be taken or restored
+#[derive(Debug)]
+pub enum BackupError {
+    Database(rusqlite::Error),
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// `PRAGMA integrity_check` found problems in the file at `path`
+    Corrupt {
+        path: PathBuf,
+        problems: Vec<String>,
+    },
+    /// The backup's schema is newer than this parser knows
+    TooNew {
+        path: PathBuf,
+        version: u32,
+    },
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupError::Database(err) => write!(f, "Database error: {}", err),
+            BackupError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            BackupError::Corrupt { path, problems } => write!(
+                f,
+                "{} failed its integrity check: {}",
+                path.display(),
+                problems.join("; ")
+            ),
+            BackupError::TooNew { path, version } => write!(
+                f,
+                "{} has schema version {}, newer than this parser supports ({})",
+                path.display(),
+                version,
+                SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+impl From<rusqlite::Error> for BackupError {
+    fn from(err: rusqlite::Error) -> Self {
+        BackupError::Database(err)
+    }
+}
+
+/// Attaches `path` to an I/O error
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> BackupError + '_ {
+    move |error| BackupError::Io {
+        path: path.to_path_buf(),
+        error,
+    }
+}
+
+/// A backup taken, and the older ones that rotation deleted
+#[derive(Debug)]
+pub struct BackupReport {
+    pub path: PathBuf,
+    pub removed: Vec<PathBuf>,
+}
+
+impl fmt::Display for BackupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Backed up database to {}", self.path.display())?;
+        if !self.removed.is_empty() {
+            write!(f, ", removed {} older backups", self.removed.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// The file name of a backup taken at `time`
+pub fn backup_name(time: i64) -> String {
+    let (year, month, day) = civil_from_days(time.div_euclid(SECONDS_PER_DAY));
+    let seconds = time.rem_euclid(SECONDS_PER_DAY);
+    format!(
+        "{}{:04}{:02}{:02}T{:02}{:02}{:02}Z{}",
+        NAME_PREFIX,
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        NAME_SUFFIX
+    )
+}
+
+/// When the backup named `name` was taken, if it is a backup's name
+fn backup_time(name: &str) -> Option<i64> {
+    let time = name.strip_prefix(NAME_PREFIX)?.strip_suffix(NAME_SUFFIX)?;
+    if time.len() != 16 || !time.is_ascii() || !time.ends_with('Z') {
+        return None;
+    }
+    parse_iso8601(&format!(
+        "{}-{}-{}{}:{}:{}Z",
+        &time[..4],
+        &time[4..6],
+        &time[6..8],
+        &time[8..11],
+        &time[11..13],
+        &time[13..15],
+    ))
+}
+
+/// The backups in `directory` and when they were taken, newest first. Other files are ignored.
+pub fn list_backups(directory: &Path) -> Result<Vec<(i64, PathBuf)>, BackupError> {
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(directory).map_err(io_error(directory))? {
+        let entry = entry.map_err(io_error(directory))?;
+        if let Some(time) = entry.file_name().to_str().and_then(backup_time) {
+            backups.push((time, entry.path()));
+        }
+    }
+    backups.sort_by(|a, b| b.cmp(a));
+    Ok(backups)
+}
+
+/// Which of the backups taken at `times` to keep: the newest, and the newest in each of the last
+/// `config.daily` days, `config.weekly` ISO weeks and `config.monthly` months that have one
+pub fn backups_to_keep(times: &[i64], config: &BackupConfig) -> HashSet<i64> {
+    let mut newest_first = times.to_vec();
+    newest_first.sort_unstable_by(|a, b| b.cmp(a));
+    let day = |time: i64| time.div_euclid(SECONDS_PER_DAY);
+    // 1970-01-01 was a Thursday, so this counts weeks from Monday
+    let week = |time: i64| (day(time) + 3).div_euclid(7);
+    let month = |time: i64| {
+        let (year, month, _) = civil_from_days(day(time));
+        year * 12 + month
+    };
+    let periods: [(usize, &dyn Fn(i64) -> i64); 3] = [
+        (config.daily, &day),
+        (config.weekly, &week),
+        (config.monthly, &month),
+    ];
+
+    let mut keep: HashSet<i64> = newest_first.first().copied().into_iter().collect();
+    for (count, period) in periods {
+        let mut periods_kept = Vec::new();
+        for &time in &newest_first {
+            if periods_kept.len() == count {
+                break;
+            }
+            if !periods_kept.contains(&period(time)) {
+                periods_kept.push(period(time));
+                keep.insert(time);
+            }
+        }
+    }
+    keep
+}
+
+/// Deletes the backups in `directory` that `backups_to_keep` doesn't keep, returning their paths
+pub fn rotate(directory: &Path, config: &BackupConfig) -> Result<Vec<PathBuf>, BackupError> {
+    let backups = list_backups(directory)?;
+    let times: Vec<i64> = backups.iter().map(|(time, _)| *time).collect();
+    let keep = backups_to_keep(&times, config);
+    let mut removed = Vec::new();
+    for (time, path) in backups {
+        if !keep.contains(&time) {
+            fs::remove_file(&path).map_err(io_error(&path))?;
+            removed.push(path);
+        }
+    }
+    Ok(removed)
+}
+
+/// Checks the database at `path` with `PRAGMA integrity_check`, without changing it
+pub fn check_integrity(path: &Path) -> Result<(), BackupError> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let problems = database::integrity_check(&conn)?;
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(BackupError::Corrupt {
+            path: path.to_path_buf(),
+            problems,
+        })
+    }
+}
+
+/// Backs `database` up into `directory` as a file named for the current time, and checks the
+/// copy's integrity. The file only appears under that name once it has passed.
+pub fn backup(database: &mut WeatherDatabase, directory: &Path) -> Result<PathBuf, BackupError> {
+    fs::create_dir_all(directory).map_err(io_error(directory))?;
+    let path = directory.join(backup_name(now()));
+    let partial = path.with_extension("db.partial");
+    // Left behind by a backup that was interrupted
+    let _ = fs::remove_file(&partial);
+
+    let result = database
+        .backup_to(&partial)
+        .map_err(BackupError::from)
+        .and_then(|()| check_integrity(&partial));
+    if let Err(err) = result {
+        let _ = fs::remove_file(&partial);
+        return Err(err);
+    }
+    fs::rename(&partial, &path).map_err(io_error(&path))?;
+    Ok(path)
+}
+
+/// Backs up the database at `database_path` into `directory`, then rotates the backups there
+pub fn backup_and_rotate(
+    database_path: &str,
+    directory: &Path,
+    config: &BackupConfig,
+) -> Result<BackupReport, BackupError> {
+    let mut database = WeatherDatabase::new(database_path)?;
+    let path = backup(&mut database, directory)?;
+    let removed = rotate(directory, config)?;
+    Ok(BackupReport { path, removed })
+}
+
+/// Replaces `database` with the backup at `path`, once the backup has passed its integrity check
+/// and is known to be a schema this parser can use
+pub fn restore(database: &mut WeatherDatabase, path: &Path) -> Result<(), BackupError> {
+    check_integrity(path)?;
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let version = migrations::schema_version(&conn)?;
+    if version > SCHEMA_VERSION {
+        return Err(BackupError::TooNew {
+            path: path.to_path_buf(),
+            version,
+        });
+    }
+    drop(conn);
+    Ok(database.restore_from(path)?)
+}
+
+/// Takes backups by a `BackupConfig` in the background, checking on them at each `poll`
+pub struct BackupScheduler {
+    database_path: String,
+    config: BackupConfig,
+    /// POSIX time the next backup is due
+    next_run: i64,
+    running: Option<JoinHandle<Result<BackupReport, BackupError>>>,
+}
+
+impl BackupScheduler {
+    /// A scheduler for the database at `database_path`, whose first backup is due
+    /// `interval_hours` after the newest already taken
+    pub fn new(database_path: &str, config: &BackupConfig) -> Self {
+        let newest = config
+            .directory
+            .as_deref()
+            .and_then(|directory| list_backups(Path::new(directory)).ok())
+            .and_then(|backups| backups.first().map(|(time, _)| *time));
+        Self {
+            database_path: database_path.to_string(),
+            config: config.clone(),
+            next_run: newest.map_or(0, |time| time + config.interval_hours as i64 * 3600),
+            running: None,
+        }
+    }
+
+    /// Reports on a backup that has finished, and starts one if it is due
+    pub fn poll(&mut self) {
+        let Some(directory) = self.config.directory.clone() else {
+            return;
+        };
+        if let Some(running) = self.running.take_if(|running| running.is_finished()) {
+            match running.join() {
+                Ok(Ok(report)) => println!("{}", report),
+                Ok(Err(err)) => {
+                    eprintln!("Backup failed: {}", err);
+                    self.next_run = self.next_run.min(now() + RETRY_SECONDS);
+                }
+                Err(_) => eprintln!("Backup failed: the backup thread panicked"),
+            }
+        }
+        if self.running.is_none() && now() >= self.next_run {
+            self.next_run = now() + self.config.interval_hours as i64 * 3600;
+            let database_path = self.database_path.clone();
+            let config = self.config.clone();
+            self.running = Some(thread::spawn(move || {
+                backup_and_rotate(&database_path, Path::new(&directory), &config)
+            }));
+        }
+    }
+}
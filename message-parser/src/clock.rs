@@ -0,0 +1,110 @@
+//! Catches measurement times from station clocks that are unsynced or wrong.
+//!
+//! A station that takes its readings before NTP has synced stamps them with a time near 1970, and
+//! a drifting clock stamps them minutes away from when they arrive.
+
+use serde::Deserialize;
+
+/// What to do with a reading whose measurement time can't be trusted
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockPolicy {
+    /// Store the time the reading was received as its measurement time
+    #[default]
+    Substitute,
+    /// Keep the station's measurement time, only flagging the row
+    Flag,
+}
+
+/// Settings for checking station clocks
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ClockConfig {
+    pub policy: ClockPolicy,
+    /// Measurement times before this POSIX time come from a clock that hasn't synced
+    pub earliest_time: i64,
+    /// Largest difference in seconds allowed between a reading's measurement and received times
+    pub max_skew: i64,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            policy: ClockPolicy::Substitute,
+            earliest_time: 1577836800, // 2020-01-01
+            max_skew: 300,
+        }
+    }
+}
+
+/// Why a measurement time can't be trusted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockProblem {
+    /// Before `earliest_time`, so the station's clock never synced
+    Unsynced,
+    /// More than `max_skew` after the reading was received
+    Future,
+    /// More than `max_skew` before the reading was received
+    Behind,
+}
+
+impl ClockProblem {
+    /// Short name stored in the `ClockStatus` column
+    pub fn kind(self) -> &'static str {
+        match self {
+            ClockProblem::Unsynced => "unsynced",
+            ClockProblem::Future => "future",
+            ClockProblem::Behind => "behind",
+        }
+    }
+
+    /// The problem a `ClockStatus` column names, the reverse of `kind`
+    pub fn from_kind(kind: &str) -> Option<Self> {
+        [
+            ClockProblem::Unsynced,
+            ClockProblem::Future,
+            ClockProblem::Behind,
+        ]
+        .into_iter()
+        .find(|problem| problem.kind() == kind)
+    }
+}
+
+/// The result of checking one measurement time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockCheck {
+    /// Received time minus measurement time in seconds, positive when the station is behind
+    pub skew: i64,
+    pub problem: Option<ClockProblem>,
+    /// The measurement time to store, after applying the policy
+    pub measurement_time: i64,
+}
+
+impl ClockConfig {
+    /// Checks a reading measured at `measurement_time` by the station's clock, and received at
+    /// `received_time` by ours.
+    ///
+    /// `buffered` readings, from a batch, may have been held by the station for a while, so only
+    /// the unsynced and future checks apply to them.
+    pub fn check(&self, measurement_time: i64, received_time: i64, buffered: bool) -> ClockCheck {
+        let skew = received_time.saturating_sub(measurement_time);
+        let problem = if measurement_time < self.earliest_time {
+            Some(ClockProblem::Unsynced)
+        } else if -skew > self.max_skew {
+            Some(ClockProblem::Future)
+        } else if skew > self.max_skew && !buffered {
+            Some(ClockProblem::Behind)
+        } else {
+            None
+        };
+        let measurement_time = match (problem, self.policy) {
+            (Some(_), ClockPolicy::Substitute) => received_time,
+            _ => measurement_time,
+        };
+        ClockCheck {
+            skew,
+            problem,
+            measurement_time,
+        }
+    }
+}
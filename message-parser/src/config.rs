@@ -0,0 +1,236 @@
+use crate::backup::BackupConfig;
+use crate::clock::ClockConfig;
+use crate::database::{CommitPolicy, IngestSettings};
+use crate::influx::InfluxConfig;
+use crate::mqtt_message::{MqttMessage, ParseError, PayloadFormat};
+use crate::retention::RetentionConfig;
+use crate::store::StoreBackend;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Settings for the message parser, read from a TOML file.
+///
+/// Every field has a default, so a missing file or a partial file is fine.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Where readings are written, `database_path` or the `influxdb` server
+    pub store: StoreBackend,
+    pub database_path: String,
+    /// How often to commit the readings written to the database
+    pub commit: CommitPolicy,
+    /// Station that readings are filed under when their topic doesn't name one
+    pub default_station: String,
+    pub mqtt: MqttConfig,
+    /// Topics whose payloads aren't the binary struct, or whose readings belong to a fixed station
+    pub topics: Vec<TopicConfig>,
+    /// Settings for individual stations, by station ID
+    pub stations: HashMap<String, StationConfig>,
+    /// Drop messages from stations without an HMAC key, rather than accepting them unauthenticated
+    pub require_hmac: bool,
+    /// How to check and correct the measurement times from station clocks
+    pub clock: ClockConfig,
+    /// How long to keep readings, telemetry and rollups
+    pub retention: RetentionConfig,
+    /// When to back the database up, and how many backups to keep
+    pub backup: BackupConfig,
+    /// The InfluxDB server written to when `store` is `influxdb`
+    pub influxdb: InfluxConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// Must be unique on the broker, so give each parser instance its own
+    pub client_id: String,
+    /// Topics from firmware that predates per-station topics, filed under `default_station`
+    pub legacy_topics: Vec<String>,
+}
+
+/// How to handle messages on topics matching `filter`
+#[derive(Deserialize)]
+pub struct TopicConfig {
+    /// MQTT topic filter, which may use the `+` and `#` wildcards
+    pub filter: String,
+    #[serde(default)]
+    pub format: PayloadFormat,
+    /// Station the readings are filed under, otherwise worked out from the topic
+    pub station: Option<String>,
+}
+
+/// Settings for a single station
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct StationConfig {
+    /// Key for the HMAC-SHA256 tag on the station's messages, written in hex, of at least 16 bytes.
+    /// Once set, untagged messages from the station are dropped.
+    #[serde(deserialize_with = "deserialize_hex_key")]
+    pub hmac_key: Option<Vec<u8>>,
+    /// Height of the station's BME280 above sea level in metres, to reduce its pressure to sea level
+    pub altitude: Option<f32>,
+}
+
+/// Shortest HMAC key accepted, in bytes. Shorter keys, down to an empty one, would make the tags
+/// easy to forge.
+const MIN_HMAC_KEY_SIZE: usize = 16;
+
+/// Reads an optional key written as a hex string, of at least `MIN_HMAC_KEY_SIZE` bytes
+fn deserialize_hex_key<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<u8>>, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(serde::de::Error::custom(
+            "HMAC key must be an even number of hex digits",
+        ));
+    }
+    if hex.len() < MIN_HMAC_KEY_SIZE * 2 {
+        return Err(serde::de::Error::custom(format!(
+            "HMAC key must be at least {} bytes ({} hex digits)",
+            MIN_HMAC_KEY_SIZE,
+            MIN_HMAC_KEY_SIZE * 2
+        )));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map(Some)
+        .map_err(|_| serde::de::Error::custom("HMAC key must be hex digits"))
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            store: StoreBackend::default(),
+            database_path: "database.db".to_string(),
+            commit: CommitPolicy::default(),
+            default_station: "default".to_string(),
+            mqtt: MqttConfig::default(),
+            topics: Vec::new(),
+            stations: HashMap::new(),
+            require_hmac: false,
+            clock: ClockConfig::default(),
+            retention: RetentionConfig::default(),
+            backup: BackupConfig::default(),
+            influxdb: InfluxConfig::default(),
+        }
+    }
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "RpiServer".to_string(),
+            legacy_topics: vec!["weather/station".to_string(), "weather/test".to_string()],
+        }
+    }
+}
+
+/// Topic filter matching every station's readings, `weather/<station>/reading`
+pub const STATION_TOPIC_FILTER: &str = "weather/+/reading";
+
+/// Topic filter matching every station's device telemetry, `weather/<station>/telemetry`
+pub const TELEMETRY_TOPIC_FILTER: &str = "weather/+/telemetry";
+
+impl Config {
+    /// Reads the config from `path`, or uses the defaults if the file doesn't exist
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        if !Path::new(path).exists() {
+            eprintln!("No config file at '{}', using defaults", path);
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// Works out which station published on `topic`, and the format of its payload.
+    ///
+    /// The first entry in `topics` with a matching filter decides. Topics without an entry carry
+    /// the binary format.
+    pub fn route<'a>(&'a self, topic: &'a str) -> (&'a str, PayloadFormat) {
+        match self
+            .topics
+            .iter()
+            .find(|entry| topic_matches(&entry.filter, topic))
+        {
+            Some(entry) => (
+                entry
+                    .station
+                    .as_deref()
+                    .unwrap_or_else(|| self.station_for_topic(topic)),
+                entry.format,
+            ),
+            None => (self.station_for_topic(topic), PayloadFormat::Binary),
+        }
+    }
+
+    /// Decodes a message from `station` with the decoder for `format`, authenticating it with the
+    /// station's HMAC key
+    pub fn decode_message(
+        &self,
+        station: &str,
+        format: PayloadFormat,
+        payload: &[u8],
+    ) -> Result<MqttMessage, ParseError> {
+        let key = self.hmac_key(station);
+        if key.is_none() && self.require_hmac {
+            return Err(ParseError::NoHmacKey);
+        }
+        format.decoder().decode(payload, key)
+    }
+
+    /// The HMAC key for `station`, if it has one
+    pub fn hmac_key(&self, station: &str) -> Option<&[u8]> {
+        self.stations
+            .get(station)
+            .and_then(|station| station.hmac_key.as_deref())
+    }
+
+    /// The altitude of `station` in metres, if it is configured
+    pub fn altitude(&self, station: &str) -> Option<f32> {
+        self.stations
+            .get(station)
+            .and_then(|station| station.altitude)
+    }
+
+    /// How to store the readings from `station`
+    pub fn ingest_settings(&self, station: &str) -> IngestSettings<'_> {
+        IngestSettings {
+            altitude: self.altitude(station),
+            clock: &self.clock,
+        }
+    }
+
+    /// Works out which station published on `topic`.
+    ///
+    /// Stations publish to `weather/<station>/reading` and `weather/<station>/telemetry`. Anything
+    /// else, such as the legacy topics, belongs to the default station.
+    fn station_for_topic<'a>(&'a self, topic: &'a str) -> &'a str {
+        let segments: Vec<&str> = topic.split('/').collect();
+        match segments.as_slice() {
+            ["weather", station, "reading" | "telemetry"] if !station.is_empty() => station,
+            _ => &self.default_station,
+        }
+    }
+}
+
+/// Checks whether `topic` matches the MQTT topic filter `filter`
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
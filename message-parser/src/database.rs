@@ -1,24 +1,86 @@
-use crate::mqtt_message::SensorMessagePayload;
-use rusqlite::{types::Value, Connection, Result};
-use std::time::{SystemTime, UNIX_EPOCH};
-
-const CREATE_SQL: &str = "CREATE TABLE weather_data (
-MeasurementTime INTEGER,
-ReceivedTime INTEGER,
-TemperatureBME INTEGER,
-TemperatureDHT22 INTEGER,
-PressureBME INTEGER,
-HumidityBME INTEGER,
-HumidityDHT22 INTEGER,
-eCO2SGP30 INTEGER,
-TVOCSGP30 INTEGER
-)";
+use crate::clock::{ClockConfig, ClockProblem};
+use crate::derived::DerivedQuantities;
+use crate::migrations::{self, SCHEMA_VERSION};
+use crate::mqtt_message::{ParseError, SensorMessagePayload, TelemetryPayload};
+use crate::reading::{self, Reading, ReadingAggregate, SELECT_READING_SQL};
+use crate::rollup;
+use crate::timestamp::now;
+use rusqlite::backup::{Backup, Progress};
+use rusqlite::{params, types::Value, Connection, DatabaseName, OptionalExtension, Result};
+use serde::Deserialize;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
 const INSERT_SQL: &str = "INSERT INTO weather_data (
     MeasurementTime, ReceivedTime, TemperatureBME,
     TemperatureDHT22, PressureBME, HumidityBME, HumidityDHT22, eCO2SGP30,
-    TVOCSGP30
-) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)";
+    TVOCSGP30, Station, DewPoint, AbsoluteHumidity, HeatIndex, Humidex, PressureSeaLevel,
+    ClockStatus, RawMessageId
+) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)";
+
+const INSERT_RAW_MESSAGE_SQL: &str =
+    "INSERT INTO raw_messages (ReceivedTime, Topic, Payload) VALUES (?1, ?2, ?3)";
+
+const SELECT_RAW_MESSAGES_SQL: &str = "SELECT Id, ReceivedTime, Topic, Payload FROM raw_messages
+WHERE ReceivedTime >= ?1 AND ReceivedTime < ?2 ORDER BY Id";
+
+const SELECT_RAW_MESSAGES_BEFORE_SQL: &str = "SELECT Id, ReceivedTime, Topic, Payload
+FROM raw_messages WHERE ReceivedTime < ?1 AND Id < ?2 ORDER BY Id DESC LIMIT ?3";
+
+const INSERT_DEAD_LETTER_SQL: &str = "INSERT INTO dead_letters (
+    ReceivedTime, Topic, Payload, ErrorKind, Error, RawMessageId
+) VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+
+const SELECT_DEAD_LETTER_SQL: &str =
+    "SELECT Id, ReceivedTime, Topic, Payload, ErrorKind, Error, RawMessageId FROM dead_letters";
+
+const READING_EXISTS_SQL: &str =
+    "SELECT EXISTS (SELECT 1 FROM weather_data WHERE Station = ?1 AND MeasurementTime = ?2)";
+
+const TELEMETRY_EXISTS_SQL: &str =
+    "SELECT EXISTS (SELECT 1 FROM device_telemetry WHERE Station = ?1 AND MeasurementTime = ?2)";
+
+/// Whether readings were stored from the archived message ?1, or from another with the same topic
+/// and payload
+const MESSAGE_STORED_SQL: &str = "SELECT EXISTS (SELECT 1 FROM weather_data WHERE RawMessageId IN (
+    SELECT other.Id FROM raw_messages AS message
+    JOIN raw_messages AS other ON other.Topic = message.Topic AND other.Payload = message.Payload
+    WHERE message.Id = ?1
+))";
+
+const SET_SEQUENCE_SQL: &str = "INSERT INTO station_sequence (Station, LastSequence)
+VALUES (?1, ?2)
+ON CONFLICT (Station) DO UPDATE SET LastSequence = excluded.LastSequence";
+
+/// Adds one clock check to a station's statistics. `?2` is the skew, or NULL if it says nothing
+/// about the clock's accuracy.
+const RECORD_CLOCK_SQL: &str = "INSERT INTO station_clock (
+    Station, Samples, SkewSum, MinSkew, MaxSkew, LastSkew, UnsyncedCount, SkewedCount
+) VALUES (?1, ?2 IS NOT NULL, COALESCE(?2, 0), ?2, ?2, ?2, ?3, ?4)
+ON CONFLICT (Station) DO UPDATE SET
+    Samples = Samples + excluded.Samples,
+    SkewSum = SkewSum + excluded.SkewSum,
+    MinSkew = COALESCE(MIN(MinSkew, excluded.MinSkew), MinSkew, excluded.MinSkew),
+    MaxSkew = COALESCE(MAX(MaxSkew, excluded.MaxSkew), MaxSkew, excluded.MaxSkew),
+    LastSkew = COALESCE(excluded.LastSkew, LastSkew),
+    UnsyncedCount = UnsyncedCount + excluded.UnsyncedCount,
+    SkewedCount = SkewedCount + excluded.SkewedCount";
+
+const CLOCK_STATS_SQL: &str = "SELECT Station, Samples, SkewSum, MinSkew, MaxSkew, LastSkew,
+    UnsyncedCount, SkewedCount
+FROM station_clock ORDER BY Station";
+
+const STATION_SUMMARY_SQL: &str = "SELECT Station, COUNT(*), MAX(MeasurementTime)
+FROM weather_data GROUP BY Station ORDER BY Station";
+
+const INSERT_TELEMETRY_SQL: &str = "INSERT INTO device_telemetry (
+    MeasurementTime, ReceivedTime, Station, BatteryMillivolts, WifiRssi, ResetReason, WakeCount,
+    FirmwareVersion
+) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)";
+
+const SELECT_TELEMETRY_SQL: &str = "SELECT Station, ReceivedTime, MeasurementTime,
+    BatteryMillivolts, WifiRssi, ResetReason, WakeCount, FirmwareVersion
+FROM device_telemetry";
 
 const CREATE_SQL_TEST: &str = "CREATE TABLE test_weather_data (
 MeasurementTime INTEGER,
@@ -29,47 +91,247 @@ PressureBME INTEGER,
 HumidityBME INTEGER,
 HumidityDHT22 INTEGER,
 eCO2SGP30 INTEGER,
-TVOCSGP30 INTEGER
+TVOCSGP30 INTEGER,
+Station TEXT
 )";
 
 const INSERT_SQL_TEST: &str = "INSERT INTO test_weather_data (
     MeasurementTime, ReceivedTime, TemperatureBME,
     TemperatureDHT22, PressureBME, HumidityBME, HumidityDHT22, eCO2SGP30,
-    TVOCSGP30
+    TVOCSGP30, Station
 ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)";
 
 const SELECT_SQL_TEST: &str = "SELECT * FROM test_weather_data";
 
+/// How many readings a station has stored, and when the latest was measured
+pub struct StationSummary {
+    pub station: String,
+    pub reading_count: i64,
+    pub last_measurement_time: Option<i64>,
+}
+
+/// How far a station's clock has been from ours, in seconds, with positive skews behind ours
+pub struct ClockStats {
+    pub station: String,
+    /// Readings whose skew counted towards the statistics, leaving out unsynced and batched ones
+    pub samples: i64,
+    pub mean_skew: Option<f64>,
+    pub min_skew: Option<i64>,
+    pub max_skew: Option<i64>,
+    pub last_skew: Option<i64>,
+    /// Readings measured before the station's clock synced
+    pub unsynced_count: i64,
+    /// Readings measured too far ahead of or behind our clock
+    pub skewed_count: i64,
+}
+
+/// How to store the readings from one station
+pub struct IngestSettings<'a> {
+    /// Height of the station above sea level in metres, for its sea-level pressure
+    pub altitude: Option<f32>,
+    pub clock: &'a ClockConfig,
+}
+
+/// A row of 'device_telemetry'
+#[derive(Debug, Clone, PartialEq)]
+pub struct TelemetryRecord {
+    pub station: String,
+    pub received_time: i64,
+    pub telemetry: TelemetryPayload,
+}
+
+impl TelemetryRecord {
+    fn from_row(row: &rusqlite::Row) -> Result<Self> {
+        Ok(TelemetryRecord {
+            station: row.get(0)?,
+            received_time: row.get(1)?,
+            telemetry: TelemetryPayload {
+                posix_time: row.get(2)?,
+                battery_millivolts: row.get(3)?,
+                wifi_rssi: row.get(4)?,
+                reset_reason: row.get(5)?,
+                wake_count: row.get(6)?,
+                firmware_version: row.get(7)?,
+            },
+        })
+    }
+}
+
+/// A row of 'raw_messages', an MQTT payload as it was received
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawMessage {
+    pub id: i64,
+    pub received_time: i64,
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+/// A row of 'dead_letters', an MQTT payload that couldn't be decoded, with why
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadLetter {
+    pub id: i64,
+    pub received_time: i64,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    /// `ParseError::kind` of the error
+    pub error_kind: String,
+    /// The error as it was displayed
+    pub error: String,
+    /// The payload's ID in 'raw_messages', if it was archived
+    pub raw_message: Option<i64>,
+}
+
+impl RawMessage {
+    fn from_row(row: &rusqlite::Row) -> Result<Self> {
+        Ok(RawMessage {
+            id: row.get(0)?,
+            received_time: row.get(1)?,
+            topic: row.get(2)?,
+            payload: row.get(3)?,
+        })
+    }
+}
+
+impl DeadLetter {
+    fn from_row(row: &rusqlite::Row) -> Result<Self> {
+        Ok(DeadLetter {
+            id: row.get(0)?,
+            received_time: row.get(1)?,
+            topic: row.get(2)?,
+            payload: row.get(3)?,
+            error_kind: row.get(4)?,
+            error: row.get(5)?,
+            raw_message: row.get(6)?,
+        })
+    }
+}
+
+/// When to commit the writes grouped into one transaction.
+///
+/// Each commit syncs the Pi's SD card, so grouping writes saves both time and wear. Writes that
+/// haven't been committed are lost if the parser is killed.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CommitPolicy {
+    /// Commit once this many rows are waiting
+    pub max_rows: usize,
+    /// Commit once the first waiting row has waited this many milliseconds
+    pub max_delay_ms: u64,
+}
+
+impl Default for CommitPolicy {
+    fn default() -> Self {
+        Self {
+            max_rows: 100,
+            max_delay_ms: 5000,
+        }
+    }
+}
+
+impl CommitPolicy {
+    /// Commits every write straight away
+    pub fn immediate() -> Self {
+        Self {
+            max_rows: 1,
+            max_delay_ms: 0,
+        }
+    }
+}
+
+/// A connection to the weather database, meant to be kept open for the life of the process.
+///
+/// Writes are grouped into transactions committed by the `CommitPolicy`. Reads on the same
+/// connection see writes that haven't been committed yet, other connections don't.
 pub struct WeatherDatabase {
     conn: Connection,
+    commit_policy: CommitPolicy,
+    /// When the open transaction began, if there is one
+    batch_started: Option<Instant>,
+    /// Rows written in the open transaction
+    pending_rows: usize,
+    /// The message `archive_message` last stored, until the readings decoded from it are stored
+    archived_message: Option<i64>,
 }
 
 impl WeatherDatabase {
-    /// Establishes connection to the database, or creates it if necessary
+    /// Establishes connection to the database, or creates it if necessary.
+    ///
+    /// Switches the database to write-ahead logging, so that readers such as Grafana don't block
+    /// the parser, and the parser doesn't block them. Writes are committed straight away until
+    /// `with_commit_policy` says otherwise.
     pub fn new(database_path: &str) -> Result<Self> {
         let conn = Connection::open(database_path)?;
-        Ok(Self { conn })
+        // Only takes effect on a new database, `vacuum` converts older ones
+        conn.pragma_update(None, "auto_vacuum", "INCREMENTAL")?;
+        // In-memory databases stay in "memory" mode, which is fine
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        // Safe with WAL, a power cut can only lose the last commits rather than corrupt the file
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Ok(Self {
+            conn,
+            commit_policy: CommitPolicy::immediate(),
+            batch_started: None,
+            pending_rows: 0,
+            archived_message: None,
+        })
     }
 
-    /// Creates a new table called 'weather_data' in the database.
-    ///
-    /// Creates `weather_data`, only if it does not already exist. Also creates a test table
-    /// `test_weather_data`, overwriting if it already exists.
-    pub fn create_tables(&self) -> Result<()> {
-        // Check whether `weather_data` exists
-        let weather_table_exists = self
-            .conn
-            .query_row(
-                "SELECT name FROM sqlite_master WHERE type='table' AND name='weather_data'",
-                [],
-                |_| Ok(true),
-            )
-            .unwrap_or(false);
+    /// Groups writes into transactions committed by `commit_policy`
+    pub fn with_commit_policy(mut self, commit_policy: CommitPolicy) -> Self {
+        self.commit_policy = commit_policy;
+        self
+    }
+
+    /// Runs `write` in the open transaction, beginning one if there isn't one. The writes it makes
+    /// are kept or rolled back together, and count as `rows` towards the commit policy.
+    fn write<T>(&mut self, rows: usize, write: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+        if self.batch_started.is_none() {
+            self.conn.execute_batch("BEGIN")?;
+            self.batch_started = Some(Instant::now());
+        }
+        // Dropping the savepoint on an error rolls back only this write
+        let savepoint = self.conn.savepoint()?;
+        let result = write(&savepoint)?;
+        savepoint.commit()?;
+        self.pending_rows += rows;
+        self.commit_if_due()?;
+        Ok(result)
+    }
 
-        // Create only if it doesn't already exist
-        if !weather_table_exists {
-            println!("No weather_data table found, creating");
-            self.conn.execute(CREATE_SQL, [])?;
+    /// Commits the open transaction if the commit policy says it has waited long enough. Call
+    /// regularly, so writes are committed even when no more arrive.
+    pub fn commit_if_due(&mut self) -> Result<()> {
+        let max_delay = Duration::from_millis(self.commit_policy.max_delay_ms);
+        match self.batch_started {
+            Some(started)
+                if self.pending_rows >= self.commit_policy.max_rows
+                    || started.elapsed() >= max_delay =>
+            {
+                self.flush()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Commits the open transaction, if there is one
+    pub fn flush(&mut self) -> Result<()> {
+        if self.batch_started.is_some() {
+            self.conn.execute_batch("COMMIT")?;
+            self.batch_started = None;
+            self.pending_rows = 0;
+        }
+        Ok(())
+    }
+
+    /// Brings the schema up to date, and recreates the test table.
+    ///
+    /// Applies any migrations the database is missing, creating every table in a new database.
+    /// Readings from before multi-station support are assigned to `default_station`. Also creates
+    /// a test table `test_weather_data`, overwriting if it already exists.
+    pub fn create_tables(&mut self, default_station: &str) -> Result<()> {
+        for description in self.migrate(default_station)? {
+            println!("Migrated database: {}", description);
         }
 
         // Always drop (if exists) and recreate `test_weather_data`
@@ -79,31 +341,610 @@ impl WeatherDatabase {
         Ok(())
     }
 
+    /// The version of the database's schema, see `migrations::SCHEMA_VERSION`
+    pub fn schema_version(&self) -> Result<u32> {
+        migrations::schema_version(&self.conn)
+    }
+
+    /// Applies every migration the database is missing, returning their descriptions
+    pub fn migrate(&mut self, default_station: &str) -> Result<Vec<&'static str>> {
+        self.migrate_to(SCHEMA_VERSION, default_station)
+    }
+
+    /// Applies the migrations up to schema version `target`, returning their descriptions
+    pub fn migrate_to(&mut self, target: u32, default_station: &str) -> Result<Vec<&'static str>> {
+        self.flush()?;
+        migrations::migrate_to(&mut self.conn, target, default_station)
+    }
+
+    /// Stores an MQTT `payload` as it was received on `topic`, in 'raw_messages', returning its ID.
+    ///
+    /// The readings stored next, by `insert_sensor_data` or `insert_sensor_batch`, are taken to be
+    /// decoded from it, so `replace_readings` can replace them if it is decoded again.
+    pub fn archive_message(
+        &mut self,
+        topic: &str,
+        received_time: i64,
+        payload: &[u8],
+    ) -> Result<i64> {
+        let id = self.write(1, |conn| {
+            conn.prepare_cached(INSERT_RAW_MESSAGE_SQL)?.execute((
+                received_time,
+                topic,
+                payload,
+            ))?;
+            Ok(conn.last_insert_rowid())
+        })?;
+        self.archived_message = Some(id);
+        Ok(id)
+    }
+
+    /// The messages in 'raw_messages' received from `start` up to `end`, in the order received
+    pub fn raw_messages(&self, start: i64, end: i64) -> Result<Vec<RawMessage>> {
+        self.conn
+            .prepare(SELECT_RAW_MESSAGES_SQL)?
+            .query_map((start, end), RawMessage::from_row)?
+            .collect()
+    }
+
+    /// At most `limit` of the messages in 'raw_messages' received before `end`, with IDs below
+    /// `before_id`, the latest received first. Pass the ID of the last one returned to page back
+    /// through the archive.
+    pub fn raw_messages_before(
+        &self,
+        end: i64,
+        before_id: i64,
+        limit: usize,
+    ) -> Result<Vec<RawMessage>> {
+        self.conn
+            .prepare_cached(SELECT_RAW_MESSAGES_BEFORE_SQL)?
+            .query_map((end, before_id, limit as i64), RawMessage::from_row)?
+            .collect()
+    }
+
+    /// Replaces the readings stored from the archived `message` with `payloads` from `station`,
+    /// decoded from it again. Returns how many readings were replaced.
+    ///
+    /// The new readings keep the message's received time, and their measurement times are checked
+    /// against it. `buffered` is as for `ClockConfig::check`. The station's clock statistics are left
+    /// alone. So are the rollups, which still summarise the replaced readings until
+    /// `rebuild_rollups` is called once every message is done.
+    pub fn replace_readings(
+        &mut self,
+        message: &RawMessage,
+        station: &str,
+        settings: &IngestSettings,
+        payloads: &[SensorMessagePayload],
+        buffered: bool,
+    ) -> Result<usize> {
+        self.write(payloads.len(), |conn| {
+            let replaced = conn
+                .prepare_cached("DELETE FROM weather_data WHERE RawMessageId = ?1")?
+                .execute([message.id])?;
+            insert_checked_rows(
+                conn,
+                station,
+                settings,
+                payloads,
+                buffered,
+                message.received_time,
+                Some(message.id),
+                false,
+            )?;
+            Ok(replaced)
+        })
+    }
+
+    /// Stores a payload that couldn't be decoded in 'dead_letters', with the `error` it was
+    /// rejected for, returning its ID. It is linked to the message `archive_message` last stored.
+    pub fn insert_dead_letter(
+        &mut self,
+        topic: &str,
+        received_time: i64,
+        payload: &[u8],
+        error: &ParseError,
+    ) -> Result<i64> {
+        let raw_message = self.archived_message.take();
+        self.write(1, |conn| {
+            conn.prepare_cached(INSERT_DEAD_LETTER_SQL)?.execute((
+                received_time,
+                topic,
+                payload,
+                error.kind(),
+                error.to_string(),
+                raw_message,
+            ))?;
+            Ok(conn.last_insert_rowid())
+        })
+    }
+
+    /// The dead letters received from `start` up to `end`, in the order received
+    pub fn dead_letters(&self, start: i64, end: i64) -> Result<Vec<DeadLetter>> {
+        self.conn
+            .prepare(&format!(
+                "{} WHERE ReceivedTime >= ?1 AND ReceivedTime < ?2 ORDER BY Id",
+                SELECT_DEAD_LETTER_SQL
+            ))?
+            .query_map((start, end), DeadLetter::from_row)?
+            .collect()
+    }
+
+    /// The dead letter with ID `id`, if there is one
+    pub fn dead_letter(&self, id: i64) -> Result<Option<DeadLetter>> {
+        self.conn
+            .query_row(
+                &format!("{} WHERE Id = ?1", SELECT_DEAD_LETTER_SQL),
+                [id],
+                DeadLetter::from_row,
+            )
+            .optional()
+    }
+
+    /// Records the `error` a dead letter was rejected for when last tried
+    pub fn set_dead_letter_error(&mut self, id: i64, error: &ParseError) -> Result<()> {
+        self.write(1, |conn| {
+            conn.prepare_cached(
+                "UPDATE dead_letters SET ErrorKind = ?2, Error = ?3 WHERE Id = ?1",
+            )?
+            .execute((id, error.kind(), error.to_string()))?;
+            Ok(())
+        })
+    }
+
+    /// Deletes the dead letter with ID `id`, once its payload has been stored
+    pub fn remove_dead_letter(&mut self, id: i64) -> Result<()> {
+        self.write(1, |conn| {
+            conn.prepare_cached("DELETE FROM dead_letters WHERE Id = ?1")?
+                .execute([id])?;
+            Ok(())
+        })
+    }
+
+    /// Deletes the dead letters of the archived message with ID `raw_message`, once its readings
+    /// have been stored, returning how many there were
+    pub fn remove_dead_letters_of(&mut self, raw_message: i64) -> Result<usize> {
+        self.write(1, |conn| {
+            conn.prepare_cached("DELETE FROM dead_letters WHERE RawMessageId = ?1")?
+                .execute([raw_message])
+        })
+    }
+
+    /// Inserts `payloads` from `station` that were received at `received_time` but are only being
+    /// stored now, such as a dead letter decoded after a fix. `raw_message` is the ID of the archived
+    /// message they were decoded from, if it has one.
+    ///
+    /// Measurement times are checked against `received_time`, with `buffered` as for
+    /// `ClockConfig::check`. The station's clock statistics are left alone. The readings are added
+    /// to the rollups.
+    pub fn insert_late_readings(
+        &mut self,
+        station: &str,
+        settings: &IngestSettings,
+        payloads: &[SensorMessagePayload],
+        buffered: bool,
+        received_time: i64,
+        raw_message: Option<i64>,
+    ) -> Result<()> {
+        self.write(payloads.len(), |conn| {
+            insert_checked_rows(
+                conn,
+                station,
+                settings,
+                payloads,
+                buffered,
+                received_time,
+                raw_message,
+                true,
+            )
+        })
+    }
+
+    /// Whether `station` has a reading measured at `measurement_time`
+    pub fn has_reading(&self, station: &str, measurement_time: i64) -> Result<bool> {
+        self.conn
+            .prepare_cached(READING_EXISTS_SQL)?
+            .query_row((station, measurement_time), |row| row.get(0))
+    }
+
+    /// Whether `station` has telemetry measured at `measurement_time`
+    pub fn has_telemetry(&self, station: &str, measurement_time: i64) -> Result<bool> {
+        self.conn
+            .prepare_cached(TELEMETRY_EXISTS_SQL)?
+            .query_row((station, measurement_time), |row| row.get(0))
+    }
+
+    /// Whether readings were stored from the archived message with ID `raw_message`, or from another
+    /// delivery of it: an archived message with the same topic and payload
+    pub fn has_readings_from(&self, raw_message: i64) -> Result<bool> {
+        self.conn
+            .prepare_cached(MESSAGE_STORED_SQL)?
+            .query_row([raw_message], |row| row.get(0))
+    }
+
     /// Inserts sensor data into the 'weather_data' table.
     ///
-    /// Reformats the SensorPayload and adds the current POSIX time.
-    pub fn insert_sensor_data(&self, payload: &SensorMessagePayload) -> Result<()> {
-        let received_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("System time predates unix epoch???")
-            .as_secs() as i64;
+    /// Reformats the SensorPayload and adds the current POSIX time, the station it came from, and
+    /// the quantities derived from it. The measurement time is checked against the current time,
+    /// and the station's clock statistics updated. The reading is added to the hourly and daily
+    /// rollups.
+    pub fn insert_sensor_data(
+        &mut self,
+        station: &str,
+        settings: &IngestSettings,
+        payload: &SensorMessagePayload,
+    ) -> Result<()> {
+        self.insert_readings(station, settings, std::slice::from_ref(payload), false)
+    }
+
+    /// Inserts several readings into the 'weather_data' table together, and adds them to the
+    /// rollups.
+    ///
+    /// Used for batch messages, either every reading is stored or none are. Each row keeps its own
+    /// measurement time, and all share the same received time. As the station may have held the
+    /// readings for a while, they aren't flagged for being measured long before they arrived.
+    pub fn insert_sensor_batch(
+        &mut self,
+        station: &str,
+        settings: &IngestSettings,
+        payloads: &[SensorMessagePayload],
+    ) -> Result<()> {
+        self.insert_readings(station, settings, payloads, true)
+    }
+
+    fn insert_readings(
+        &mut self,
+        station: &str,
+        settings: &IngestSettings,
+        payloads: &[SensorMessagePayload],
+        buffered: bool,
+    ) -> Result<()> {
+        let raw_message = self.archived_message.take();
+        let received_time = now();
+
+        self.write(payloads.len(), |conn| {
+            let mut record_clock = conn.prepare_cached(RECORD_CLOCK_SQL)?;
+            for payload in payloads {
+                let check = settings
+                    .clock
+                    .check(payload.posix_time, received_time, buffered);
+                let skew_sample = match check.problem {
+                    Some(ClockProblem::Unsynced) => None,
+                    _ if buffered => None,
+                    _ => Some(check.skew),
+                };
+                record_clock.execute((
+                    station,
+                    skew_sample,
+                    check.problem == Some(ClockProblem::Unsynced),
+                    matches!(
+                        check.problem,
+                        Some(ClockProblem::Future | ClockProblem::Behind)
+                    ),
+                ))?;
+
+                let rowid = insert_row(
+                    conn,
+                    station,
+                    settings.altitude,
+                    payload,
+                    received_time,
+                    check.measurement_time,
+                    check.problem.map(ClockProblem::kind),
+                    raw_message,
+                )?;
+                rollup::record(conn, rowid)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Inserts a reading from a file rather than MQTT, such as one logged while the parser was down,
+    /// unless `station` already has a reading measured at the same time. Returns whether it was
+    /// inserted.
+    ///
+    /// The measurement time is stored as it is, without a clock check, and the station's clock
+    /// statistics are left alone. The reading is added to the rollups.
+    pub fn import_reading(
+        &mut self,
+        station: &str,
+        altitude: Option<f32>,
+        received_time: i64,
+        payload: &SensorMessagePayload,
+    ) -> Result<bool> {
+        if self.has_reading(station, payload.posix_time)? {
+            return Ok(false);
+        }
+        self.write(1, |conn| {
+            let rowid = insert_row(
+                conn,
+                station,
+                altitude,
+                payload,
+                received_time,
+                payload.posix_time,
+                None,
+                None,
+            )?;
+            rollup::record(conn, rowid)
+        })?;
+        Ok(true)
+    }
 
+    /// Recomputes the hourly and daily rollups of every period with readings in 'weather_data',
+    /// returning how many rollup rows were written
+    pub fn rebuild_rollups(&mut self) -> Result<usize> {
+        self.flush()?;
+        let transaction = self.conn.transaction()?;
+        let rows = rollup::rebuild(&transaction)?;
+        transaction.commit()?;
+        Ok(rows)
+    }
+
+    /// Deletes at most `limit` rows of `table` whose `time_column` is before `cutoff`, in their
+    /// own transaction. Returns how many were deleted.
+    pub(crate) fn prune_before(
+        &mut self,
+        table: &str,
+        time_column: &str,
+        cutoff: i64,
+        limit: usize,
+    ) -> Result<usize> {
+        self.flush()?;
+        self.conn.execute(
+            &format!(
+                "DELETE FROM {0} WHERE rowid IN (SELECT rowid FROM {0} WHERE {1} < ?1 LIMIT ?2)",
+                table, time_column
+            ),
+            (cutoff, limit as i64),
+        )
+    }
+
+    /// Returns at most `pages` of the pages freed by deleted rows to the file system, returning how
+    /// many free pages are left. A database from before incremental vacuuming needs a full `vacuum`
+    /// first, until then nothing is released and this returns 0.
+    pub fn incremental_vacuum(&mut self, pages: usize) -> Result<usize> {
+        self.flush()?;
+        if !self.is_incremental()? {
+            return Ok(0);
+        }
+        // A count of 0 would release every free page
+        self.conn
+            .execute_batch(&format!("PRAGMA incremental_vacuum({})", pages.max(1)))?;
         self.conn
-            .execute(INSERT_SQL, payload.to_sql_tuple(received_time))?;
+            .pragma_query_value(None, "freelist_count", |row| row.get(0))
+    }
+
+    /// Returns the pages freed by deleted rows to the file system.
+    ///
+    /// A database from before incremental vacuuming gets one full VACUUM, which rewrites the whole
+    /// file and so needs as much free space again. Afterwards only the free pages are released.
+    pub fn vacuum(&mut self) -> Result<()> {
+        self.flush()?;
+        if self.is_incremental()? {
+            self.conn.execute_batch("PRAGMA incremental_vacuum")
+        } else {
+            println!("Converting database to incremental vacuuming, this may take a while");
+            self.conn
+                .pragma_update(None, "auto_vacuum", "INCREMENTAL")?;
+            self.conn.execute_batch("VACUUM")
+        }
+    }
+
+    /// Whether the database has incremental vacuuming turned on
+    fn is_incremental(&self) -> Result<bool> {
+        let auto_vacuum: i64 = self
+            .conn
+            .pragma_query_value(None, "auto_vacuum", |row| row.get(0))?;
+        // 2 is INCREMENTAL
+        Ok(auto_vacuum == 2)
+    }
+
+    /// Copies the database to a new file at `path` with SQLite's online backup API.
+    ///
+    /// The copy is taken in one read transaction, so it is consistent, and in write-ahead log mode
+    /// other connections carry on writing meanwhile. It is switched to a rollback journal, so the
+    /// backup is a single file.
+    pub fn backup_to(&mut self, path: &Path) -> Result<()> {
+        self.flush()?;
+        let mut backup_conn = Connection::open(path)?;
+        // Every page in one step, so in one read transaction
+        Backup::new(&self.conn, &mut backup_conn)?.run_to_completion(
+            i32::MAX,
+            Duration::from_millis(250),
+            None,
+        )?;
+        backup_conn.pragma_update_and_check(None, "journal_mode", "DELETE", |row| {
+            row.get::<_, String>(0)
+        })?;
         Ok(())
     }
 
+    /// Replaces the whole database with the one at `path`, with SQLite's online backup API
+    pub fn restore_from(&mut self, path: &Path) -> Result<()> {
+        self.flush()?;
+        self.conn
+            .restore(DatabaseName::Main, path, None::<fn(Progress)>)
+    }
+
+    /// The problems `PRAGMA integrity_check` finds, or none if the database is intact
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        integrity_check(&self.conn)
+    }
+
+    /// The latest reading from `station`, by measurement time
+    pub fn latest_reading(&self, station: &str) -> Result<Option<Reading>> {
+        self.conn
+            .query_row(
+                &format!(
+                    "{} WHERE Station = ?1 ORDER BY MeasurementTime DESC LIMIT 1",
+                    SELECT_READING_SQL
+                ),
+                [station],
+                Reading::from_row,
+            )
+            .optional()
+    }
+
+    /// The latest reading from every station, by measurement time, in order of station
+    pub fn latest_readings(&self) -> Result<Vec<Reading>> {
+        // SQLite takes a bare column such as rowid from the row with the maximum
+        let mut stmt = self.conn.prepare(&format!(
+            "{} WHERE rowid IN (
+                SELECT id FROM (
+                    SELECT rowid AS id, MAX(MeasurementTime) FROM weather_data
+                    WHERE Station IS NOT NULL GROUP BY Station
+                )
+            )
+            ORDER BY Station",
+            SELECT_READING_SQL
+        ))?;
+        let readings = stmt.query_map([], Reading::from_row)?;
+        readings.collect()
+    }
+
+    /// Readings from `station` measured from `start` up to but not including `end`, oldest first
+    pub fn readings_in_range(&self, station: &str, start: i64, end: i64) -> Result<Vec<Reading>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "{} WHERE Station = ?1 AND MeasurementTime >= ?2 AND MeasurementTime < ?3
+            ORDER BY MeasurementTime",
+            SELECT_READING_SQL
+        ))?;
+        let readings = stmt.query_map((station, start, end), Reading::from_row)?;
+        readings.collect()
+    }
+
+    /// The minimum, maximum and mean of each reading and derived quantity from `station` measured
+    /// from `start` up to but not including `end`
+    pub fn aggregate(&self, station: &str, start: i64, end: i64) -> Result<ReadingAggregate> {
+        self.conn
+            .query_row(&reading::aggregate_sql(), (station, start, end), |row| {
+                ReadingAggregate::from_row(station, start, end, row)
+            })
+    }
+
+    /// Inserts device telemetry from `station` into the 'device_telemetry' table
+    pub fn insert_telemetry(&mut self, station: &str, telemetry: &TelemetryPayload) -> Result<()> {
+        self.archived_message = None;
+        let received_time = now();
+        self.insert_late_telemetry(station, telemetry, received_time)
+    }
+
+    /// Inserts device telemetry from `station` that was received at `received_time` but is only
+    /// being stored now
+    pub fn insert_late_telemetry(
+        &mut self,
+        station: &str,
+        telemetry: &TelemetryPayload,
+        received_time: i64,
+    ) -> Result<()> {
+        self.write(1, |conn| {
+            conn.prepare_cached(INSERT_TELEMETRY_SQL)?.execute((
+                telemetry.posix_time,
+                received_time,
+                station,
+                telemetry.battery_millivolts,
+                telemetry.wifi_rssi,
+                telemetry.reset_reason,
+                telemetry.wake_count,
+                &telemetry.firmware_version,
+            ))?;
+            Ok(())
+        })
+    }
+
+    /// The most recently measured telemetry from `station`, if it has sent any
+    pub fn latest_telemetry(&self, station: &str) -> Result<Option<TelemetryRecord>> {
+        self.conn
+            .query_row(
+                &format!(
+                    "{} WHERE Station = ?1 ORDER BY MeasurementTime DESC LIMIT 1",
+                    SELECT_TELEMETRY_SQL
+                ),
+                [station],
+                TelemetryRecord::from_row,
+            )
+            .optional()
+    }
+
+    /// Telemetry from `station` measured from `start` up to but not including `end`, oldest first
+    pub fn telemetry_in_range(
+        &self,
+        station: &str,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<TelemetryRecord>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "{} WHERE Station = ?1 AND MeasurementTime >= ?2 AND MeasurementTime < ?3
+            ORDER BY MeasurementTime",
+            SELECT_TELEMETRY_SQL
+        ))?;
+        let records = stmt.query_map((station, start, end), TelemetryRecord::from_row)?;
+        records.collect()
+    }
+
+    /// The sequence number of the latest message stored from `station`, if it has sent any
+    pub fn last_sequence(&self, station: &str) -> Result<Option<u32>> {
+        self.conn
+            .query_row(
+                "SELECT LastSequence FROM station_sequence WHERE Station = ?1",
+                [station],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Records `sequence` as the latest message stored from `station`
+    pub fn set_last_sequence(&mut self, station: &str, sequence: u32) -> Result<()> {
+        self.write(0, |conn| {
+            conn.prepare_cached(SET_SEQUENCE_SQL)?
+                .execute((station, sequence))?;
+            Ok(())
+        })
+    }
+
+    /// Lists every station with readings in 'weather_data'
+    pub fn station_summaries(&self) -> Result<Vec<StationSummary>> {
+        let mut stmt = self.conn.prepare(STATION_SUMMARY_SQL)?;
+        let summaries = stmt.query_map([], |row| {
+            Ok(StationSummary {
+                station: row.get(0)?,
+                reading_count: row.get(1)?,
+                last_measurement_time: row.get(2)?,
+            })
+        })?;
+        summaries.collect()
+    }
+
+    /// Lists every station's clock statistics
+    pub fn clock_stats(&self) -> Result<Vec<ClockStats>> {
+        let mut stmt = self.conn.prepare(CLOCK_STATS_SQL)?;
+        let stats = stmt.query_map([], |row| {
+            let samples: i64 = row.get(1)?;
+            let skew_sum: i64 = row.get(2)?;
+            Ok(ClockStats {
+                station: row.get(0)?,
+                samples,
+                mean_skew: (samples > 0).then(|| skew_sum as f64 / samples as f64),
+                min_skew: row.get(3)?,
+                max_skew: row.get(4)?,
+                last_skew: row.get(5)?,
+                unsynced_count: row.get(6)?,
+                skewed_count: row.get(7)?,
+            })
+        })?;
+        stats.collect()
+    }
+
     /// Inserts a dummy payload, prints the result
     pub fn test_sqlite(&self) -> Result<()> {
         let dummy_payload = SensorMessagePayload::create_dummy();
 
-        let received_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("System time predates unix epoch???")
-            .as_secs() as i64;
+        let received_time = now();
 
-        self.conn
-            .execute(INSERT_SQL_TEST, dummy_payload.to_sql_tuple(received_time))?;
+        self.conn.execute(
+            INSERT_SQL_TEST,
+            dummy_payload.to_sql_tuple(received_time, "test"),
+        )?;
 
         let mut stmt = self.conn.prepare(SELECT_SQL_TEST)?;
         let col_count = stmt.column_count();
@@ -123,3 +964,96 @@ impl WeatherDatabase {
         Ok(())
     }
 }
+
+/// Inserts readings into 'weather_data' as `insert_row` does, with their measurement times checked
+/// against `received_time` but without adding to the station's clock statistics. They are added to
+/// the rollups if `record_rollups` is set.
+#[allow(clippy::too_many_arguments)]
+fn insert_checked_rows(
+    conn: &Connection,
+    station: &str,
+    settings: &IngestSettings,
+    payloads: &[SensorMessagePayload],
+    buffered: bool,
+    received_time: i64,
+    raw_message: Option<i64>,
+    record_rollups: bool,
+) -> Result<()> {
+    for payload in payloads {
+        let check = settings
+            .clock
+            .check(payload.posix_time, received_time, buffered);
+        let rowid = insert_row(
+            conn,
+            station,
+            settings.altitude,
+            payload,
+            received_time,
+            check.measurement_time,
+            check.problem.map(ClockProblem::kind),
+            raw_message,
+        )?;
+        if record_rollups {
+            rollup::record(conn, rowid)?;
+        }
+    }
+    Ok(())
+}
+
+/// Inserts a reading into 'weather_data', with the quantities derived from it, returning its rowid
+/// to add to the rollups. `raw_message` is the ID of the archived message it was decoded from, if it
+/// has one.
+#[allow(clippy::too_many_arguments)]
+fn insert_row(
+    conn: &Connection,
+    station: &str,
+    altitude: Option<f32>,
+    payload: &SensorMessagePayload,
+    received_time: i64,
+    measurement_time: i64,
+    clock_status: Option<&str>,
+    raw_message: Option<i64>,
+) -> Result<i64> {
+    let row = payload.to_sql_tuple(received_time, station);
+    let derived = DerivedQuantities::from_payload(payload, altitude).to_sql_tuple();
+    conn.prepare_cached(INSERT_SQL)?.execute(params![
+        measurement_time,
+        row.1,
+        row.2,
+        row.3,
+        row.4,
+        row.5,
+        row.6,
+        row.7,
+        row.8,
+        row.9,
+        derived.0,
+        derived.1,
+        derived.2,
+        derived.3,
+        derived.4,
+        clock_status,
+        raw_message,
+    ])?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// The problems `PRAGMA integrity_check` finds in the database on `conn`, or none if it is intact
+pub(crate) fn integrity_check(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    let results = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>>>()?;
+    Ok(if results == ["ok"] {
+        Vec::new()
+    } else {
+        results
+    })
+}
+
+impl Drop for WeatherDatabase {
+    fn drop(&mut self) {
+        self.flush()
+            .unwrap_or_else(|err| eprintln!("Failed to commit on closing database: {}", err));
+    }
+}
@@ -0,0 +1,361 @@
+//! The `dead-letters` subcommand, for the messages that were rejected because they couldn't be
+//! decoded.
+//!
+//! `on_message` keeps each such payload in `dead_letters` with the error it failed with. They can
+//! be listed, dumped as hex to see what the station actually sent, and retried once the decoder or
+//! config is fixed, which stores their readings and deletes them.
+
+use crate::config::Config;
+use crate::database::{DeadLetter, WeatherDatabase};
+use crate::inspect::write_table;
+use crate::mqtt_message::MqttMessage;
+use crate::timestamp::{format_iso8601, now, parse_duration, parse_iso8601};
+use serde_json::Value as JsonValue;
+use std::fmt;
+use std::io::{self, Write};
+
+pub const USAGE: &str = "Usage: message_parser dead-letters list [options]
+       message_parser dead-letters show ID...
+       message_parser dead-letters retry [ID...]
+
+Messages that couldn't be decoded or authenticated are kept as dead letters. `list` prints them,
+`show` prints their payloads as hex, and `retry` decodes them again with the current decoders and
+config, storing the readings of those that decode and deleting them. Without IDs, `retry` tries every
+dead letter. Sequence numbers aren't checked, but messages already stored from another delivery are
+skipped.
+
+Options for list:
+  --last DURATION     only messages received in the last DURATION, e.g. 90m, 24h, 7d or 2w
+  --since TIME        only messages received from TIME on, an ISO 8601 date such as 2025-03-15
+                      or 2025-03-15T12:00:00Z, or a POSIX time
+  --until TIME        only messages received before TIME
+  --kind KIND         only messages rejected with the error kind KIND, e.g. checksum_mismatch";
+
+/// Why `dead-letters` failed
+#[derive(Debug)]
+pub enum DeadLetterError {
+    /// The command line doesn't make sense, with a description of what's wrong
+    Usage(String),
+    /// There is no dead letter with this ID
+    NotFound(i64),
+    Database(rusqlite::Error),
+    Output(io::Error),
+}
+
+impl fmt::Display for DeadLetterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeadLetterError::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            DeadLetterError::NotFound(id) => write!(f, "No dead letter with ID {}", id),
+            DeadLetterError::Database(err) => write!(f, "Database error: {}", err),
+            DeadLetterError::Output(err) => write!(f, "Could not write output: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for DeadLetterError {}
+
+impl From<rusqlite::Error> for DeadLetterError {
+    fn from(err: rusqlite::Error) -> Self {
+        DeadLetterError::Database(err)
+    }
+}
+
+impl From<io::Error> for DeadLetterError {
+    fn from(err: io::Error) -> Self {
+        DeadLetterError::Output(err)
+    }
+}
+
+/// Which dead letters `list` prints
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListOptions {
+    /// Earliest POSIX time a message was received
+    pub since: Option<i64>,
+    /// POSIX time before which messages were received
+    pub until: Option<i64>,
+    /// `ParseError::kind` of the error
+    pub kind: Option<String>,
+}
+
+/// What `dead-letters` was asked to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    List(ListOptions),
+    Show(Vec<i64>),
+    /// Retry the dead letters with these IDs, or every one if there are none
+    Retry(Vec<i64>),
+}
+
+impl Command {
+    /// Parses the arguments that follow `dead-letters`
+    pub fn parse(args: &[String]) -> Result<Self, DeadLetterError> {
+        let Some((command, args)) = args.split_first() else {
+            return Err(DeadLetterError::Usage("No command given".to_string()));
+        };
+        match command.as_str() {
+            "list" => Ok(Command::List(parse_list_options(args)?)),
+            "show" if args.is_empty() => Err(DeadLetterError::Usage(
+                "show needs the IDs of dead letters".to_string(),
+            )),
+            "show" => Ok(Command::Show(parse_ids(args)?)),
+            "retry" => Ok(Command::Retry(parse_ids(args)?)),
+            _ => Err(DeadLetterError::Usage(format!(
+                "Unknown command '{}'",
+                command
+            ))),
+        }
+    }
+}
+
+fn parse_list_options(args: &[String]) -> Result<ListOptions, DeadLetterError> {
+    let mut options = ListOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| DeadLetterError::Usage(format!("{} needs a value", arg)))
+        };
+        match arg.as_str() {
+            "--last" => {
+                let duration = value()?;
+                let seconds = parse_duration(duration).ok_or_else(|| {
+                    DeadLetterError::Usage(format!("Invalid duration '{}'", duration))
+                })?;
+                options.since = Some(now() - seconds);
+            }
+            "--since" => options.since = Some(parse_time(value()?)?),
+            "--until" => options.until = Some(parse_time(value()?)?),
+            "--kind" => options.kind = Some(value()?.clone()),
+            _ => {
+                return Err(DeadLetterError::Usage(format!(
+                    "Unexpected argument '{}'",
+                    arg
+                )))
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn parse_time(time: &str) -> Result<i64, DeadLetterError> {
+    parse_iso8601(time).ok_or_else(|| DeadLetterError::Usage(format!("Invalid time '{}'", time)))
+}
+
+fn parse_ids(args: &[String]) -> Result<Vec<i64>, DeadLetterError> {
+    args.iter()
+        .map(|arg| {
+            arg.parse()
+                .map_err(|_| DeadLetterError::Usage(format!("Invalid ID '{}'", arg)))
+        })
+        .collect()
+}
+
+/// Runs `command` on the dead letters in `database`, printing what it did to `out`
+pub fn run(
+    database: &mut WeatherDatabase,
+    config: &Config,
+    command: &Command,
+    out: &mut impl Write,
+) -> Result<(), DeadLetterError> {
+    match command {
+        Command::List(options) => print_list(database, options, out),
+        Command::Show(ids) => {
+            for (index, dead_letter) in find(database, ids)?.iter().enumerate() {
+                if index > 0 {
+                    writeln!(out)?;
+                }
+                print_dead_letter(dead_letter, out)?;
+            }
+            Ok(())
+        }
+        Command::Retry(ids) => {
+            let report = retry(database, config, ids)?;
+            Ok(writeln!(out, "{}", report)?)
+        }
+    }
+}
+
+/// The dead letters with `ids`, or every one if `ids` is empty
+fn find(database: &WeatherDatabase, ids: &[i64]) -> Result<Vec<DeadLetter>, DeadLetterError> {
+    if ids.is_empty() {
+        return Ok(database.dead_letters(i64::MIN, i64::MAX)?);
+    }
+    ids.iter()
+        .map(|&id| {
+            database
+                .dead_letter(id)?
+                .ok_or(DeadLetterError::NotFound(id))
+        })
+        .collect()
+}
+
+fn print_list(
+    database: &WeatherDatabase,
+    options: &ListOptions,
+    out: &mut impl Write,
+) -> Result<(), DeadLetterError> {
+    let columns: Vec<String> = ["Id", "ReceivedTime", "Topic", "ErrorKind", "Bytes", "Error"]
+        .iter()
+        .map(|column| column.to_string())
+        .collect();
+    let rows: Vec<Vec<JsonValue>> = database
+        .dead_letters(
+            options.since.unwrap_or(i64::MIN),
+            options.until.unwrap_or(i64::MAX),
+        )?
+        .into_iter()
+        .filter(|dead_letter| {
+            options
+                .kind
+                .as_ref()
+                .is_none_or(|kind| *kind == dead_letter.error_kind)
+        })
+        .map(|dead_letter| {
+            vec![
+                JsonValue::from(dead_letter.id),
+                JsonValue::from(format_iso8601(dead_letter.received_time)),
+                JsonValue::from(dead_letter.topic),
+                JsonValue::from(dead_letter.error_kind),
+                JsonValue::from(dead_letter.payload.len()),
+                JsonValue::from(dead_letter.error),
+            ]
+        })
+        .collect();
+    Ok(write_table(&columns, &rows, true, out)?)
+}
+
+fn print_dead_letter(dead_letter: &DeadLetter, out: &mut impl Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "Dead letter {}, received {} on '{}', {} bytes",
+        dead_letter.id,
+        format_iso8601(dead_letter.received_time),
+        dead_letter.topic,
+        dead_letter.payload.len()
+    )?;
+    writeln!(
+        out,
+        "Rejected as {}: {}",
+        dead_letter.error_kind, dead_letter.error
+    )?;
+    write!(out, "{}", hex_dump(&dead_letter.payload))
+}
+
+/// `bytes` laid out as `hexdump -C` does: the offset, sixteen bytes in hex, then those of them that
+/// are printable ASCII
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (index, line) in bytes.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for (position, byte) in line.iter().enumerate() {
+            if position == 8 {
+                hex.push(' ');
+            }
+            hex += &format!("{:02x} ", byte);
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        dump += &format!("{:08x}  {:<49} |{}|\n", index * 16, hex, ascii);
+    }
+    dump
+}
+
+/// What retrying dead letters did
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RetryReport {
+    /// Dead letters that decoded, were stored and deleted
+    pub stored: usize,
+    /// Readings stored from them
+    pub readings: usize,
+    /// Readings and telemetry skipped because another delivery of their message was already
+    /// stored
+    pub duplicates: usize,
+    /// Dead letters that still couldn't be decoded
+    pub failed: usize,
+}
+
+impl fmt::Display for RetryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Stored {} dead letters with {} readings, skipping {} already stored; {} still failed",
+            self.stored, self.readings, self.duplicates, self.failed
+        )
+    }
+}
+
+/// Decodes the dead letters with `ids` again, or every one if `ids` is empty, storing what they
+/// hold and deleting them. Those that still fail are logged to stderr and kept, with their error
+/// updated.
+///
+/// Readings keep the dead letter's received time and are linked to its archived message, so
+/// `reprocess` can replace them later. Dead letters whose archived message, or another delivery of
+/// it, already has readings are skipped, so a message rejected on each of several deliveries is
+/// only stored once. Measurement times can't be compared for this, as the clock policy may have
+/// replaced them. Telemetry keeps the station's time, so it is skipped if the station already has
+/// telemetry measured then.
+pub fn retry(
+    database: &mut WeatherDatabase,
+    config: &Config,
+    ids: &[i64],
+) -> Result<RetryReport, DeadLetterError> {
+    let mut report = RetryReport::default();
+    for dead_letter in find(database, ids)? {
+        let (station, format) = config.route(&dead_letter.topic);
+        let message = match config.decode_message(station, format, &dead_letter.payload) {
+            Ok(message) => message,
+            Err(error) => {
+                eprintln!("Dead letter {} still failed: {}", dead_letter.id, error);
+                database.set_dead_letter_error(dead_letter.id, &error)?;
+                report.failed += 1;
+                continue;
+            }
+        };
+        let (payloads, buffered) = match message {
+            MqttMessage::Reading(reading) => (vec![reading.payload], false),
+            MqttMessage::Batch(batch) => (batch.payloads, true),
+            MqttMessage::Telemetry(telemetry) => {
+                if database.has_telemetry(station, telemetry.payload.posix_time)? {
+                    report.duplicates += 1;
+                } else {
+                    database.insert_late_telemetry(
+                        station,
+                        &telemetry.payload,
+                        dead_letter.received_time,
+                    )?;
+                }
+                (Vec::new(), false)
+            }
+        };
+        let stored = match dead_letter.raw_message {
+            Some(raw_message) if !payloads.is_empty() => database.has_readings_from(raw_message)?,
+            _ => false,
+        };
+        if stored {
+            report.duplicates += payloads.len();
+        } else {
+            database.insert_late_readings(
+                station,
+                &config.ingest_settings(station),
+                &payloads,
+                buffered,
+                dead_letter.received_time,
+                dead_letter.raw_message,
+            )?;
+            report.readings += payloads.len();
+        }
+        database.remove_dead_letter(dead_letter.id)?;
+        report.stored += 1;
+    }
+    database.flush()?;
+    Ok(report)
+}
@@ -0,0 +1,137 @@
+//! Meteorological quantities derived from a station's readings at ingest, so dashboards can chart
+//! them without recomputing them in SQL.
+
+use crate::mqtt_message::SensorMessagePayload;
+use crate::units::{AbsoluteHumidity, Celsius, FixedPoint, Pascal, RelativeHumidity};
+
+/// Magnus formula coefficients over water (Sonntag 1990), the same ones the firmware uses for the
+/// SGP30's humidity compensation
+const MAGNUS_A: f32 = 17.62;
+const MAGNUS_B: f32 = 243.12;
+const MAGNUS_HPA: f32 = 6.112;
+
+/// Temperature lapse rate of the standard atmosphere, in K/m
+const LAPSE_RATE: f32 = 0.0065;
+
+/// Exponent of the barometric formula, g·M / (R·L)
+const BAROMETRIC_EXPONENT: f32 = 5.257;
+
+/// Quantities computed from one payload, `None` where the readings they need are missing
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DerivedQuantities {
+    pub dew_point: Option<Celsius>,
+    pub absolute_humidity: Option<AbsoluteHumidity>,
+    pub heat_index: Option<Celsius>,
+    pub humidex: Option<Celsius>,
+    pub sea_level_pressure: Option<Pascal>,
+}
+
+impl DerivedQuantities {
+    /// Derives the quantities from `payload`, from a station `altitude` metres above sea level.
+    ///
+    /// The BME280 is used when it can be trusted, as it reads temperature and humidity together,
+    /// otherwise the DHT22. Sea-level pressure needs the station's altitude.
+    pub fn from_payload(payload: &SensorMessagePayload, altitude: Option<f32>) -> Self {
+        let air = match (payload.temperature_bme(), payload.humidity_bme()) {
+            (Some(temperature), Some(humidity)) => Some((temperature, humidity)),
+            _ => payload.temperature_dht22().zip(payload.humidity_dht22()),
+        }
+        // The Magnus formula has no dew point for completely dry air, nor for supersaturated air
+        .filter(|(_, humidity)| humidity.0 > 0.0 && humidity.0 <= 100.0);
+
+        let dew_point = air.map(|(temperature, humidity)| dew_point(temperature, humidity));
+        DerivedQuantities {
+            dew_point,
+            absolute_humidity: air
+                .map(|(temperature, humidity)| absolute_humidity(temperature, humidity)),
+            heat_index: air.map(|(temperature, humidity)| heat_index(temperature, humidity)),
+            humidex: air
+                .zip(dew_point)
+                .map(|((temperature, _), dew_point)| humidex(temperature, dew_point)),
+            sea_level_pressure: payload
+                .pressure_bme()
+                .zip(payload.temperature_bme())
+                .zip(altitude)
+                .map(|((pressure, temperature), altitude)| {
+                    sea_level_pressure(pressure, temperature, altitude)
+                }),
+        }
+    }
+
+    /// Scales the quantities to the integer columns of `weather_data`, in the order `DewPoint`,
+    /// `AbsoluteHumidity`, `HeatIndex`, `Humidex`, `PressureSeaLevel`
+    #[allow(clippy::type_complexity)]
+    pub fn to_sql_tuple(
+        &self,
+    ) -> (
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+    ) {
+        (
+            self.dew_point.map(FixedPoint::to_fixed),
+            self.absolute_humidity.map(FixedPoint::to_fixed),
+            self.heat_index.map(FixedPoint::to_fixed),
+            self.humidex.map(FixedPoint::to_fixed),
+            self.sea_level_pressure.map(FixedPoint::to_fixed),
+        )
+    }
+}
+
+/// Saturation vapour pressure over water at `temperature`, in hPa
+fn saturation_vapour_pressure(temperature: Celsius) -> f32 {
+    MAGNUS_HPA * (MAGNUS_A * temperature.0 / (MAGNUS_B + temperature.0)).exp()
+}
+
+/// The temperature air must cool to for its water vapour to condense, by the Magnus formula
+pub fn dew_point(temperature: Celsius, humidity: RelativeHumidity) -> Celsius {
+    let gamma = (humidity.0 / 100.0).ln() + MAGNUS_A * temperature.0 / (MAGNUS_B + temperature.0);
+    Celsius(MAGNUS_B * gamma / (MAGNUS_A - gamma))
+}
+
+/// Mass of water vapour per volume of air, as the firmware's `getAbsoluteHumidity`
+pub fn absolute_humidity(temperature: Celsius, humidity: RelativeHumidity) -> AbsoluteHumidity {
+    let vapour_pressure = humidity.0 / 100.0 * saturation_vapour_pressure(temperature);
+    AbsoluteHumidity(216.7 * vapour_pressure / temperature.kelvin())
+}
+
+/// How hot it feels, by the US National Weather Service's algorithm (Rothfusz regression with
+/// Steadman's formula below 80 °F, and its adjustments for very dry or humid air)
+pub fn heat_index(temperature: Celsius, humidity: RelativeHumidity) -> Celsius {
+    let t = temperature.fahrenheit();
+    let rh = humidity.0;
+
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    if (simple + t) / 2.0 < 80.0 {
+        return Celsius::from_fahrenheit(simple);
+    }
+
+    let mut index = -42.379 + 2.049_015_2 * t + 10.143_331 * rh
+        - 0.224_755_4 * t * rh
+        - 0.006_837_83 * t * t
+        - 0.054_817_17 * rh * rh
+        + 0.001_228_74 * t * t * rh
+        + 0.000_852_82 * t * rh * rh
+        - 0.000_001_99 * t * t * rh * rh;
+    if rh < 13.0 && (80.0..=112.0).contains(&t) {
+        index -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+    } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+        index += (rh - 85.0) / 10.0 * (87.0 - t) / 5.0;
+    }
+    Celsius::from_fahrenheit(index)
+}
+
+/// How hot it feels, by Environment Canada's humidex
+pub fn humidex(temperature: Celsius, dew_point: Celsius) -> Celsius {
+    let vapour_pressure = 6.11 * (5417.753 * (1.0 / 273.16 - 1.0 / dew_point.kelvin())).exp();
+    Celsius(temperature.0 + 0.5555 * (vapour_pressure - 10.0))
+}
+
+/// `pressure` measured `altitude` metres above sea level, reduced to sea level with the
+/// barometric formula
+pub fn sea_level_pressure(pressure: Pascal, temperature: Celsius, altitude: f32) -> Pascal {
+    let lapse = LAPSE_RATE * altitude;
+    Pascal(pressure.0 * (1.0 - lapse / (temperature.kelvin() + lapse)).powf(-BAROMETRIC_EXPONENT))
+}
@@ -0,0 +1,500 @@
+//! The `import` subcommand, for backfilling `weather_data` with readings that didn't arrive over
+//! MQTT, such as those from when the parser was down or from a station's serial log.
+//!
+//! Files are CSV with a header row, or NDJSON with an object per line, using the column names of
+//! `weather_data` and the units `inspect` prints, so its exports can be imported again. Each row
+//! becomes a `SensorMessagePayload` and is stored by the same path as a message, so it gets the
+//! same scaling and derived quantities. Columns the parser computes itself are ignored.
+
+use crate::config::Config;
+use crate::database::WeatherDatabase;
+use crate::mqtt_message::{
+    SensorMessagePayload, VALID_BME280, VALID_DHT22_HUMIDITY, VALID_DHT22_TEMPERATURE, VALID_SGP30,
+};
+use crate::timestamp::{now, parse_iso8601};
+use crate::units::{Celsius, FixedPoint, Pascal, RelativeHumidity};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::io::{self, Read};
+use std::ops::RangeInclusive;
+
+pub const USAGE: &str = "Usage: message_parser import [options] FILE...
+
+Stores the readings in each CSV or NDJSON FILE, or standard input for -, skipping any measured at
+the same time as a reading already stored from their station.
+
+Rows need a MeasurementTime, as an ISO 8601 time or a POSIX time, and have readings in the units
+`message_parser inspect` prints: TemperatureBME, TemperatureDHT22 (°C), PressureBME (Pa),
+HumidityBME, HumidityDHT22 (%), eCO2SGP30 (ppm) and TVOCSGP30 (ppb). An empty reading is from a
+failed sensor. Station and ReceivedTime columns are optional, other columns are ignored.
+
+Options:
+  --station NAME      station for rows without a Station, instead of the config's default_station
+  --format FORMAT     csv or ndjson, by default from the file's extension";
+
+/// Readings the sensors can report, from their datasheets. Anything outside them is corrupt.
+const TEMPERATURE_RANGE: RangeInclusive<f64> = -40.0..=85.0;
+const PRESSURE_RANGE: RangeInclusive<f64> = 30000.0..=110000.0;
+const HUMIDITY_RANGE: RangeInclusive<f64> = 0.0..=100.0;
+const ECO2_RANGE: RangeInclusive<f64> = 400.0..=60000.0;
+const TVOC_RANGE: RangeInclusive<f64> = 0.0..=60000.0;
+
+/// How an import file is laid out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportFormat {
+    /// Comma-separated values with a header row naming the columns
+    Csv,
+    /// A JSON object per line
+    Ndjson,
+}
+
+impl ImportFormat {
+    /// The format a file's extension names, CSV unless it is `.ndjson` or `.jsonl`
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".ndjson") || path.ends_with(".jsonl") {
+            ImportFormat::Ndjson
+        } else {
+            ImportFormat::Csv
+        }
+    }
+}
+
+/// Why the import failed
+#[derive(Debug)]
+pub enum ImportError {
+    /// The command line doesn't make sense, with a description of what's wrong
+    Usage(String),
+    /// A file couldn't be read
+    Read {
+        path: String,
+        error: io::Error,
+    },
+    Database(rusqlite::Error),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            ImportError::Read { path, error } => write!(f, "Could not read '{}': {}", path, error),
+            ImportError::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<rusqlite::Error> for ImportError {
+    fn from(err: rusqlite::Error) -> Self {
+        ImportError::Database(err)
+    }
+}
+
+/// A row that can't be imported, and why
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidRow {
+    /// Line of the file the row starts on
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+/// A reading read from an import file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportRow {
+    /// Line of the file the row starts on
+    pub line: usize,
+    pub station: Option<String>,
+    pub received_time: Option<i64>,
+    pub payload: SensorMessagePayload,
+}
+
+/// A row of an import file before validation, with the line it starts on and its fields keyed
+/// by column name
+type RawRow = (usize, Map<String, Value>);
+
+/// Splits CSV into records of fields, each with the line it starts on. Fields may be quoted, with
+/// `""` for a quote, and quoted fields may span lines. Blank lines are skipped.
+fn csv_records(text: &str) -> Vec<(usize, Vec<String>)> {
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            '\r' if !quoted => {}
+            '\n' if !quoted => {
+                fields.push(std::mem::take(&mut field));
+                if fields.len() > 1 || !fields[0].trim().is_empty() {
+                    records.push((record_line, std::mem::take(&mut fields)));
+                }
+                fields.clear();
+                line += 1;
+                record_line = line;
+            }
+            c => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+    if !fields.is_empty() || !field.trim().is_empty() {
+        fields.push(field);
+        records.push((record_line, fields));
+    }
+    records
+}
+
+/// The rows of a CSV file, as objects keyed by the header's column names with empty fields null
+fn csv_rows(text: &str) -> Vec<Result<RawRow, InvalidRow>> {
+    let mut records = csv_records(text).into_iter();
+    let Some((_, header)) = records.next() else {
+        return Vec::new();
+    };
+    let header: Vec<String> = header.iter().map(|name| name.trim().to_string()).collect();
+    records
+        .map(|(line, fields)| {
+            if fields.len() != header.len() {
+                return Err(InvalidRow {
+                    line,
+                    reason: format!(
+                        "has {} fields but the header has {}",
+                        fields.len(),
+                        header.len()
+                    ),
+                });
+            }
+            let row = header
+                .iter()
+                .zip(fields)
+                .map(|(name, field)| {
+                    let field = field.trim();
+                    let value = if field.is_empty() {
+                        Value::Null
+                    } else {
+                        Value::String(field.to_string())
+                    };
+                    (name.clone(), value)
+                })
+                .collect();
+            Ok((line, row))
+        })
+        .collect()
+}
+
+/// The rows of an NDJSON file, skipping blank lines
+fn ndjson_rows(text: &str) -> Vec<Result<RawRow, InvalidRow>> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .map(|row| (index + 1, row))
+                .map_err(|err| InvalidRow {
+                    line: index + 1,
+                    reason: format!("is not a JSON object: {}", err),
+                })
+        })
+        .collect()
+}
+
+/// A text field, `None` if missing or null
+fn string(row: &Map<String, Value>, column: &str) -> Result<Option<String>, String> {
+    match row.get(column) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(text)) => Ok(Some(text.clone())),
+        Some(value) => Err(format!("{} is not text: {}", column, value)),
+    }
+}
+
+/// A time field, ISO 8601 or POSIX, `None` if missing or null
+fn time(row: &Map<String, Value>, column: &str) -> Result<Option<i64>, String> {
+    match row.get(column) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Number(number)) => number
+            .as_i64()
+            .map(Some)
+            .ok_or_else(|| format!("{} is not a whole POSIX time: {}", column, number)),
+        Some(Value::String(text)) => parse_iso8601(text)
+            .map(Some)
+            .ok_or_else(|| format!("{} is not a time: '{}'", column, text)),
+        Some(value) => Err(format!("{} is not a time: {}", column, value)),
+    }
+}
+
+/// A reading, `None` if missing or null, checked to be within `range`
+fn reading(
+    row: &Map<String, Value>,
+    column: &str,
+    range: RangeInclusive<f64>,
+) -> Result<Option<f64>, String> {
+    let reading = match row.get(column) {
+        None | Some(Value::Null) => return Ok(None),
+        Some(Value::Number(number)) => number.as_f64(),
+        Some(Value::String(text)) => text.parse().ok(),
+        Some(_) => None,
+    };
+    match reading {
+        Some(reading) if range.contains(&reading) => Ok(Some(reading)),
+        Some(reading) => Err(format!(
+            "{} {} is outside {} to {}",
+            column,
+            reading,
+            range.start(),
+            range.end()
+        )),
+        None => Err(format!(
+            "{} is not a number: {}",
+            column,
+            row.get(column).unwrap()
+        )),
+    }
+}
+
+/// The reading a sensor would have sent for `value` to be stored as the same fixed-point integer.
+///
+/// `value` is truncated to the column's scale, as readings from MQTT are. A decimal at that scale,
+/// such as 45.53 %, can be just below it once it is an `f32`, so is moved up to it rather than
+/// being truncated to 45.52 %.
+fn sensor_value<T: FixedPoint>(value: f64) -> f32 {
+    let scaled = value * T::SCALE as f64;
+    let fixed = (scaled + scaled.signum() * 1e-6).trunc() as i32;
+    let mut sensor_value = T::from_fixed(fixed).value();
+    for _ in 0..4 {
+        if T::from_value(sensor_value).to_fixed() == fixed {
+            break;
+        }
+        // The next float further from zero
+        sensor_value = f32::from_bits(sensor_value.to_bits() + 1);
+    }
+    sensor_value
+}
+
+/// Reads a row into a payload, with the validity bits of the sensors that have readings
+fn payload(row: &Map<String, Value>, earliest_time: i64) -> Result<SensorMessagePayload, String> {
+    let posix_time = time(row, "MeasurementTime")?.ok_or("MeasurementTime is missing")?;
+    if posix_time < earliest_time {
+        return Err(format!(
+            "MeasurementTime {} is before the clock's earliest_time",
+            posix_time
+        ));
+    }
+    let bme = (
+        reading(row, "TemperatureBME", TEMPERATURE_RANGE)?,
+        reading(row, "PressureBME", PRESSURE_RANGE)?,
+        reading(row, "HumidityBME", HUMIDITY_RANGE)?,
+    );
+    let sgp30 = (
+        reading(row, "eCO2SGP30", ECO2_RANGE)?,
+        reading(row, "TVOCSGP30", TVOC_RANGE)?,
+    );
+    let dht22_temperature = reading(row, "TemperatureDHT22", TEMPERATURE_RANGE)?;
+    let dht22_humidity = reading(row, "HumidityDHT22", HUMIDITY_RANGE)?;
+
+    // The BME280 and SGP30 each have one validity bit for all of their readings
+    let mut payload = SensorMessagePayload {
+        posix_time,
+        bme_temperature: 0.0,
+        bme_pressure: 0.0,
+        bme_humidity: 0.0,
+        sgp30_eCO2: 0,
+        sgp30_TVOC: 0,
+        dht22_temperature: 0.0,
+        dht22_humidity: 0.0,
+        valid: 0,
+    };
+    match bme {
+        (Some(temperature), Some(pressure), Some(humidity)) => {
+            payload.bme_temperature = sensor_value::<Celsius>(temperature);
+            payload.bme_pressure = sensor_value::<Pascal>(pressure);
+            payload.bme_humidity = sensor_value::<RelativeHumidity>(humidity);
+            payload.valid |= VALID_BME280;
+        }
+        (None, None, None) => {}
+        _ => return Err("has some BME280 readings but not all of them".to_string()),
+    }
+    match sgp30 {
+        (Some(eco2), Some(tvoc)) => {
+            payload.sgp30_eCO2 = eco2 as u16;
+            payload.sgp30_TVOC = tvoc as u16;
+            payload.valid |= VALID_SGP30;
+        }
+        (None, None) => {}
+        _ => return Err("has some SGP30 readings but not all of them".to_string()),
+    }
+    if let Some(temperature) = dht22_temperature {
+        payload.dht22_temperature = sensor_value::<Celsius>(temperature);
+        payload.valid |= VALID_DHT22_TEMPERATURE;
+    }
+    if let Some(humidity) = dht22_humidity {
+        payload.dht22_humidity = sensor_value::<RelativeHumidity>(humidity);
+        payload.valid |= VALID_DHT22_HUMIDITY;
+    }
+    if payload.valid == 0 {
+        return Err("has no readings".to_string());
+    }
+    Ok(payload)
+}
+
+/// Reads and validates the rows of an import file. Rows measured before `earliest_time` are from
+/// an unsynced clock, and invalid.
+pub fn parse(
+    text: &str,
+    format: ImportFormat,
+    earliest_time: i64,
+) -> Vec<Result<ImportRow, InvalidRow>> {
+    let rows = match format {
+        ImportFormat::Csv => csv_rows(text),
+        ImportFormat::Ndjson => ndjson_rows(text),
+    };
+    rows.into_iter()
+        .map(|row| {
+            let (line, row) = row?;
+            let invalid = |reason| InvalidRow { line, reason };
+            Ok(ImportRow {
+                line,
+                station: string(&row, "Station").map_err(invalid)?,
+                received_time: time(&row, "ReceivedTime").map_err(invalid)?,
+                payload: payload(&row, earliest_time).map_err(invalid)?,
+            })
+        })
+        .collect()
+}
+
+/// What `import` was asked to do
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ImportOptions {
+    /// Station for rows without one
+    pub station: Option<String>,
+    /// Format of every file, instead of guessing from their extensions
+    pub format: Option<ImportFormat>,
+    pub paths: Vec<String>,
+}
+
+impl ImportOptions {
+    /// Parses the arguments that follow `import`
+    pub fn parse(args: &[String]) -> Result<Self, ImportError> {
+        let mut options = ImportOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ImportError::Usage(format!("{} needs a value", arg)))
+            };
+            match arg.as_str() {
+                "--station" => options.station = Some(value()?.clone()),
+                "--format" => {
+                    options.format = match value()?.as_str() {
+                        "csv" => Some(ImportFormat::Csv),
+                        "ndjson" => Some(ImportFormat::Ndjson),
+                        format => {
+                            return Err(ImportError::Usage(format!("Unknown format '{}'", format)))
+                        }
+                    };
+                }
+                _ if arg.starts_with("--") => {
+                    return Err(ImportError::Usage(format!("Unknown option '{}'", arg)))
+                }
+                _ => options.paths.push(arg.clone()),
+            }
+        }
+        if options.paths.is_empty() {
+            return Err(ImportError::Usage("No files to import".to_string()));
+        }
+        Ok(options)
+    }
+}
+
+/// How many rows of a file were stored or skipped
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ImportReport {
+    pub inserted: usize,
+    /// Rows measured at the same time as a reading already stored from their station
+    pub duplicates: usize,
+    pub invalid: usize,
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "inserted {} readings, skipped {} duplicates and {} invalid rows",
+            self.inserted, self.duplicates, self.invalid
+        )
+    }
+}
+
+/// Stores the rows of the file at `path`, `-` for standard input, printing the invalid rows to
+/// standard error
+pub fn import_file(
+    database: &mut WeatherDatabase,
+    config: &Config,
+    options: &ImportOptions,
+    path: &str,
+) -> Result<ImportReport, ImportError> {
+    let mut text = String::new();
+    let read = if path == "-" {
+        io::stdin().read_to_string(&mut text)
+    } else {
+        std::fs::File::open(path).and_then(|mut file| file.read_to_string(&mut text))
+    };
+    read.map_err(|error| ImportError::Read {
+        path: path.to_string(),
+        error,
+    })?;
+    let format = options
+        .format
+        .unwrap_or_else(|| ImportFormat::from_path(path));
+
+    // Every row from the file is received now, unless it says otherwise
+    let received_time = now();
+    let mut report = ImportReport::default();
+    for row in parse(&text, format, config.clock.earliest_time) {
+        let row = match row {
+            Ok(row) => row,
+            Err(invalid) => {
+                eprintln!("{} {}", path, invalid);
+                report.invalid += 1;
+                continue;
+            }
+        };
+        let station = row
+            .station
+            .as_deref()
+            .or(options.station.as_deref())
+            .unwrap_or(&config.default_station);
+        let inserted = database.import_reading(
+            station,
+            config.altitude(station),
+            row.received_time.unwrap_or(received_time),
+            &row.payload,
+        )?;
+        if inserted {
+            report.inserted += 1;
+        } else {
+            report.duplicates += 1;
+        }
+    }
+    database.flush()?;
+    Ok(report)
+}
@@ -0,0 +1,311 @@
+//! Writes readings to InfluxDB 2, for setups that already chart everything from InfluxDB.
+//!
+//! Readings and telemetry are written in line protocol through the `/api/v2/write` HTTP API. Each
+//! row that `WeatherDatabase` would store becomes a point with the same column names as fields,
+//! tagged with its station and timestamped with its measurement time in seconds.
+
+use crate::database::{CommitPolicy, IngestSettings};
+use crate::mqtt_message::{SensorMessagePayload, TelemetryPayload};
+use crate::reading::Reading;
+use crate::store::{ReadingStore, Result, StoreError};
+use crate::timestamp::now;
+use crate::units::FixedPoint;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// Most points kept waiting while InfluxDB can't be reached, about a day of readings from a
+/// handful of stations. Beyond this the oldest are dropped.
+const MAX_PENDING_LINES: usize = 10000;
+
+/// Where and how to write to InfluxDB
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct InfluxConfig {
+    /// Base URL of the InfluxDB server
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    /// API token with write access to the bucket
+    pub token: String,
+    /// Measurement the readings are written to. Telemetry goes to `device_telemetry`.
+    pub measurement: String,
+    /// Seconds to wait for InfluxDB to answer a write
+    pub timeout_secs: u64,
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        Self {
+            url: "http://localhost:8086".to_string(),
+            org: String::new(),
+            bucket: "weather".to_string(),
+            token: String::new(),
+            measurement: "weather_data".to_string(),
+            timeout_secs: 10,
+        }
+    }
+}
+
+/// Writes readings to InfluxDB, grouped into requests by a `CommitPolicy`.
+///
+/// Points that InfluxDB couldn't be reached for, or that it was too busy for, are kept and sent
+/// with the next request. Points it refuses as malformed are dropped. Sequence numbers are kept
+/// in memory only, so a restart forgets them.
+pub struct InfluxStore {
+    agent: ureq::Agent,
+    config: InfluxConfig,
+    commit_policy: CommitPolicy,
+    /// Points waiting to be sent, in line protocol
+    pending: Vec<String>,
+    /// When the oldest waiting point was added, or the last failed request was made
+    batch_started: Option<Instant>,
+    sequences: HashMap<String, u32>,
+}
+
+impl InfluxStore {
+    /// Writes to the server in `config`, sending each point straight away until
+    /// `with_commit_policy` says otherwise
+    pub fn new(config: &InfluxConfig) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build();
+        Self {
+            agent,
+            config: config.clone(),
+            commit_policy: CommitPolicy::immediate(),
+            pending: Vec::new(),
+            batch_started: None,
+            sequences: HashMap::new(),
+        }
+    }
+
+    /// Groups points into requests sent by `commit_policy`
+    pub fn with_commit_policy(mut self, commit_policy: CommitPolicy) -> Self {
+        self.commit_policy = commit_policy;
+        self
+    }
+
+    /// Points waiting to be sent
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Queues `lines` to be sent, sending them if the commit policy says so
+    fn write(&mut self, lines: impl IntoIterator<Item = String>) -> Result<()> {
+        self.pending.extend(lines);
+        if self.pending.len() > MAX_PENDING_LINES {
+            let dropped = self.pending.len() - MAX_PENDING_LINES;
+            eprintln!(
+                "InfluxDB write backlog is full, dropping {} points",
+                dropped
+            );
+            self.pending.drain(..dropped);
+        }
+        self.batch_started.get_or_insert_with(Instant::now);
+        self.commit_if_due()
+    }
+
+    fn insert_readings(
+        &mut self,
+        station: &str,
+        settings: &IngestSettings,
+        payloads: &[SensorMessagePayload],
+        buffered: bool,
+    ) -> Result<()> {
+        let received_time = now();
+        let lines: Vec<String> = payloads
+            .iter()
+            .map(|payload| {
+                let reading =
+                    Reading::from_payload(station, received_time, payload, settings, buffered);
+                reading_line(&self.config.measurement, &reading)
+            })
+            .collect();
+        self.write(lines)
+    }
+}
+
+impl ReadingStore for InfluxStore {
+    fn insert_sensor_data(
+        &mut self,
+        station: &str,
+        settings: &IngestSettings,
+        payload: &SensorMessagePayload,
+    ) -> Result<()> {
+        self.insert_readings(station, settings, std::slice::from_ref(payload), false)
+    }
+
+    fn insert_sensor_batch(
+        &mut self,
+        station: &str,
+        settings: &IngestSettings,
+        payloads: &[SensorMessagePayload],
+    ) -> Result<()> {
+        self.insert_readings(station, settings, payloads, true)
+    }
+
+    fn insert_telemetry(&mut self, station: &str, telemetry: &TelemetryPayload) -> Result<()> {
+        self.write([telemetry_line(station, now(), telemetry)])
+    }
+
+    fn last_sequence(&self, station: &str) -> Result<Option<u32>> {
+        Ok(self.sequences.get(station).copied())
+    }
+
+    fn set_last_sequence(&mut self, station: &str, sequence: u32) -> Result<()> {
+        self.sequences.insert(station.to_string(), sequence);
+        Ok(())
+    }
+
+    fn commit_if_due(&mut self) -> Result<()> {
+        let max_delay = Duration::from_millis(self.commit_policy.max_delay_ms);
+        match self.batch_started {
+            Some(started)
+                if self.pending.len() >= self.commit_policy.max_rows
+                    || started.elapsed() >= max_delay =>
+            {
+                self.flush()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends every waiting point in one request
+    fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let url = format!("{}/api/v2/write", self.config.url.trim_end_matches('/'));
+        let response = self
+            .agent
+            .post(&url)
+            .query("org", &self.config.org)
+            .query("bucket", &self.config.bucket)
+            .query("precision", "s")
+            .set("Authorization", &format!("Token {}", self.config.token))
+            .set("Content-Type", "text/plain; charset=utf-8")
+            .send_string(&self.pending.join("\n"));
+        match response {
+            Ok(_) => {
+                self.pending.clear();
+                self.batch_started = None;
+                Ok(())
+            }
+            // InfluxDB won't accept these points however often they are sent
+            Err(ureq::Error::Status(status, response)) if is_permanent(status) => {
+                self.pending.clear();
+                self.batch_started = None;
+                Err(StoreError::Rejected {
+                    status,
+                    message: response.into_string().unwrap_or_default(),
+                })
+            }
+            // Try again once the commit policy's delay has passed
+            Err(ureq::Error::Status(status, response)) => {
+                self.batch_started = Some(Instant::now());
+                Err(StoreError::Rejected {
+                    status,
+                    message: response.into_string().unwrap_or_default(),
+                })
+            }
+            Err(ureq::Error::Transport(transport)) => {
+                self.batch_started = Some(Instant::now());
+                Err(StoreError::Connection(transport.to_string()))
+            }
+        }
+    }
+}
+
+/// Whether a write that failed with HTTP `status` would fail again, such as for a malformed point
+/// or a bad token, rather than because InfluxDB was busy or broken
+fn is_permanent(status: u16) -> bool {
+    (400..500).contains(&status) && status != 408 && status != 429
+}
+
+/// Escapes a measurement name, which can't contain unescaped commas or spaces
+fn escape_measurement(name: &str) -> String {
+    name.replace(',', "\\,").replace(' ', "\\ ")
+}
+
+/// Escapes a tag value, which can't contain unescaped backslashes, commas, equals signs or spaces.
+/// Line breaks can't be escaped at all, so they are dropped.
+fn escape_tag(value: &str) -> String {
+    value
+        .replace(['\n', '\r'], "")
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// Quotes a string field value
+fn quote_field(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Adds a float field for `quantity`, unless it is missing
+fn push_quantity<T: FixedPoint>(fields: &mut Vec<String>, name: &str, quantity: Option<T>) {
+    if let Some(quantity) = quantity {
+        fields.push(format!("{}={}", name, quantity.value()));
+    }
+}
+
+/// Builds a point from the measurement, tags, fields and seconds timestamp
+fn line(measurement: &str, station: &str, fields: &[String], time: i64) -> String {
+    let mut line = format!(
+        "{},station={} ",
+        escape_measurement(measurement),
+        escape_tag(station)
+    );
+    line.push_str(&fields.join(","));
+    write!(line, " {}", time).unwrap();
+    line
+}
+
+/// The line protocol point for `reading`. Readings from failed sensors are left out, and its
+/// ClockStatus is only written when the clock was wrong.
+pub fn reading_line(measurement: &str, reading: &Reading) -> String {
+    let mut fields = Vec::new();
+    push_quantity(&mut fields, "TemperatureBME", reading.temperature_bme);
+    push_quantity(&mut fields, "TemperatureDHT22", reading.temperature_dht22);
+    push_quantity(&mut fields, "PressureBME", reading.pressure_bme);
+    push_quantity(&mut fields, "HumidityBME", reading.humidity_bme);
+    push_quantity(&mut fields, "HumidityDHT22", reading.humidity_dht22);
+    push_quantity(&mut fields, "eCO2SGP30", reading.eco2_sgp30);
+    push_quantity(&mut fields, "TVOCSGP30", reading.tvoc_sgp30);
+    let derived = &reading.derived;
+    push_quantity(&mut fields, "DewPoint", derived.dew_point);
+    push_quantity(&mut fields, "AbsoluteHumidity", derived.absolute_humidity);
+    push_quantity(&mut fields, "HeatIndex", derived.heat_index);
+    push_quantity(&mut fields, "Humidex", derived.humidex);
+    push_quantity(&mut fields, "PressureSeaLevel", derived.sea_level_pressure);
+    // A point needs at least one field, so this is always written
+    fields.push(format!("ReceivedTime={}i", reading.received_time));
+    if let Some(problem) = reading.clock_status {
+        fields.push(format!("ClockStatus={}", quote_field(problem.kind())));
+    }
+    line(
+        measurement,
+        &reading.station,
+        &fields,
+        reading.measurement_time,
+    )
+}
+
+/// The line protocol point for `telemetry` from `station`, in the `device_telemetry` measurement
+pub fn telemetry_line(station: &str, received_time: i64, telemetry: &TelemetryPayload) -> String {
+    let fields = [
+        format!("ReceivedTime={}i", received_time),
+        format!("BatteryMillivolts={}i", telemetry.battery_millivolts),
+        format!("WifiRssi={}i", telemetry.wifi_rssi),
+        format!("ResetReason={}i", telemetry.reset_reason),
+        format!("WakeCount={}i", telemetry.wake_count),
+        format!(
+            "FirmwareVersion={}",
+            quote_field(&telemetry.firmware_version)
+        ),
+    ];
+    line("device_telemetry", station, &fields, telemetry.posix_time)
+}
@@ -0,0 +1,82 @@
+//! Handling of each message the ingest loop receives, from decoding it to storing what it holds
+
+use crate::config::Config;
+use crate::mqtt_message::{check_sequence, MqttMessage, ParseError, ParseErrorCounts};
+use crate::store::ReadingStore;
+
+/// Counts and logs a message from `station` that is dropped because of `error`
+fn report_rejected(station: &str, error: &ParseError, error_counts: &mut ParseErrorCounts) {
+    let count = error_counts.record(error);
+    eprintln!(
+        "Rejected message from station '{}' ({} so far of kind `{}`): {}",
+        station,
+        count,
+        error.kind(),
+        error
+    );
+    eprintln!("Rejected messages since startup: {}", error_counts);
+}
+
+/// Callback run when an MQTT message is received.
+///
+/// Archives the payload as it was received, then decodes it and inserts it into the store, filed
+/// under the station named by the topic. The topic also picks the decoder for the payload.
+/// Messages that fail to parse or authenticate, and repeats of sequence numbers already stored, are
+/// counted by error kind in `error_counts` and dropped. Those that failed to parse or authenticate
+/// are kept as dead letters first.
+pub fn on_message(
+    config: &Config,
+    store: &mut impl ReadingStore,
+    topic: &str,
+    payload: &[u8],
+    error_counts: &mut ParseErrorCounts,
+) {
+    store
+        .archive_message(topic, payload)
+        .unwrap_or_else(|err| eprintln!("Failed to archive message: {}", err));
+
+    let (station, format) = config.route(topic);
+    let message = match config.decode_message(station, format, payload) {
+        Ok(message) => message,
+        Err(error) => {
+            store
+                .insert_dead_letter(topic, payload, &error)
+                .unwrap_or_else(|err| eprintln!("Failed to store dead letter: {}", err));
+            return report_rejected(station, &error, error_counts);
+        }
+    };
+
+    // Drop redelivered and replayed messages before they reach the store
+    let sequence = message.sequence();
+    if let Some(sequence) = sequence {
+        let last = match store.last_sequence(station) {
+            Ok(last) => last,
+            Err(err) => {
+                eprintln!("Failed to read last sequence number from store: {}", err);
+                return;
+            }
+        };
+        if let Err(error) = check_sequence(sequence, last) {
+            return report_rejected(station, &error, error_counts);
+        }
+    }
+
+    let settings = config.ingest_settings(station);
+    let inserted = match message {
+        MqttMessage::Reading(sensor_message) => store
+            .insert_sensor_data(station, &settings, &sensor_message.payload)
+            .map_err(|err| eprintln!("Failed to insert sensor payload into store: {}", err)),
+        MqttMessage::Batch(batch_message) => store
+            .insert_sensor_batch(station, &settings, &batch_message.payloads)
+            .map_err(|err| eprintln!("Failed to insert sensor batch into store: {}", err)),
+        MqttMessage::Telemetry(telemetry_message) => store
+            .insert_telemetry(station, &telemetry_message.payload)
+            .map_err(|err| eprintln!("Failed to insert telemetry into store: {}", err)),
+    };
+
+    if let (Ok(()), Some(sequence)) = (inserted, sequence) {
+        store
+            .set_last_sequence(station, sequence)
+            .unwrap_or_else(|err| eprintln!("Failed to store last sequence number: {}", err));
+    }
+}
@@ -0,0 +1,479 @@
+//! The `inspect` subcommand, for looking at the database without `sqlite3`.
+//!
+//! Lists the tables and their schema, or prints the latest rows of one table. Readings are shown
+//! in the units of `crate::units` rather than the stored fixed-point integers, and times as ISO
+//! 8601, unless `--raw` is given.
+
+use crate::timestamp::{format_iso8601, now, parse_duration, parse_iso8601};
+use crate::units::{AbsoluteHumidity, Celsius, FixedPoint, Pascal, Ppb, Ppm, RelativeHumidity};
+use rusqlite::types::Value;
+use rusqlite::{Connection, OpenFlags};
+use serde_json::Value as JsonValue;
+use std::fmt;
+use std::io::{self, Write};
+
+pub const USAGE: &str = "Usage: message_parser inspect [TABLE] [options]
+
+Without a table, lists every table with its row count and schema. With one, prints its latest rows.
+
+Options:
+  --database PATH     database to read, instead of the config's database_path
+  --limit N           number of rows to print, 10 by default, 0 for every row
+  --last DURATION     only rows from the last DURATION, e.g. 90m, 24h, 7d or 2w
+  --since TIME        only rows from TIME on, an ISO 8601 date such as 2025-03-15 or
+                      2025-03-15T12:00:00Z, or a POSIX time
+  --until TIME        only rows before TIME
+  --station NAME      only rows from station NAME
+  --format FORMAT     table (the default), csv, json or ndjson
+  --raw               print values as stored, without converting units or times";
+
+/// Columns holding POSIX times, in the order they are preferred for filtering and sorting rows
+const TIME_COLUMNS: [&str; 3] = ["MeasurementTime", "PeriodStart", "ReceivedTime"];
+
+/// Why `inspect` failed
+#[derive(Debug)]
+pub enum InspectError {
+    /// The command line doesn't make sense, with a description of what's wrong
+    Usage(String),
+    /// There is no table of this name, the database's tables are listed
+    UnknownTable {
+        table: String,
+        tables: Vec<String>,
+    },
+    Database(rusqlite::Error),
+    Output(io::Error),
+}
+
+impl fmt::Display for InspectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InspectError::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            InspectError::UnknownTable { table, tables } => write!(
+                f,
+                "No table '{}' in the database, it has: {}",
+                table,
+                tables.join(", ")
+            ),
+            InspectError::Database(err) => write!(f, "Database error: {}", err),
+            InspectError::Output(err) => write!(f, "Could not write output: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for InspectError {}
+
+impl From<rusqlite::Error> for InspectError {
+    fn from(err: rusqlite::Error) -> Self {
+        InspectError::Database(err)
+    }
+}
+
+impl From<io::Error> for InspectError {
+    fn from(err: io::Error) -> Self {
+        InspectError::Output(err)
+    }
+}
+
+/// How rows are printed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Aligned columns, with units in the header
+    Table,
+    Csv,
+    /// One array of objects
+    Json,
+    /// One object per line
+    Ndjson,
+}
+
+/// What `inspect` was asked to print
+#[derive(Debug, Clone, PartialEq)]
+pub struct InspectOptions {
+    pub database: Option<String>,
+    /// The table to print rows of, or `None` to list the tables
+    pub table: Option<String>,
+    /// Most rows printed, `None` for every row
+    pub limit: Option<usize>,
+    /// Earliest POSIX time printed
+    pub since: Option<i64>,
+    /// POSIX time before which rows are printed
+    pub until: Option<i64>,
+    pub station: Option<String>,
+    pub format: Format,
+    pub raw: bool,
+}
+
+impl Default for InspectOptions {
+    fn default() -> Self {
+        Self {
+            database: None,
+            table: None,
+            limit: Some(10),
+            since: None,
+            until: None,
+            station: None,
+            format: Format::Table,
+            raw: false,
+        }
+    }
+}
+
+impl InspectOptions {
+    /// Parses the arguments that follow `inspect`
+    pub fn parse(args: &[String]) -> Result<Self, InspectError> {
+        let mut options = InspectOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| InspectError::Usage(format!("{} needs a value", arg)))
+            };
+            match arg.as_str() {
+                "--database" => options.database = Some(value()?.clone()),
+                "--limit" => {
+                    let limit = value()?;
+                    options.limit = match limit.parse() {
+                        Ok(0) => None,
+                        Ok(limit) => Some(limit),
+                        Err(_) => {
+                            return Err(InspectError::Usage(format!("Invalid limit '{}'", limit)))
+                        }
+                    };
+                }
+                "--last" => {
+                    let duration = value()?;
+                    let seconds = parse_duration(duration).ok_or_else(|| {
+                        InspectError::Usage(format!("Invalid duration '{}'", duration))
+                    })?;
+                    options.since = Some(now() - seconds);
+                }
+                "--since" => options.since = Some(parse_time(value()?)?),
+                "--until" => options.until = Some(parse_time(value()?)?),
+                "--station" => options.station = Some(value()?.clone()),
+                "--format" => {
+                    options.format = match value()?.as_str() {
+                        "table" => Format::Table,
+                        "csv" => Format::Csv,
+                        "json" => Format::Json,
+                        "ndjson" => Format::Ndjson,
+                        format => {
+                            return Err(InspectError::Usage(format!("Unknown format '{}'", format)))
+                        }
+                    };
+                }
+                "--raw" => options.raw = true,
+                _ if arg.starts_with("--") => {
+                    return Err(InspectError::Usage(format!("Unknown option '{}'", arg)))
+                }
+                _ if options.table.is_none() => options.table = Some(arg.clone()),
+                _ => {
+                    return Err(InspectError::Usage(format!(
+                        "Unexpected argument '{}'",
+                        arg
+                    )))
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn parse_time(time: &str) -> Result<i64, InspectError> {
+    parse_iso8601(time).ok_or_else(|| InspectError::Usage(format!("Invalid time '{}'", time)))
+}
+
+/// Opens the database at `path` for reading only, failing if it doesn't exist
+pub fn open(path: &str) -> Result<Connection, InspectError> {
+    Ok(Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?)
+}
+
+/// Prints what `options` asks for from the database `conn` to `out`
+pub fn run(
+    conn: &Connection,
+    options: &InspectOptions,
+    out: &mut impl Write,
+) -> Result<(), InspectError> {
+    match &options.table {
+        Some(table) => print_rows(conn, table, options, out),
+        None => print_tables(conn, out),
+    }
+}
+
+/// Names of the database's tables, leaving out SQLite's own
+fn table_names(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'
+        ORDER BY name",
+    )?;
+    let names = stmt.query_map([], |row| row.get(0))?;
+    names.collect()
+}
+
+/// Quotes `name` for use as an identifier in SQL
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Prints the schema version, then each table with its row count, definition and indexes
+fn print_tables(conn: &Connection, out: &mut impl Write) -> Result<(), InspectError> {
+    let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    writeln!(out, "Schema version {}", version)?;
+    let mut definitions = conn.prepare(
+        "SELECT sql FROM sqlite_master WHERE tbl_name = ?1 AND sql IS NOT NULL
+        ORDER BY type = 'index', name",
+    )?;
+    for table in table_names(conn)? {
+        let rows: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM {}", quote_identifier(&table)),
+            [],
+            |row| row.get(0),
+        )?;
+        writeln!(out, "\n{} ({} rows)", table, rows)?;
+        let sqls = definitions.query_map([&table], |row| row.get::<_, String>(0))?;
+        for sql in sqls {
+            for line in sql?.lines() {
+                writeln!(out, "    {}", line.trim())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The scale and unit symbol of a column of readings, also matching the `<Column>Min`, `Max` and
+/// `Mean` columns of the rollups
+fn column_unit(column: &str) -> Option<(f32, &'static str)> {
+    let column = ["Min", "Max", "Mean"]
+        .iter()
+        .find_map(|suffix| column.strip_suffix(suffix))
+        .unwrap_or(column);
+    fn unit<T: FixedPoint>() -> Option<(f32, &'static str)> {
+        Some((T::SCALE, T::SYMBOL))
+    }
+    match column {
+        "TemperatureBME" | "TemperatureDHT22" | "DewPoint" | "HeatIndex" | "Humidex" => {
+            unit::<Celsius>()
+        }
+        "PressureBME" | "PressureSeaLevel" => unit::<Pascal>(),
+        "HumidityBME" | "HumidityDHT22" => unit::<RelativeHumidity>(),
+        "AbsoluteHumidity" => unit::<AbsoluteHumidity>(),
+        "eCO2SGP30" => unit::<Ppm>(),
+        "TVOCSGP30" => unit::<Ppb>(),
+        _ => None,
+    }
+}
+
+/// `value` from `column` as JSON, converted to human units unless `raw`
+fn convert(column: &str, value: Value, raw: bool) -> JsonValue {
+    let float = |float: f64| {
+        serde_json::Number::from_f64(float)
+            .map(JsonValue::Number)
+            .unwrap_or(JsonValue::Null)
+    };
+    match value {
+        Value::Null => JsonValue::Null,
+        Value::Integer(time) if !raw && TIME_COLUMNS.contains(&column) => {
+            JsonValue::String(format_iso8601(time))
+        }
+        // Whole units such as pascals stay integers
+        Value::Integer(integer) => match column_unit(column) {
+            Some((scale, _)) if !raw && scale != 1.0 => float(integer as f64 / scale as f64),
+            _ => JsonValue::from(integer),
+        },
+        Value::Real(real) => match column_unit(column) {
+            Some((scale, _)) if !raw => float(real / scale as f64),
+            _ => float(real),
+        },
+        Value::Text(text) => JsonValue::String(text),
+        Value::Blob(blob) => {
+            JsonValue::String(blob.iter().map(|byte| format!("{:02x}", byte)).collect())
+        }
+    }
+}
+
+/// `value` as plain text, empty for NULL
+fn text(value: &JsonValue) -> String {
+    match value {
+        JsonValue::Null => String::new(),
+        JsonValue::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Quotes a CSV field if it needs it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// A row as a JSON object, with its keys in column order
+fn json_object(columns: &[String], row: &[JsonValue]) -> String {
+    let fields: Vec<String> = columns
+        .iter()
+        .zip(row)
+        .map(|(column, value)| format!("{}:{}", JsonValue::from(column.as_str()), value))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// Prints the latest rows of `table` chosen by `options`, oldest first
+fn print_rows(
+    conn: &Connection,
+    table: &str,
+    options: &InspectOptions,
+    out: &mut impl Write,
+) -> Result<(), InspectError> {
+    // Only tables that exist are put into SQL
+    let tables = table_names(conn)?;
+    if !tables.iter().any(|name| name == table) {
+        return Err(InspectError::UnknownTable {
+            table: table.to_string(),
+            tables,
+        });
+    }
+    let columns: Vec<String> = conn
+        .prepare("SELECT name FROM pragma_table_info(?1) ORDER BY cid")?
+        .query_map([table], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    let time_column = TIME_COLUMNS
+        .into_iter()
+        .find(|column| columns.iter().any(|name| name == column));
+
+    let mut conditions = Vec::new();
+    let mut params: Vec<Value> = Vec::new();
+    if options.since.is_some() || options.until.is_some() {
+        let Some(time_column) = time_column else {
+            return Err(InspectError::Usage(format!(
+                "Table '{}' has no time column to filter by",
+                table
+            )));
+        };
+        if let Some(since) = options.since {
+            params.push(Value::Integer(since));
+            conditions.push(format!("{} >= ?{}", time_column, params.len()));
+        }
+        if let Some(until) = options.until {
+            params.push(Value::Integer(until));
+            conditions.push(format!("{} < ?{}", time_column, params.len()));
+        }
+    }
+    if let Some(station) = &options.station {
+        if !columns.iter().any(|name| name == "Station") {
+            return Err(InspectError::Usage(format!(
+                "Table '{}' has no Station column to filter by",
+                table
+            )));
+        }
+        params.push(Value::Text(station.clone()));
+        conditions.push(format!("Station = ?{}", params.len()));
+    }
+    params.push(Value::Integer(
+        options.limit.map_or(-1, |limit| limit as i64),
+    ));
+
+    let sql = format!(
+        "SELECT * FROM {} {} ORDER BY {} DESC LIMIT ?{}",
+        quote_identifier(table),
+        if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        },
+        time_column.unwrap_or("rowid"),
+        params.len()
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows: Vec<Vec<JsonValue>> = stmt
+        .query_map(rusqlite::params_from_iter(params), |row| {
+            columns
+                .iter()
+                .enumerate()
+                .map(|(index, column)| Ok(convert(column, row.get(index)?, options.raw)))
+                .collect()
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    rows.reverse();
+
+    match options.format {
+        Format::Table => write_table(&columns, &rows, options.raw, out)?,
+        Format::Csv => {
+            let header: Vec<String> = columns.iter().map(|column| csv_field(column)).collect();
+            writeln!(out, "{}", header.join(","))?;
+            for row in &rows {
+                let fields: Vec<String> = row.iter().map(|value| csv_field(&text(value))).collect();
+                writeln!(out, "{}", fields.join(","))?;
+            }
+        }
+        Format::Json => {
+            let objects: Vec<String> = rows
+                .iter()
+                .map(|row| format!("  {}", json_object(&columns, row)))
+                .collect();
+            if objects.is_empty() {
+                writeln!(out, "[]")?;
+            } else {
+                writeln!(out, "[\n{}\n]", objects.join(",\n"))?;
+            }
+        }
+        Format::Ndjson => {
+            for row in &rows {
+                writeln!(out, "{}", json_object(&columns, row))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints `rows` in aligned columns, headed by the column names and, unless `raw`, their units
+pub(crate) fn write_table(
+    columns: &[String],
+    rows: &[Vec<JsonValue>],
+    raw: bool,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let header: Vec<String> = columns
+        .iter()
+        .map(|column| match column_unit(column) {
+            Some((_, symbol)) if !raw => format!("{} ({})", column, symbol),
+            _ => column.clone(),
+        })
+        .collect();
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| row.iter().map(text).collect())
+        .collect();
+    let widths: Vec<usize> = (0..columns.len())
+        .map(|index| {
+            cells
+                .iter()
+                .map(|row| row[index].chars().count())
+                .chain([header[index].chars().count()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let write_line = |out: &mut dyn Write, fields: &[String]| {
+        let padded: Vec<String> = fields
+            .iter()
+            .zip(&widths)
+            .map(|(field, width)| {
+                let padding = width - field.chars().count();
+                format!("{}{}", field, " ".repeat(padding))
+            })
+            .collect();
+        writeln!(out, "{}", padded.join("  ").trim_end())
+    };
+    write_line(out, &header)?;
+    let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    write_line(out, &rule)?;
+    for row in &cells {
+        write_line(out, row)?;
+    }
+    Ok(())
+}
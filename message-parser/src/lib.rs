@@ -0,0 +1,24 @@
+//! Parses weather station MQTT messages and stores them in the SQLite database.
+//!
+//! The binary in `main.rs` runs the ingest loop on the Pi. The modules are also a library so that
+//! tests, fuzz targets and tools such as simulators can share the same message format.
+
+pub mod backup;
+pub mod clock;
+pub mod config;
+pub mod database;
+pub mod dead_letter;
+pub mod derived;
+pub mod import;
+pub mod influx;
+pub mod ingest;
+pub mod inspect;
+pub mod migrations;
+pub mod mqtt_message;
+pub mod reading;
+pub mod reprocess;
+pub mod retention;
+pub mod rollup;
+pub mod store;
+pub mod timestamp;
+pub mod units;
@@ -1,84 +1,453 @@
-use crate::database::WeatherDatabase;
-use crate::mqtt_message::SensorMessage;
-use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
-use std::env;
+use message_parser::backup::{self, BackupScheduler};
+use message_parser::config::{Config, STATION_TOPIC_FILTER, TELEMETRY_TOPIC_FILTER};
+use message_parser::database::WeatherDatabase;
+use message_parser::dead_letter::{self, Command};
+use message_parser::import::{self, ImportOptions};
+use message_parser::influx::InfluxStore;
+use message_parser::ingest::on_message;
+use message_parser::inspect::{self, InspectError, InspectOptions};
+use message_parser::migrations::{MIGRATIONS, SCHEMA_VERSION};
+use message_parser::mqtt_message::{reset_reason_name, ParseErrorCounts};
+use message_parser::reprocess::{self, ReprocessOptions};
+use message_parser::retention::Pruner;
+use message_parser::store::{ReadingStore, StoreBackend};
+use rumqttc::{Client, Connection, Event, MqttOptions, Packet, QoS, RecvTimeoutError};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use std::{env, process};
 
-mod database;
-mod mqtt_message;
+const CONFIG_PATH: &str = "config.toml";
 
-const DATABASE_PATH: &str = "database.db";
-
-/// Callback run when an MQTT message is received.
+/// Stores the messages received on `mqtt_connection` until it is closed.
 ///
-/// Reads the reformats the payload for the database and inserts it to the database.
-fn on_message(payload: &[u8]) {
-    let sensor_message = match SensorMessage::from_bytes(payload) {
-        Ok(message) => message,
+/// `maintain` is run on the store after each message, and whenever nothing has arrived for as long
+/// as writes may wait to be committed. It returns whether it has more to do, in which case the
+/// loop doesn't wait for messages before running it again.
+fn ingest<S: ReadingStore>(
+    config: &Config,
+    store: &mut S,
+    mqtt_connection: &mut Connection,
+    mut maintain: impl FnMut(&mut S) -> bool,
+) {
+    let commit_interval = Duration::from_millis(config.commit.max_delay_ms.max(100));
+    let mut error_counts = ParseErrorCounts::default();
+    let mut maintaining = false;
+    loop {
+        let timeout = if maintaining {
+            Duration::from_millis(10)
+        } else {
+            commit_interval
+        };
+        match mqtt_connection.recv_timeout(timeout) {
+            Ok(Ok(Event::Incoming(Packet::Publish(pub_packet)))) => {
+                on_message(
+                    config,
+                    store,
+                    &pub_packet.topic,
+                    &pub_packet.payload,
+                    &mut error_counts,
+                );
+            }
+            Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => {}
+            Ok(Err(conn_err)) => println!("Connection error: {:?}", conn_err),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        store
+            .commit_if_due()
+            .unwrap_or_else(|err| eprintln!("Failed to commit to store: {}", err));
+        maintaining = maintain(store);
+    }
+    store
+        .flush()
+        .unwrap_or_else(|err| eprintln!("Failed to commit to store: {}", err));
+}
+
+fn test_database(config: &Config) {
+    let database_conn = match WeatherDatabase::new(&config.database_path) {
+        Ok(conn) => conn,
         Err(error) => {
-            eprintln!("Error parsing message bytes: {}", error);
+            eprintln!("Error connecting to database for test: {}", error);
             return;
         }
     };
+    database_conn
+        .test_sqlite()
+        .unwrap_or_else(|err| eprintln!("Database test failed: {}", err));
+}
 
-    let database_conn = match WeatherDatabase::new(DATABASE_PATH) {
-        Ok(conn) => conn,
-        Err(error) => {
-            eprintln!("Error connecting to database `on_message`: {}", error);
-            return;
+/// Prints each station already in the database, with its latest telemetry
+fn print_stations(database_conn: &WeatherDatabase) {
+    match database_conn.station_summaries() {
+        Ok(summaries) => {
+            for summary in summaries {
+                println!(
+                    "Station '{}': {} readings, last measured at {:?}",
+                    summary.station, summary.reading_count, summary.last_measurement_time
+                );
+                match database_conn.latest_telemetry(&summary.station) {
+                    Ok(Some(record)) => {
+                        let telemetry = record.telemetry;
+                        println!(
+                            "  firmware {}, battery {} mV, RSSI {} dBm, {} wakes, last reset: {}",
+                            telemetry.firmware_version,
+                            telemetry.battery_millivolts,
+                            telemetry.wifi_rssi,
+                            telemetry.wake_count,
+                            reset_reason_name(telemetry.reset_reason)
+                        );
+                    }
+                    Ok(None) => {}
+                    Err(err) => eprintln!("Failed to read telemetry: {}", err),
+                }
+            }
+        }
+        Err(err) => eprintln!("Failed to list stations: {}", err),
+    }
+    match database_conn.clock_stats() {
+        Ok(stats) => {
+            for stats in stats {
+                println!(
+                    "Station '{}' clock: mean skew {:?} s over {} readings (min {:?}, max {:?}, last {:?}), {} unsynced, {} skewed",
+                    stats.station,
+                    stats.mean_skew,
+                    stats.samples,
+                    stats.min_skew,
+                    stats.max_skew,
+                    stats.last_skew,
+                    stats.unsynced_count,
+                    stats.skewed_count
+                );
+            }
+        }
+        Err(err) => eprintln!("Failed to read clock statistics: {}", err),
+    }
+}
+
+/// Prints the database's schema version against this build's, returning whether they match
+fn check_schema(database_conn: &WeatherDatabase) -> bool {
+    let version = database_conn
+        .schema_version()
+        .expect("Could not read schema version");
+    println!(
+        "Database schema version {}, parser expects {}",
+        version, SCHEMA_VERSION
+    );
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        println!(
+            "  pending migration {}: {}",
+            index + 1,
+            migration.description
+        );
+    }
+    version == SCHEMA_VERSION
+}
+
+/// Runs the `inspect` subcommand with the arguments after it, returning the exit code
+fn inspect_database(args: &[String]) -> i32 {
+    if args.iter().any(|arg| arg == "--help") {
+        println!("{}", inspect::USAGE);
+        return 0;
+    }
+    let result = InspectOptions::parse(args).and_then(|options| {
+        let path = match &options.database {
+            Some(path) => path.clone(),
+            None => {
+                Config::load(CONFIG_PATH)
+                    .expect("Could not read config file")
+                    .database_path
+            }
+        };
+        let conn = inspect::open(&path)?;
+        let mut out = io::BufWriter::new(io::stdout().lock());
+        inspect::run(&conn, &options, &mut out)?;
+        Ok(out.flush()?)
+    });
+    match result {
+        Ok(()) => 0,
+        // The output was piped into something like `head` that stopped reading
+        Err(InspectError::Output(err)) if err.kind() == io::ErrorKind::BrokenPipe => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+/// Runs the `import` subcommand with the arguments after it, returning the exit code
+fn import_files(config: &Config, mut database_conn: WeatherDatabase, args: &[String]) -> i32 {
+    if args.iter().any(|arg| arg == "--help") {
+        println!("{}", import::USAGE);
+        return 0;
+    }
+    let options = match ImportOptions::parse(args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
         }
     };
+    database_conn
+        .migrate(&config.default_station)
+        .expect("Migration failed");
+    let mut database_conn = database_conn.with_commit_policy(config.commit.clone());
+    for path in &options.paths {
+        match import::import_file(&mut database_conn, config, &options, path) {
+            Ok(report) => println!("{}: {}", path, report),
+            Err(err) => {
+                eprintln!("{}", err);
+                return 1;
+            }
+        }
+    }
+    0
+}
 
+/// Runs the `reprocess` subcommand with the arguments after it, returning the exit code
+fn reprocess_messages(config: &Config, mut database_conn: WeatherDatabase, args: &[String]) -> i32 {
+    if args.iter().any(|arg| arg == "--help") {
+        println!("{}", reprocess::USAGE);
+        return 0;
+    }
+    let options = match ReprocessOptions::parse(args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
     database_conn
-        .insert_sensor_data(&sensor_message.payload)
-        .unwrap_or_else(|err| eprintln!("Failed to insert sensor payload into database: {}", err));
+        .migrate(&config.default_station)
+        .expect("Migration failed");
+    let mut database_conn = database_conn.with_commit_policy(config.commit.clone());
+    match reprocess::reprocess(&mut database_conn, config, &options) {
+        Ok(report) => {
+            println!("{}", report);
+            0
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
 }
 
-fn test_database() {
-    let database_conn = match WeatherDatabase::new(DATABASE_PATH) {
-        Ok(conn) => conn,
-        Err(error) => {
-            eprintln!("Error connecting to database for test: {}", error);
-            return;
+/// Runs the `dead-letters` subcommand with the arguments after it, returning the exit code
+fn manage_dead_letters(
+    config: &Config,
+    mut database_conn: WeatherDatabase,
+    args: &[String],
+) -> i32 {
+    if args.iter().any(|arg| arg == "--help") {
+        println!("{}", dead_letter::USAGE);
+        return 0;
+    }
+    let command = match Command::parse(args) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
         }
     };
     database_conn
-        .test_sqlite()
-        .unwrap_or_else(|err| eprintln!("Database test failed: {}", err));
+        .migrate(&config.default_station)
+        .expect("Migration failed");
+    let mut database_conn = database_conn.with_commit_policy(config.commit.clone());
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    let result = dead_letter::run(&mut database_conn, config, &command, &mut out)
+        .and_then(|()| Ok(out.flush()?));
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+/// Runs the `restore` subcommand with the arguments after it, returning the exit code
+fn restore_database(config: &Config, args: &[String]) -> i32 {
+    let path = match args {
+        [path] if path != "--help" => Path::new(path),
+        [help] if help == "--help" => {
+            println!("{}", backup::USAGE);
+            return 0;
+        }
+        _ => {
+            eprintln!("{}", backup::USAGE);
+            return 1;
+        }
+    };
+    let result = WeatherDatabase::new(&config.database_path)
+        .map_err(backup::BackupError::from)
+        .and_then(|mut database_conn| backup::restore(&mut database_conn, path));
+    match result {
+        Ok(()) => {
+            println!("Restored {} from {}", config.database_path, path.display());
+            0
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+/// Backs the database up into `directory`, or the config's backup directory, returning the exit
+/// code
+fn backup_database(config: &Config, directory: Option<&String>) -> i32 {
+    let Some(directory) = directory.or(config.backup.directory.as_ref()) else {
+        eprintln!("No backup directory given, and none in the config's [backup] section");
+        return 1;
+    };
+    match backup::backup_and_rotate(&config.database_path, Path::new(directory), &config.backup) {
+        Ok(report) => {
+            println!("{}", report);
+            0
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
 }
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    // `inspect` only reads the database, so it runs before anything opens or migrates it
+    if args.get(1).map(String::as_str) == Some("inspect") {
+        process::exit(inspect_database(&args[2..]));
+    }
+    let config = Config::load(CONFIG_PATH).expect("Could not read config file");
+    // `restore` replaces the database, which may be too damaged to open otherwise
+    if args.get(1).map(String::as_str) == Some("restore") {
+        process::exit(restore_database(&config, &args[2..]));
+    }
+
+    let mut database_conn =
+        WeatherDatabase::new(&config.database_path).expect("Could not connect to database");
+    let version = database_conn
+        .schema_version()
+        .expect("Could not read schema version");
+    if version > SCHEMA_VERSION {
+        eprintln!(
+            "Database schema version {} is newer than this parser supports ({}), refusing to use it",
+            version, SCHEMA_VERSION
+        );
+        process::exit(1);
+    }
+
+    // `--check-schema` reports pending migrations, failing if there are any, and `--migrate`
+    // applies them. `--rebuild-rollups` recomputes the hourly and daily rollups from the stored
+    // readings, `--prune` deletes expired rows now, and `--backup` takes a backup now. `import`
+    // stores readings from files, `reprocess` decodes archived messages again, and `dead-letters`
+    // looks at and retries the messages that couldn't be decoded. All exit without connecting to
+    // MQTT.
+    match args.get(1).map(String::as_str) {
+        Some("--check-schema") => process::exit(if check_schema(&database_conn) { 0 } else { 1 }),
+        Some("--migrate") => {
+            let applied = database_conn
+                .migrate(&config.default_station)
+                .expect("Migration failed");
+            for description in &applied {
+                println!("Applied migration: {}", description);
+            }
+            println!("Database schema is at version {}", SCHEMA_VERSION);
+            process::exit(0);
+        }
+        Some("--rebuild-rollups") => {
+            database_conn
+                .migrate(&config.default_station)
+                .expect("Migration failed");
+            let rows = database_conn
+                .rebuild_rollups()
+                .expect("Could not rebuild rollups");
+            println!("Rebuilt {} rollup rows", rows);
+            process::exit(0);
+        }
+        Some("import") => process::exit(import_files(&config, database_conn, &args[2..])),
+        Some("dead-letters") => {
+            process::exit(manage_dead_letters(&config, database_conn, &args[2..]))
+        }
+        Some("reprocess") => process::exit(reprocess_messages(&config, database_conn, &args[2..])),
+        Some("--prune") => {
+            database_conn
+                .migrate(&config.default_station)
+                .expect("Migration failed");
+            Pruner::new(&config.retention)
+                .run(&mut database_conn)
+                .expect("Pruning failed");
+            process::exit(0);
+        }
+        Some("--backup") => process::exit(backup_database(&config, args.get(2))),
+        _ => {}
+    }
+
     // Connect to the MQTT server
-    let options = MqttOptions::new("RpiServer", "localhost", 1883);
+    let options = MqttOptions::new(
+        config.mqtt.client_id.as_str(),
+        config.mqtt.host.as_str(),
+        config.mqtt.port,
+    );
     let (mqtt_client, mut mqtt_connection) = Client::new(options, 10);
 
     println!("Database connection initialised and MQTT connected.");
 
-    // Verify presence of tables or create if necessary
-    WeatherDatabase::new(DATABASE_PATH)
-        .expect("Could not connect to database")
-        .create_tables()
+    // Bring the tables up to date, creating them if necessary
+    database_conn
+        .create_tables(&config.default_station)
         .expect("Could not create or verify tables");
+    print_stations(&database_conn);
 
     // If passed the `--setup-test` argument, setup the database and test it!
-    let args: Vec<String> = env::args().collect();
     if args.len() > 1 && args[1] == "--setup-test" {
-        test_database();
+        test_database(&config);
         println!("Tests successful");
     }
 
-    // Subscribe to the MQTT channel
+    // Subscribe to every station's topics, the topics of firmware that predates them, and any
+    // topics from the config
     mqtt_client
-        .subscribe("weather/station", QoS::AtMostOnce)
-        .expect("Couldn't subscribe to 'WeatherStation'");
-
-    for notification in mqtt_connection.iter() {
-        match notification {
-            Ok(Event::Incoming(Packet::Publish(pub_packet))) => {
-                // let topic = pub_packet.topic;  // All topic should be WeatherStation...
-                on_message(&pub_packet.payload);
-            }
-            Ok(_) => {}
-            Err(conn_err) => println!("Connection error: {:?}", conn_err),
-        };
+        .subscribe(STATION_TOPIC_FILTER, QoS::AtMostOnce)
+        .unwrap_or_else(|_| panic!("Couldn't subscribe to '{}'", STATION_TOPIC_FILTER));
+    mqtt_client
+        .subscribe(TELEMETRY_TOPIC_FILTER, QoS::AtMostOnce)
+        .unwrap_or_else(|_| panic!("Couldn't subscribe to '{}'", TELEMETRY_TOPIC_FILTER));
+    let configured_topics = config.topics.iter().map(|entry| &entry.filter);
+    for topic in config.mqtt.legacy_topics.iter().chain(configured_topics) {
+        mqtt_client
+            .subscribe(topic, QoS::AtMostOnce)
+            .unwrap_or_else(|_| panic!("Couldn't subscribe to '{}'", topic));
+    }
+
+    // Group writes into transactions from here on, waking up at least as often as they must be
+    // committed even if nothing arrives
+    match config.store {
+        StoreBackend::Sqlite => {
+            let mut database_conn = database_conn.with_commit_policy(config.commit.clone());
+            // Prune a batch of expired rows between messages when a run is due, and back up in the
+            // background
+            let mut pruner = Pruner::new(&config.retention);
+            let mut backups = BackupScheduler::new(&config.database_path, &config.backup);
+            ingest(
+                &config,
+                &mut database_conn,
+                &mut mqtt_connection,
+                |database_conn| {
+                    backups.poll();
+                    pruner.step(database_conn).unwrap_or_else(|err| {
+                        eprintln!("Failed to prune database: {}", err);
+                        false
+                    })
+                },
+            );
+        }
+        // InfluxDB applies its own retention policies, so there is nothing to maintain
+        StoreBackend::Influxdb => {
+            println!("Writing readings to InfluxDB at {}", config.influxdb.url);
+            let mut store =
+                InfluxStore::new(&config.influxdb).with_commit_policy(config.commit.clone());
+            ingest(&config, &mut store, &mut mqtt_connection, |_| false);
+        }
     }
 }
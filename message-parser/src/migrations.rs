@@ -0,0 +1,250 @@
+//! Versioned changes to the database schema.
+//!
+//! The schema version is kept in SQLite's `PRAGMA user_version`, which is 0 for a new database and
+//! for every database from before migrations existed. Each migration moves the schema up one
+//! version, inside its own transaction, so a Pi's database can be upgraded in place.
+//!
+//! Migrations 1 to 6 replay the changes `create_tables` used to make without recording a version,
+//! so they skip whatever a database already has. Later migrations can assume the version is right.
+
+use crate::rollup;
+use rusqlite::{Connection, Result};
+
+/// A change to the schema, taking it from the previous version to the next
+pub struct Migration {
+    pub description: &'static str,
+    /// Applies the change. `default_station` owns any readings from before stations existed.
+    apply: fn(&Connection, &str) -> Result<()>,
+}
+
+/// Every migration in order, the first taking version 0 to version 1
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "create weather_data",
+        apply: create_weather_data,
+    },
+    Migration {
+        description: "add Station to weather_data",
+        apply: add_station,
+    },
+    Migration {
+        description: "create station_sequence",
+        apply: create_station_sequence,
+    },
+    Migration {
+        description: "create device_telemetry",
+        apply: create_device_telemetry,
+    },
+    Migration {
+        description: "add derived quantities to weather_data",
+        apply: add_derived_quantities,
+    },
+    Migration {
+        description: "add ClockStatus to weather_data and create station_clock",
+        apply: add_clock_checks,
+    },
+    Migration {
+        description: "create weather_hourly and weather_daily rollups",
+        apply: create_rollups,
+    },
+    Migration {
+        description: "create raw_messages and add RawMessageId to weather_data",
+        apply: create_raw_messages,
+    },
+    Migration {
+        description: "create dead_letters",
+        apply: create_dead_letters,
+    },
+    Migration {
+        description: "index raw_messages by Topic and Payload",
+        apply: index_raw_message_payloads,
+    },
+];
+
+/// The schema version this build of the parser reads and writes
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// The schema version of the database on `conn`
+pub fn schema_version(conn: &Connection) -> Result<u32> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// Applies the migrations after the database's version, up to and including `target`. Returns
+/// the descriptions of those applied.
+///
+/// Does nothing to a database at or past `target`, leaving callers to refuse databases newer than
+/// `SCHEMA_VERSION`.
+pub fn migrate_to(
+    conn: &mut Connection,
+    target: u32,
+    default_station: &str,
+) -> Result<Vec<&'static str>> {
+    let mut applied = Vec::new();
+    for version in schema_version(conn)? + 1..=target.min(SCHEMA_VERSION) {
+        let migration = &MIGRATIONS[version as usize - 1];
+        let transaction = conn.transaction()?;
+        (migration.apply)(&transaction, default_station)?;
+        transaction.pragma_update(None, "user_version", version)?;
+        transaction.commit()?;
+        applied.push(migration.description);
+    }
+    Ok(applied)
+}
+
+/// Adds `column` to `table` unless it is already there
+fn add_column(conn: &Connection, table: &str, column: &str, column_type: &str) -> Result<()> {
+    let exists = conn
+        .prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?
+        .exists([table, column])?;
+    if !exists {
+        conn.execute(
+            &format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, column_type
+            ),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+fn create_weather_data(conn: &Connection, _: &str) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS weather_data (
+        MeasurementTime INTEGER,
+        ReceivedTime INTEGER,
+        TemperatureBME INTEGER,
+        TemperatureDHT22 INTEGER,
+        PressureBME INTEGER,
+        HumidityBME INTEGER,
+        HumidityDHT22 INTEGER,
+        eCO2SGP30 INTEGER,
+        TVOCSGP30 INTEGER
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_station(conn: &Connection, default_station: &str) -> Result<()> {
+    add_column(conn, "weather_data", "Station", "TEXT")?;
+    conn.execute(
+        "UPDATE weather_data SET Station = ?1 WHERE Station IS NULL",
+        [default_station],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS weather_data_station_time
+        ON weather_data (Station, MeasurementTime)",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_station_sequence(conn: &Connection, _: &str) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS station_sequence (
+        Station TEXT PRIMARY KEY,
+        LastSequence INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_device_telemetry(conn: &Connection, _: &str) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS device_telemetry (
+        MeasurementTime INTEGER,
+        ReceivedTime INTEGER,
+        Station TEXT,
+        BatteryMillivolts INTEGER,
+        WifiRssi INTEGER,
+        ResetReason INTEGER,
+        WakeCount INTEGER,
+        FirmwareVersion TEXT
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS device_telemetry_station_time
+        ON device_telemetry (Station, MeasurementTime)",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_derived_quantities(conn: &Connection, _: &str) -> Result<()> {
+    for column in [
+        "DewPoint",
+        "AbsoluteHumidity",
+        "HeatIndex",
+        "Humidex",
+        "PressureSeaLevel",
+    ] {
+        add_column(conn, "weather_data", column, "INTEGER")?;
+    }
+    Ok(())
+}
+
+fn add_clock_checks(conn: &Connection, _: &str) -> Result<()> {
+    add_column(conn, "weather_data", "ClockStatus", "TEXT")?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS station_clock (
+        Station TEXT PRIMARY KEY,
+        Samples INTEGER NOT NULL,
+        SkewSum INTEGER NOT NULL,
+        MinSkew INTEGER,
+        MaxSkew INTEGER,
+        LastSkew INTEGER,
+        UnsyncedCount INTEGER NOT NULL,
+        SkewedCount INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_rollups(conn: &Connection, _: &str) -> Result<()> {
+    rollup::create_tables(conn)?;
+    rollup::rebuild(conn)?;
+    Ok(())
+}
+
+fn create_raw_messages(conn: &Connection, _: &str) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE raw_messages (
+        Id INTEGER PRIMARY KEY,
+        ReceivedTime INTEGER NOT NULL,
+        Topic TEXT NOT NULL,
+        Payload BLOB NOT NULL
+        );
+        CREATE INDEX raw_messages_time ON raw_messages (ReceivedTime);
+        ALTER TABLE weather_data ADD COLUMN RawMessageId INTEGER;
+        CREATE INDEX weather_data_raw_message ON weather_data (RawMessageId);",
+    )
+}
+
+fn create_dead_letters(conn: &Connection, _: &str) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE dead_letters (
+        Id INTEGER PRIMARY KEY,
+        ReceivedTime INTEGER NOT NULL,
+        Topic TEXT NOT NULL,
+        Payload BLOB NOT NULL,
+        ErrorKind TEXT NOT NULL,
+        Error TEXT NOT NULL,
+        RawMessageId INTEGER
+        );
+        CREATE INDEX dead_letters_time ON dead_letters (ReceivedTime);",
+    )
+}
+
+/// Finding other deliveries of a message, which `dead-letters retry` does for each one, would
+/// otherwise scan the whole archive
+fn index_raw_message_payloads(conn: &Connection, _: &str) -> Result<()> {
+    conn.execute(
+        "CREATE INDEX raw_messages_topic_payload ON raw_messages (Topic, Payload)",
+        [],
+    )?;
+    Ok(())
+}
@@ -1,73 +1,1286 @@
+use crate::units::{Celsius, FixedPoint, Pascal, Ppb, Ppm, RelativeHumidity};
 use byteorder::{LittleEndian, ReadBytesExt};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::io::Cursor;
 
-#[allow(non_snake_case)]
-struct SensorMessageHeader {
+/// Magic number of legacy (version 0) messages, whose header is only the magic number
+const LEGACY_MAGIC_NUMBER: u32 = 0x12345678;
+
+/// Magic number of versioned messages, the bytes "WEAT" read as a little-endian `u32`
+const MAGIC_NUMBER: u32 = 0x54414557;
+
+/// Magic number of batch messages, the bytes "WBAT" read as a little-endian `u32`
+const BATCH_MAGIC_NUMBER: u32 = 0x54414257;
+
+/// Magic number of device telemetry messages, the bytes "WTEL" read as a little-endian `u32`
+const TELEMETRY_MAGIC_NUMBER: u32 = 0x4C455457;
+
+/// Size in bytes of the telemetry payload
+const TELEMETRY_PAYLOAD_SIZE: usize = 32;
+
+/// Size in bytes of the NUL-padded firmware version string in the telemetry payload
+const FIRMWARE_VERSION_SIZE: usize = 16;
+
+/// Size in bytes of the legacy header, just the magic number
+const LEGACY_HEADER_SIZE: usize = 4;
+
+/// Size in bytes of the versioned header: magic number, version and flags
+const HEADER_SIZE: usize = 6;
+
+/// The first protocol version whose header has a sequence number after the flags
+const FIRST_SEQUENCED_VERSION: u8 = 3;
+
+/// Size in bytes of the header's sequence number
+const SEQUENCE_SIZE: usize = 4;
+
+/// Header flag marking that the message ends with a CRC-32 of everything before it
+pub const FLAG_CRC32: u8 = 0x01;
+
+/// Header flag marking that the message ends with an HMAC-SHA256 tag of the header and payload,
+/// before any CRC-32 trailer
+pub const FLAG_HMAC: u8 = 0x02;
+
+/// Every header flag this parser understands
+const KNOWN_FLAGS: u8 = FLAG_CRC32 | FLAG_HMAC;
+
+/// Size in bytes of the CRC-32 trailer
+const CRC32_SIZE: usize = 4;
+
+/// Size in bytes of the HMAC-SHA256 tag
+const HMAC_SIZE: usize = 32;
+
+/// Payload validity bit for the BME280 temperature, pressure and humidity
+pub const VALID_BME280: u8 = 0x01;
+
+/// Payload validity bit for the SGP30 eCO2 and TVOC
+pub const VALID_SGP30: u8 = 0x02;
+
+/// Payload validity bit for the DHT22 temperature
+pub const VALID_DHT22_TEMPERATURE: u8 = 0x04;
+
+/// Payload validity bit for the DHT22 humidity
+pub const VALID_DHT22_HUMIDITY: u8 = 0x08;
+
+/// Size in bytes of the reading count that follows the header of a batch message
+const BATCH_COUNT_SIZE: usize = 1;
+
+/// Reasons a received MQTT payload could not be decoded into a `SensorMessage`, or was rejected
+/// before being stored
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// The message is shorter than a complete `SensorMessage`
+    WrongLength { expected: usize, actual: usize },
+    /// The message does not start with a known magic number
+    BadMagic(u32),
+    /// A magic number is present but byte-swapped, the sender has the wrong endianness
+    ByteSwappedMagic(u32),
+    /// The header names a protocol version with no registered decoder
+    UnsupportedVersion(u8),
+    /// The header sets flags this version of the parser does not understand
+    UnsupportedFlags(u8),
+    /// The message is longer than a `SensorMessage`, by the given number of bytes
+    TrailingBytes(usize),
+    /// A float field marked as valid is NaN or infinite
+    NonFiniteFloat(&'static str),
+    /// The CRC-32 trailer does not match the message, it was corrupted in transit
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// A batch has more readings than its `u8` count can hold
+    BatchTooLarge(usize),
+    /// The station has an HMAC key, but the message carries no tag
+    MissingHmac,
+    /// The message has an HMAC tag but there is no key to check it with, or the config requires
+    /// every station to have a key
+    NoHmacKey,
+    /// The HMAC tag doesn't match, the message wasn't sent by a station with the key
+    HmacMismatch,
+    /// The station already sent a message with this sequence number, it was delivered twice
+    DuplicateSequence(u32),
+    /// The sequence number is older than the station's latest, the message is a stale replay
+    StaleSequence { sequence: u32, last: u32 },
+    /// A JSON or CBOR message could not be read into readings
+    InvalidEncoding {
+        format: &'static str,
+        message: String,
+    },
+}
+
+impl ParseError {
+    /// A short, stable name for the error kind, used when counting errors
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ParseError::WrongLength { .. } => "wrong_length",
+            ParseError::BadMagic(_) => "bad_magic",
+            ParseError::ByteSwappedMagic(_) => "byte_swapped_magic",
+            ParseError::UnsupportedVersion(_) => "unsupported_version",
+            ParseError::UnsupportedFlags(_) => "unsupported_flags",
+            ParseError::TrailingBytes(_) => "trailing_bytes",
+            ParseError::NonFiniteFloat(_) => "non_finite_float",
+            ParseError::ChecksumMismatch { .. } => "checksum_mismatch",
+            ParseError::BatchTooLarge(_) => "batch_too_large",
+            ParseError::MissingHmac => "missing_hmac",
+            ParseError::NoHmacKey => "no_hmac_key",
+            ParseError::HmacMismatch => "hmac_mismatch",
+            ParseError::DuplicateSequence(_) => "duplicate_sequence",
+            ParseError::StaleSequence { .. } => "stale_sequence",
+            ParseError::InvalidEncoding { .. } => "invalid_encoding",
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::WrongLength { expected, actual } => write!(
+                f,
+                "Message length error, expected {} bytes but got {}",
+                expected, actual
+            ),
+            ParseError::BadMagic(val) => write!(f, "Magic number error: {:#010x}", val),
+            ParseError::ByteSwappedMagic(val) => write!(
+                f,
+                "Magic number error, looks like wrong endiness: {:#010x}",
+                val
+            ),
+            ParseError::UnsupportedVersion(version) => {
+                write!(f, "No decoder for protocol version {}", version)
+            }
+            ParseError::UnsupportedFlags(flags) => {
+                write!(f, "Unsupported header flags: {:#04x}", flags)
+            }
+            ParseError::TrailingBytes(count) => {
+                write!(f, "Message has {} unexpected trailing bytes", count)
+            }
+            ParseError::NonFiniteFloat(field) => {
+                write!(f, "Field `{}` is not a finite number", field)
+            }
+            ParseError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksum error, trailer says {:#010x} but message has {:#010x}",
+                expected, actual
+            ),
+            ParseError::BatchTooLarge(count) => write!(
+                f,
+                "Batch of {} readings is more than the limit of {}",
+                count,
+                u8::MAX
+            ),
+            ParseError::MissingHmac => {
+                write!(
+                    f,
+                    "Unauthenticated, the station has a key but the message has no HMAC"
+                )
+            }
+            ParseError::NoHmacKey => write!(f, "Unauthenticated, no HMAC key for the station"),
+            ParseError::HmacMismatch => write!(f, "Unauthenticated, the HMAC does not match"),
+            ParseError::DuplicateSequence(sequence) => {
+                write!(f, "Duplicate message, sequence {} already stored", sequence)
+            }
+            ParseError::StaleSequence { sequence, last } => write!(
+                f,
+                "Stale message, sequence {} is older than the latest {}",
+                sequence, last
+            ),
+            ParseError::InvalidEncoding { format, message } => {
+                write!(f, "Invalid {} message: {}", format, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Checks a message's `sequence` number is newer than the `last` one stored from its station
+pub fn check_sequence(sequence: u32, last: Option<u32>) -> Result<(), ParseError> {
+    match last {
+        Some(last) if sequence == last => Err(ParseError::DuplicateSequence(sequence)),
+        Some(last) if sequence < last => Err(ParseError::StaleSequence { sequence, last }),
+        _ => Ok(()),
+    }
+}
+
+/// Running count of parse errors, by error kind
+#[derive(Default)]
+pub struct ParseErrorCounts {
+    counts: BTreeMap<&'static str, u64>,
+}
+
+impl ParseErrorCounts {
+    /// Records an occurrence of `error`, returning the total for its kind so far
+    pub fn record(&mut self, error: &ParseError) -> u64 {
+        let count = self.counts.entry(error.kind()).or_insert(0);
+        *count += 1;
+        *count
+    }
+}
+
+impl fmt::Display for ParseErrorCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts: Vec<String> = self
+            .counts
+            .iter()
+            .map(|(kind, count)| format!("{}={}", kind, count))
+            .collect();
+        write!(f, "{}", counts.join(", "))
+    }
+}
+
+/// Decodes and encodes the payload layout used by one protocol version.
+///
+/// Codecs are looked up by the version in the message header, so several firmware versions can
+/// publish to the same server while stations are updated one at a time.
+struct VersionCodec {
+    version: u8,
+    /// Size in bytes of the payload, excluding the header
+    payload_size: usize,
+    /// Reads the payload. The data has already been checked to be `payload_size` bytes long.
+    decode: fn(&mut Cursor<&[u8]>) -> std::io::Result<SensorMessagePayload>,
+    /// Appends the payload, exactly `payload_size` bytes
+    encode: fn(&SensorMessagePayload, &mut Vec<u8>),
+}
+
+/// The protocol version new messages are encoded with by default
+pub const CURRENT_VERSION: u8 = 3;
+
+/// Every payload layout the parser understands. Add new layouts here as the firmware changes,
+/// keeping the old ones so that stations which have not been updated can still be decoded.
+const CODECS: &[VersionCodec] = &[
+    // Version 0: the legacy, magic-only header
+    VersionCodec {
+        version: 0,
+        payload_size: 32,
+        decode: decode_payload_v0,
+        encode: encode_payload_v0,
+    },
+    // Version 1: the versioned header, the payload is unchanged
+    VersionCodec {
+        version: 1,
+        payload_size: 32,
+        decode: decode_payload_v0,
+        encode: encode_payload_v0,
+    },
+    // Version 2: a validity bitmask appended to the payload
+    VersionCodec {
+        version: 2,
+        payload_size: 33,
+        decode: decode_payload_v2,
+        encode: encode_payload_v2,
+    },
+    // Version 3: a sequence number in the header, the payload is unchanged
+    VersionCodec {
+        version: 3,
+        payload_size: 33,
+        decode: decode_payload_v2,
+        encode: encode_payload_v2,
+    },
+];
+
+/// Reads the SGP30 layout with a `posix_time`, used by protocol versions 0 and 1.
+///
+/// These layouts have no validity bitmask, so it is inferred from the values the firmware writes
+/// when a sensor fails.
+fn decode_payload_v0(cursor: &mut Cursor<&[u8]>) -> std::io::Result<SensorMessagePayload> {
+    let mut payload = SensorMessagePayload {
+        posix_time: cursor.read_i64::<LittleEndian>()?,
+        bme_temperature: cursor.read_f32::<LittleEndian>()?,
+        bme_pressure: cursor.read_f32::<LittleEndian>()?,
+        bme_humidity: cursor.read_f32::<LittleEndian>()?,
+        sgp30_eCO2: cursor.read_u16::<LittleEndian>()?,
+        sgp30_TVOC: cursor.read_u16::<LittleEndian>()?,
+        dht22_temperature: cursor.read_f32::<LittleEndian>()?,
+        dht22_humidity: cursor.read_f32::<LittleEndian>()?,
+        valid: 0,
+    };
+    payload.valid = payload.infer_validity();
+    Ok(payload)
+}
+
+/// Reads the version 0 layout followed by a `u8` validity bitmask, used from protocol version 2
+fn decode_payload_v2(cursor: &mut Cursor<&[u8]>) -> std::io::Result<SensorMessagePayload> {
+    let mut payload = decode_payload_v0(cursor)?;
+    payload.valid = cursor.read_u8()?;
+    Ok(payload)
+}
+
+/// Writes the version 0 layout. It has no validity bitmask, so readings from failed sensors are
+/// written as they are and will only be recognised if they hold the values the firmware uses.
+fn encode_payload_v0(payload: &SensorMessagePayload, data: &mut Vec<u8>) {
+    data.extend_from_slice(&payload.posix_time.to_le_bytes());
+    data.extend_from_slice(&payload.bme_temperature.to_le_bytes());
+    data.extend_from_slice(&payload.bme_pressure.to_le_bytes());
+    data.extend_from_slice(&payload.bme_humidity.to_le_bytes());
+    data.extend_from_slice(&payload.sgp30_eCO2.to_le_bytes());
+    data.extend_from_slice(&payload.sgp30_TVOC.to_le_bytes());
+    data.extend_from_slice(&payload.dht22_temperature.to_le_bytes());
+    data.extend_from_slice(&payload.dht22_humidity.to_le_bytes());
+}
+
+/// Writes the version 2 layout, the version 0 layout followed by the validity bitmask
+fn encode_payload_v2(payload: &SensorMessagePayload, data: &mut Vec<u8>) {
+    encode_payload_v0(payload, data);
+    data.push(payload.valid);
+}
+
+/// The header of a binary message. Legacy messages are version 0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorMessageHeader {
     magic_number: u32,
+    version: u8,
+    flags: u8,
+    /// Counts up with every message a station sends, from version 3
+    sequence: Option<u32>,
 }
 
+impl SensorMessageHeader {
+    /// Header for a single reading in protocol `version`, with the header `flags`
+    pub fn reading(version: u8, flags: u8) -> Result<Self, ParseError> {
+        let magic_number = match version {
+            0 => LEGACY_MAGIC_NUMBER,
+            _ => MAGIC_NUMBER,
+        };
+        Self::checked(magic_number, version, flags)
+    }
+
+    /// Header for a batch of readings in protocol `version`, with the header `flags`
+    pub fn batch(version: u8, flags: u8) -> Result<Self, ParseError> {
+        Self::checked(BATCH_MAGIC_NUMBER, version, flags)
+    }
+
+    /// Header for device telemetry in protocol `version`, with the header `flags`
+    pub fn telemetry(version: u8, flags: u8) -> Result<Self, ParseError> {
+        if version == 0 {
+            return Err(ParseError::UnsupportedVersion(version));
+        }
+        Self::checked(TELEMETRY_MAGIC_NUMBER, version, flags)
+    }
+
+    /// Whether the header starts a single reading, rather than a batch or telemetry
+    fn is_reading(&self) -> bool {
+        matches!(self.magic_number, LEGACY_MAGIC_NUMBER | MAGIC_NUMBER)
+    }
+
+    /// Sets the sequence number. Versions before 3 have no sequence number, and are unchanged.
+    pub fn with_sequence(mut self, sequence: u32) -> Self {
+        if self.sequence.is_some() {
+            self.sequence = Some(sequence);
+        }
+        self
+    }
+
+    /// Builds a header, checking it can be encoded and decoded again
+    fn checked(magic_number: u32, version: u8, flags: u8) -> Result<Self, ParseError> {
+        // The legacy header has nowhere to put the flags
+        if magic_number == LEGACY_MAGIC_NUMBER && flags != 0 {
+            return Err(ParseError::UnsupportedFlags(flags));
+        }
+        let header = SensorMessageHeader {
+            magic_number,
+            version,
+            flags,
+            sequence: (version >= FIRST_SEQUENCED_VERSION).then_some(0),
+        };
+        header.codec()?;
+        Ok(header)
+    }
+
+    /// Appends the header to `data`
+    fn encode(&self, data: &mut Vec<u8>) {
+        data.extend_from_slice(&self.magic_number.to_le_bytes());
+        if self.magic_number != LEGACY_MAGIC_NUMBER {
+            data.push(self.version);
+            data.push(self.flags);
+        }
+        if let Some(sequence) = self.sequence {
+            data.extend_from_slice(&sequence.to_le_bytes());
+        }
+    }
+
+    /// Appends the HMAC tag and CRC-32 trailer if the header asks for them, `data` must be the
+    /// whole message.
+    ///
+    /// Panics if the header asks for an HMAC tag but there is no key.
+    fn append_trailers(&self, data: &mut Vec<u8>, key: Option<&[u8]>) {
+        if self.flags & FLAG_HMAC != 0 {
+            let key = key.expect("Header asks for an HMAC but no key was given");
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key length");
+            mac.update(data);
+            data.extend_from_slice(&mac.finalize().into_bytes());
+        }
+        if self.flags & FLAG_CRC32 != 0 {
+            let checksum = crc32fast::hash(data);
+            data.extend_from_slice(&checksum.to_le_bytes());
+        }
+    }
+
+    /// Reads the header from the start of `data`, returning it with its size in bytes.
+    ///
+    /// Legacy messages have no version field, and are reported as version 0.
+    fn from_bytes(data: &[u8]) -> Result<(Self, usize), ParseError> {
+        if data.len() < LEGACY_HEADER_SIZE {
+            return Err(ParseError::WrongLength {
+                expected: LEGACY_HEADER_SIZE,
+                actual: data.len(),
+            });
+        }
+
+        let magic_number = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        match magic_number {
+            LEGACY_MAGIC_NUMBER => Ok((
+                SensorMessageHeader {
+                    magic_number,
+                    version: 0,
+                    flags: 0,
+                    sequence: None,
+                },
+                LEGACY_HEADER_SIZE,
+            )),
+            MAGIC_NUMBER | BATCH_MAGIC_NUMBER | TELEMETRY_MAGIC_NUMBER
+                if data.len() < HEADER_SIZE =>
+            {
+                Err(ParseError::WrongLength {
+                    expected: HEADER_SIZE,
+                    actual: data.len(),
+                })
+            }
+            MAGIC_NUMBER | BATCH_MAGIC_NUMBER | TELEMETRY_MAGIC_NUMBER
+                if data[4] < FIRST_SEQUENCED_VERSION =>
+            {
+                Ok((
+                    SensorMessageHeader {
+                        magic_number,
+                        version: data[4],
+                        flags: data[5],
+                        sequence: None,
+                    },
+                    HEADER_SIZE,
+                ))
+            }
+            MAGIC_NUMBER | BATCH_MAGIC_NUMBER | TELEMETRY_MAGIC_NUMBER
+                if data.len() < HEADER_SIZE + SEQUENCE_SIZE =>
+            {
+                Err(ParseError::WrongLength {
+                    expected: HEADER_SIZE + SEQUENCE_SIZE,
+                    actual: data.len(),
+                })
+            }
+            MAGIC_NUMBER | BATCH_MAGIC_NUMBER | TELEMETRY_MAGIC_NUMBER => Ok((
+                SensorMessageHeader {
+                    magic_number,
+                    version: data[4],
+                    flags: data[5],
+                    sequence: Some(u32::from_le_bytes([data[6], data[7], data[8], data[9]])),
+                },
+                HEADER_SIZE + SEQUENCE_SIZE,
+            )),
+            val if [
+                LEGACY_MAGIC_NUMBER,
+                MAGIC_NUMBER,
+                BATCH_MAGIC_NUMBER,
+                TELEMETRY_MAGIC_NUMBER,
+            ]
+            .iter()
+            .any(|magic| magic.swap_bytes() == val) =>
+            {
+                Err(ParseError::ByteSwappedMagic(val))
+            }
+            val => Err(ParseError::BadMagic(val)),
+        }
+    }
+
+    /// Finds the codec for the header's protocol version
+    fn codec(&self) -> Result<&'static VersionCodec, ParseError> {
+        // Refuse any flags we don't know, we might misinterpret the message
+        if self.flags & !KNOWN_FLAGS != 0 {
+            return Err(ParseError::UnsupportedFlags(self.flags));
+        }
+
+        CODECS
+            .iter()
+            .find(|codec| codec.version == self.version)
+            .ok_or(ParseError::UnsupportedVersion(self.version))
+    }
+
+    /// Verifies the CRC-32 trailer if the header says there is one, and returns the message
+    /// without it. Messages without a trailer are returned unchanged.
+    fn strip_checksum<'a>(
+        &self,
+        data: &'a [u8],
+        header_size: usize,
+    ) -> Result<&'a [u8], ParseError> {
+        if self.flags & FLAG_CRC32 == 0 {
+            return Ok(data);
+        }
+        if data.len() < header_size + CRC32_SIZE {
+            return Err(ParseError::WrongLength {
+                expected: header_size + CRC32_SIZE,
+                actual: data.len(),
+            });
+        }
+
+        let (message, trailer) = data.split_at(data.len() - CRC32_SIZE);
+        let expected = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let actual = crc32fast::hash(message);
+        if expected != actual {
+            return Err(ParseError::ChecksumMismatch { expected, actual });
+        }
+        Ok(message)
+    }
+
+    /// Verifies the HMAC tag with the station's `key`, and returns the message without it.
+    ///
+    /// A station with a key must tag every message, and a tagged message can't be trusted without
+    /// a key to check it.
+    fn strip_hmac<'a>(
+        &self,
+        data: &'a [u8],
+        header_size: usize,
+        key: Option<&[u8]>,
+    ) -> Result<&'a [u8], ParseError> {
+        let key = match (self.flags & FLAG_HMAC != 0, key) {
+            (false, None) => return Ok(data),
+            (false, Some(_)) => return Err(ParseError::MissingHmac),
+            (true, None) => return Err(ParseError::NoHmacKey),
+            (true, Some(key)) => key,
+        };
+        if data.len() < header_size + HMAC_SIZE {
+            return Err(ParseError::WrongLength {
+                expected: header_size + HMAC_SIZE,
+                actual: data.len(),
+            });
+        }
+
+        let (message, tag) = data.split_at(data.len() - HMAC_SIZE);
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key length");
+        mac.update(message);
+        mac.verify_slice(tag)
+            .map_err(|_| ParseError::HmacMismatch)?;
+        Ok(message)
+    }
+}
+
+/// Checks a message is exactly `expected` bytes long
+fn check_length(data: &[u8], expected: usize) -> Result<(), ParseError> {
+    match data.len() {
+        len if len == expected => Ok(()),
+        len if len > expected => Err(ParseError::TrailingBytes(len - expected)),
+        len => Err(ParseError::WrongLength {
+            expected,
+            actual: len,
+        }),
+    }
+}
+
+/// Reads `count` consecutive payloads from `data` using `codec`.
+///
+/// The caller must have checked that `data` holds at least `count` payloads.
+fn decode_payloads(
+    codec: &VersionCodec,
+    data: &[u8],
+    count: usize,
+) -> Result<Vec<SensorMessagePayload>, ParseError> {
+    let mut cursor = Cursor::new(data);
+    let mut payloads = Vec::with_capacity(count);
+    for _ in 0..count {
+        let payload = (codec.decode)(&mut cursor).map_err(|_| ParseError::WrongLength {
+            expected: count * codec.payload_size,
+            actual: data.len(),
+        })?;
+        payload.check_valid_finite()?;
+        payloads.push(payload);
+    }
+    Ok(payloads)
+}
+
+/// One set of readings from a station, in the units the sensors report.
+///
+/// The fields are the raw values from the wire, even for a failed sensor. Accessors such as
+/// `temperature_bme` give the readings that can be trusted as `units` quantities.
 #[allow(non_snake_case)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct SensorMessagePayload {
-    posix_time: i64,
-    bme_temperature: f32,
-    bme_pressure: f32,
-    bme_humidity: f32,
-    sgp30_eCO2: u16,
-    sgp30_TVOC: u16,
-    dht22_temperature: f32,
-    dht22_humidity: f32,
+    pub posix_time: i64,
+    /// In °C
+    pub bme_temperature: f32,
+    /// In Pa
+    pub bme_pressure: f32,
+    /// In %
+    pub bme_humidity: f32,
+    /// In ppm
+    pub sgp30_eCO2: u16,
+    /// In ppb
+    pub sgp30_TVOC: u16,
+    /// In °C
+    pub dht22_temperature: f32,
+    /// In %
+    pub dht22_humidity: f32,
+    /// Bitmask of the `VALID_*` sensor readings that can be trusted
+    pub valid: u8,
 }
 
 #[allow(non_snake_case)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct SensorMessage {
-    header: SensorMessageHeader,
+    /// The binary header, or `None` for a JSON or CBOR message
+    header: Option<SensorMessageHeader>,
     pub payload: SensorMessagePayload,
 }
 
+/// Several readings sent together by a device that buffers its measurements.
+///
+/// Laid out as the usual header, a `u8` count of readings, then that many payloads in the layout
+/// of the header's protocol version, then the optional CRC-32 trailer. Each payload keeps its own
+/// `posix_time`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorBatchMessage {
+    /// The binary header, or `None` for a JSON or CBOR message
+    header: Option<SensorMessageHeader>,
+    pub payloads: Vec<SensorMessagePayload>,
+}
+
+/// Any message a station can publish, distinguished by the magic number
+#[derive(Debug, Clone, PartialEq)]
+pub enum MqttMessage {
+    Reading(SensorMessage),
+    Batch(SensorBatchMessage),
+    Telemetry(TelemetryMessage),
+}
+
+impl MqttMessage {
+    /// Decodes a message from a station without an HMAC key
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ParseError> {
+        Self::from_bytes_with_key(data, None)
+    }
+
+    /// Decodes a message, verifying its HMAC tag with the station's `key` if it has one
+    pub fn from_bytes_with_key(data: &[u8], key: Option<&[u8]>) -> Result<Self, ParseError> {
+        let (header, _) = SensorMessageHeader::from_bytes(data)?;
+        match header.magic_number {
+            BATCH_MAGIC_NUMBER => {
+                SensorBatchMessage::from_bytes_with_key(data, key).map(MqttMessage::Batch)
+            }
+            TELEMETRY_MAGIC_NUMBER => {
+                TelemetryMessage::from_bytes_with_key(data, key).map(MqttMessage::Telemetry)
+            }
+            _ => SensorMessage::from_bytes_with_key(data, key).map(MqttMessage::Reading),
+        }
+    }
+
+    /// The sequence number from the header, if the message has one
+    pub fn sequence(&self) -> Option<u32> {
+        let header = match self {
+            MqttMessage::Reading(message) => &message.header,
+            MqttMessage::Batch(message) => &message.header,
+            MqttMessage::Telemetry(message) => return message.header.sequence,
+        };
+        header.as_ref().and_then(|header| header.sequence)
+    }
+
+    /// Encodes the message as `from_bytes` expects it
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with_key(None)
+    }
+
+    /// Encodes the message as `from_bytes_with_key` expects it, tagging it with `key` if the
+    /// header has `FLAG_HMAC`
+    pub fn to_bytes_with_key(&self, key: Option<&[u8]>) -> Vec<u8> {
+        match self {
+            MqttMessage::Reading(message) => message.to_bytes_with_key(key),
+            MqttMessage::Batch(message) => message.to_bytes_with_key(key),
+            MqttMessage::Telemetry(message) => message.to_bytes_with_key(key),
+        }
+    }
+}
+
+/// Encodings that a station can publish its readings in, chosen per topic in the config
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    /// The packed little-endian C structs in interfaces.md
+    #[default]
+    Binary,
+    /// A JSON object of `ReadingFields`, or an array of them for a batch
+    Json,
+    /// The same structure as `Json`, encoded as CBOR
+    Cbor,
+}
+
+impl PayloadFormat {
+    /// The decoder for messages in this format
+    pub fn decoder(self) -> &'static dyn PayloadDecoder {
+        match self {
+            PayloadFormat::Binary => &BinaryDecoder,
+            PayloadFormat::Json => &JsonDecoder,
+            PayloadFormat::Cbor => &CborDecoder,
+        }
+    }
+}
+
+/// Turns the bytes of an MQTT message into readings
+pub trait PayloadDecoder {
+    /// Decodes `data`, authenticating it with the station's HMAC `key` if it has one
+    fn decode(&self, data: &[u8], key: Option<&[u8]>) -> Result<MqttMessage, ParseError>;
+}
+
+/// Decodes the packed binary structs sent by the ESP8266 firmware
+pub struct BinaryDecoder;
+
+impl PayloadDecoder for BinaryDecoder {
+    fn decode(&self, data: &[u8], key: Option<&[u8]>) -> Result<MqttMessage, ParseError> {
+        MqttMessage::from_bytes_with_key(data, key)
+    }
+}
+
+/// Decodes JSON, as published by Tasmota or ESPHome nodes
+pub struct JsonDecoder;
+
+impl PayloadDecoder for JsonDecoder {
+    /// JSON messages carry no HMAC tag, so stations with a key can't use JSON
+    fn decode(&self, data: &[u8], key: Option<&[u8]>) -> Result<MqttMessage, ParseError> {
+        if key.is_some() {
+            return Err(ParseError::MissingHmac);
+        }
+        let fields: OneOrMany =
+            serde_json::from_slice(data).map_err(|err| ParseError::InvalidEncoding {
+                format: "JSON",
+                message: err.to_string(),
+            })?;
+        fields.into_message()
+    }
+}
+
+/// Decodes CBOR, with the same structure as `JsonDecoder`
+pub struct CborDecoder;
+
+impl PayloadDecoder for CborDecoder {
+    /// CBOR messages carry no HMAC tag, so stations with a key can't use CBOR
+    fn decode(&self, data: &[u8], key: Option<&[u8]>) -> Result<MqttMessage, ParseError> {
+        if key.is_some() {
+            return Err(ParseError::MissingHmac);
+        }
+        let fields: OneOrMany =
+            ciborium::de::from_reader(data).map_err(|err| ParseError::InvalidEncoding {
+                format: "CBOR",
+                message: err.to_string(),
+            })?;
+        fields.into_message()
+    }
+}
+
+/// One reading in a JSON or CBOR message, in the units of `SensorMessagePayload`.
+///
+/// Every field but `posix_time` is optional. A sensor with any of its fields missing is stored as
+/// invalid.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReadingFields {
+    posix_time: i64,
+    bme_temperature: Option<f32>,
+    bme_pressure: Option<f32>,
+    bme_humidity: Option<f32>,
+    sgp30_eco2: Option<u16>,
+    sgp30_tvoc: Option<u16>,
+    dht22_temperature: Option<f32>,
+    dht22_humidity: Option<f32>,
+}
+
+/// A JSON or CBOR message is a single reading, or an array of them for a batch
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(ReadingFields),
+    Many(Vec<ReadingFields>),
+}
+
+impl OneOrMany {
+    fn into_message(self) -> Result<MqttMessage, ParseError> {
+        match self {
+            OneOrMany::One(fields) => Ok(MqttMessage::Reading(SensorMessage {
+                header: None,
+                payload: fields.into_payload()?,
+            })),
+            OneOrMany::Many(readings) => Ok(MqttMessage::Batch(SensorBatchMessage {
+                header: None,
+                payloads: readings
+                    .into_iter()
+                    .map(ReadingFields::into_payload)
+                    .collect::<Result<_, _>>()?,
+            })),
+        }
+    }
+}
+
+impl ReadingFields {
+    fn into_payload(self) -> Result<SensorMessagePayload, ParseError> {
+        let mut valid = 0;
+        let bme = match (self.bme_temperature, self.bme_pressure, self.bme_humidity) {
+            (Some(temperature), Some(pressure), Some(humidity)) => {
+                valid |= VALID_BME280;
+                (temperature, pressure, humidity)
+            }
+            _ => (0.0, 0.0, 0.0),
+        };
+        let sgp30 = match (self.sgp30_eco2, self.sgp30_tvoc) {
+            (Some(eco2), Some(tvoc)) => {
+                valid |= VALID_SGP30;
+                (eco2, tvoc)
+            }
+            _ => (0, 0),
+        };
+        if self.dht22_temperature.is_some() {
+            valid |= VALID_DHT22_TEMPERATURE;
+        }
+        if self.dht22_humidity.is_some() {
+            valid |= VALID_DHT22_HUMIDITY;
+        }
+        let payload = SensorMessagePayload {
+            posix_time: self.posix_time,
+            bme_temperature: bme.0,
+            bme_pressure: bme.1,
+            bme_humidity: bme.2,
+            sgp30_eCO2: sgp30.0,
+            sgp30_TVOC: sgp30.1,
+            dht22_temperature: self.dht22_temperature.unwrap_or(0.0),
+            dht22_humidity: self.dht22_humidity.unwrap_or(0.0),
+            valid,
+        };
+        payload.check_valid_finite()?;
+        Ok(payload)
+    }
+}
+
 impl SensorMessage {
-    pub fn from_bytes(data: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
-        // TODO: Validate data size first
+    /// A message for `payload` with the given binary header
+    pub fn new(
+        header: SensorMessageHeader,
+        payload: SensorMessagePayload,
+    ) -> Result<Self, ParseError> {
+        if !header.is_reading() {
+            return Err(ParseError::BadMagic(header.magic_number));
+        }
+        Ok(SensorMessage {
+            header: Some(header),
+            payload,
+        })
+    }
 
-        let mut cursor = Cursor::new(data);
-        let message = SensorMessage {
-            header: SensorMessageHeader {
-                magic_number: cursor.read_u32::<LittleEndian>()?,
-            },
-            payload: SensorMessagePayload {
-                posix_time: cursor.read_i64::<LittleEndian>()?,
-                bme_temperature: cursor.read_f32::<LittleEndian>()?,
-                bme_pressure: cursor.read_f32::<LittleEndian>()?,
-                bme_humidity: cursor.read_f32::<LittleEndian>()?,
-                sgp30_eCO2: cursor.read_u16::<LittleEndian>()?,
-                sgp30_TVOC: cursor.read_u16::<LittleEndian>()?,
-                dht22_temperature: cursor.read_f32::<LittleEndian>()?,
-                dht22_humidity: cursor.read_f32::<LittleEndian>()?,
-            },
+    /// Encodes the message as `from_bytes` expects it. Messages that were not binary are encoded
+    /// in the current version with a CRC-32 trailer.
+    ///
+    /// Panics if the header has `FLAG_HMAC`, use `to_bytes_with_key` for those.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with_key(None)
+    }
+
+    /// Encodes the message as `from_bytes_with_key` expects it, tagging it with `key` if the
+    /// header has `FLAG_HMAC`
+    pub fn to_bytes_with_key(&self, key: Option<&[u8]>) -> Vec<u8> {
+        let header = self.header.clone().unwrap_or_else(|| {
+            SensorMessageHeader::reading(CURRENT_VERSION, FLAG_CRC32)
+                .expect("Current version is always supported")
+        });
+        let codec = header.codec().expect("Header was checked when created");
+
+        let mut data = Vec::with_capacity(
+            HEADER_SIZE + SEQUENCE_SIZE + codec.payload_size + HMAC_SIZE + CRC32_SIZE,
+        );
+        header.encode(&mut data);
+        (codec.encode)(&self.payload, &mut data);
+        header.append_trailers(&mut data, key);
+        data
+    }
+
+    /// Decodes a message from a station without an HMAC key
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ParseError> {
+        Self::from_bytes_with_key(data, None)
+    }
+
+    /// Decodes a message, verifying its HMAC tag with the station's `key` if it has one
+    pub fn from_bytes_with_key(data: &[u8], key: Option<&[u8]>) -> Result<Self, ParseError> {
+        let (header, header_size) = SensorMessageHeader::from_bytes(data)?;
+        if !header.is_reading() {
+            return Err(ParseError::BadMagic(header.magic_number));
+        }
+
+        let codec = header.codec()?;
+        let data = header.strip_checksum(data, header_size)?;
+        let data = header.strip_hmac(data, header_size, key)?;
+        check_length(data, header_size + codec.payload_size)?;
+
+        let payload = decode_payloads(codec, &data[header_size..], 1)?.remove(0);
+        Ok(SensorMessage {
+            header: Some(header),
+            payload,
+        })
+    }
+}
+
+impl SensorBatchMessage {
+    /// A batch of `payloads` with the given binary header
+    pub fn new(
+        header: SensorMessageHeader,
+        payloads: Vec<SensorMessagePayload>,
+    ) -> Result<Self, ParseError> {
+        if header.magic_number != BATCH_MAGIC_NUMBER {
+            return Err(ParseError::BadMagic(header.magic_number));
+        }
+        if payloads.len() > u8::MAX as usize {
+            return Err(ParseError::BatchTooLarge(payloads.len()));
+        }
+        Ok(SensorBatchMessage {
+            header: Some(header),
+            payloads,
+        })
+    }
+
+    /// Encodes the message as `from_bytes` expects it. Messages that were not binary are encoded
+    /// in the current version with a CRC-32 trailer.
+    ///
+    /// Panics if the header has `FLAG_HMAC`, use `to_bytes_with_key` for those.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with_key(None)
+    }
+
+    /// Encodes the message as `from_bytes_with_key` expects it, tagging it with `key` if the
+    /// header has `FLAG_HMAC`.
+    ///
+    /// Panics if a batch decoded from JSON or CBOR holds more readings than a binary batch can.
+    pub fn to_bytes_with_key(&self, key: Option<&[u8]>) -> Vec<u8> {
+        let header = self.header.clone().unwrap_or_else(|| {
+            SensorMessageHeader::batch(CURRENT_VERSION, FLAG_CRC32)
+                .expect("Current version is always supported")
+        });
+        let codec = header.codec().expect("Header was checked when created");
+        let count = u8::try_from(self.payloads.len()).expect("Too many readings for a batch");
+
+        let mut data = Vec::with_capacity(
+            HEADER_SIZE
+                + SEQUENCE_SIZE
+                + BATCH_COUNT_SIZE
+                + self.payloads.len() * codec.payload_size
+                + HMAC_SIZE
+                + CRC32_SIZE,
+        );
+        header.encode(&mut data);
+        data.push(count);
+        for payload in &self.payloads {
+            (codec.encode)(payload, &mut data);
+        }
+        header.append_trailers(&mut data, key);
+        data
+    }
+
+    /// Decodes a batch from a station without an HMAC key
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ParseError> {
+        Self::from_bytes_with_key(data, None)
+    }
+
+    /// Decodes a batch, verifying its HMAC tag with the station's `key` if it has one
+    pub fn from_bytes_with_key(data: &[u8], key: Option<&[u8]>) -> Result<Self, ParseError> {
+        let (header, header_size) = SensorMessageHeader::from_bytes(data)?;
+        if header.magic_number != BATCH_MAGIC_NUMBER {
+            return Err(ParseError::BadMagic(header.magic_number));
+        }
+
+        let codec = header.codec()?;
+        let data = header.strip_checksum(data, header_size)?;
+        let data = header.strip_hmac(data, header_size, key)?;
+        let payloads_start = header_size + BATCH_COUNT_SIZE;
+        if data.len() < payloads_start {
+            return Err(ParseError::WrongLength {
+                expected: payloads_start,
+                actual: data.len(),
+            });
+        }
+        let count = data[header_size] as usize;
+        check_length(data, payloads_start + count * codec.payload_size)?;
+
+        let payloads = decode_payloads(codec, &data[payloads_start..], count)?;
+        Ok(SensorBatchMessage {
+            header: Some(header),
+            payloads,
+        })
+    }
+}
+
+/// The state of the station's ESP8266 itself, sent alongside its readings
+#[derive(Debug, Clone, PartialEq)]
+pub struct TelemetryPayload {
+    pub posix_time: i64,
+    pub battery_millivolts: u16,
+    /// WiFi signal strength in dBm
+    pub wifi_rssi: i8,
+    /// The ESP8266 `rst_info.reason` of the last reset, see `reset_reason_name`
+    pub reset_reason: u8,
+    /// Number of times the station has woken since it was powered on
+    pub wake_count: u32,
+    /// At most 16 bytes of UTF-8
+    pub firmware_version: String,
+}
+
+/// Telemetry from a station, laid out as the usual header, the telemetry payload, then the
+/// optional HMAC and CRC-32 trailers.
+///
+/// The payload is the packed `TelemetryPayload` in interfaces.md, the same in every protocol
+/// version so far. Legacy (version 0) headers aren't used for telemetry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TelemetryMessage {
+    header: SensorMessageHeader,
+    pub payload: TelemetryPayload,
+}
+
+/// A readable name for an ESP8266 `rst_info.reason`
+pub fn reset_reason_name(reason: u8) -> &'static str {
+    match reason {
+        0 => "power on",
+        1 => "hardware watchdog",
+        2 => "exception",
+        3 => "software watchdog",
+        4 => "software restart",
+        5 => "deep sleep wake",
+        6 => "external reset",
+        _ => "unknown",
+    }
+}
+
+impl TelemetryMessage {
+    /// A telemetry message for `payload` with the given binary header
+    pub fn new(header: SensorMessageHeader, payload: TelemetryPayload) -> Result<Self, ParseError> {
+        if header.magic_number != TELEMETRY_MAGIC_NUMBER {
+            return Err(ParseError::BadMagic(header.magic_number));
+        }
+        if payload.firmware_version.len() > FIRMWARE_VERSION_SIZE {
+            return Err(ParseError::InvalidEncoding {
+                format: "telemetry",
+                message: "firmware version is longer than 16 bytes".to_string(),
+            });
+        }
+        Ok(TelemetryMessage { header, payload })
+    }
+
+    /// Encodes the message as `from_bytes` expects it.
+    ///
+    /// Panics if the header has `FLAG_HMAC`, use `to_bytes_with_key` for those.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with_key(None)
+    }
+
+    /// Encodes the message as `from_bytes_with_key` expects it, tagging it with `key` if the
+    /// header has `FLAG_HMAC`.
+    ///
+    /// A firmware version longer than 16 bytes is cut short at the last whole character that fits.
+    pub fn to_bytes_with_key(&self, key: Option<&[u8]>) -> Vec<u8> {
+        let mut data = Vec::with_capacity(
+            HEADER_SIZE + SEQUENCE_SIZE + TELEMETRY_PAYLOAD_SIZE + HMAC_SIZE + CRC32_SIZE,
+        );
+        self.header.encode(&mut data);
+        let payload = &self.payload;
+        data.extend_from_slice(&payload.posix_time.to_le_bytes());
+        data.extend_from_slice(&payload.battery_millivolts.to_le_bytes());
+        data.extend_from_slice(&payload.wifi_rssi.to_le_bytes());
+        data.push(payload.reset_reason);
+        data.extend_from_slice(&payload.wake_count.to_le_bytes());
+        let mut version_end = payload.firmware_version.len().min(FIRMWARE_VERSION_SIZE);
+        while !payload.firmware_version.is_char_boundary(version_end) {
+            version_end -= 1;
+        }
+        let mut firmware_version = [0u8; FIRMWARE_VERSION_SIZE];
+        firmware_version[..version_end]
+            .copy_from_slice(&payload.firmware_version.as_bytes()[..version_end]);
+        data.extend_from_slice(&firmware_version);
+        self.header.append_trailers(&mut data, key);
+        data
+    }
+
+    /// Decodes telemetry from a station without an HMAC key
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ParseError> {
+        Self::from_bytes_with_key(data, None)
+    }
+
+    /// Decodes telemetry, verifying its HMAC tag with the station's `key` if it has one
+    pub fn from_bytes_with_key(data: &[u8], key: Option<&[u8]>) -> Result<Self, ParseError> {
+        let (header, header_size) = SensorMessageHeader::from_bytes(data)?;
+        if header.magic_number != TELEMETRY_MAGIC_NUMBER {
+            return Err(ParseError::BadMagic(header.magic_number));
+        }
+        // As in `SensorMessageHeader::telemetry`, there was never a version 0 telemetry message
+        if header.version == 0 {
+            return Err(ParseError::UnsupportedVersion(header.version));
+        }
+
+        // The payload layout doesn't depend on the version, but the version must be known
+        header.codec()?;
+        let data = header.strip_checksum(data, header_size)?;
+        let data = header.strip_hmac(data, header_size, key)?;
+        check_length(data, header_size + TELEMETRY_PAYLOAD_SIZE)?;
+
+        let mut cursor = Cursor::new(&data[header_size..]);
+        let read_error = |_| ParseError::WrongLength {
+            expected: header_size + TELEMETRY_PAYLOAD_SIZE,
+            actual: data.len(),
         };
+        let posix_time = cursor.read_i64::<LittleEndian>().map_err(read_error)?;
+        let battery_millivolts = cursor.read_u16::<LittleEndian>().map_err(read_error)?;
+        let wifi_rssi = cursor.read_i8().map_err(read_error)?;
+        let reset_reason = cursor.read_u8().map_err(read_error)?;
+        let wake_count = cursor.read_u32::<LittleEndian>().map_err(read_error)?;
 
-        match message.header.magic_number {
-            0x12345678 => Ok(message),
-            0x78563412 => panic!("Magic number error, looks like wrong endiness: 0x78563412"),
-            val => panic!("Magic number error: {:#06x}", val),
+        let version_bytes = &data[data.len() - FIRMWARE_VERSION_SIZE..];
+        let version_end = version_bytes
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(FIRMWARE_VERSION_SIZE);
+        // Anything after the first NUL must be padding, so the message re-encodes identically
+        if version_bytes[version_end..].iter().any(|&byte| byte != 0) {
+            return Err(ParseError::InvalidEncoding {
+                format: "telemetry",
+                message: "firmware version has data after its NUL terminator".to_string(),
+            });
         }
+        let firmware_version = std::str::from_utf8(&version_bytes[..version_end])
+            .map_err(|_| ParseError::InvalidEncoding {
+                format: "telemetry",
+                message: "firmware version is not UTF-8".to_string(),
+            })?
+            .to_string();
+
+        Ok(TelemetryMessage {
+            header,
+            payload: TelemetryPayload {
+                posix_time,
+                battery_millivolts,
+                wifi_rssi,
+                reset_reason,
+                wake_count,
+                firmware_version,
+            },
+        })
     }
 }
 
 impl SensorMessagePayload {
-    pub fn to_sql_tuple(
+    /// Guesses which readings are valid in a payload without a validity bitmask.
+    ///
+    /// A failed BME280 read gives NaN. The firmware sends eCO2 = TVOC = 0 when the SGP30 fails,
+    /// which it never reports otherwise as eCO2 bottoms out at 400ppm. DHT22 fields are left at
+    /// 0.0 on failure; a real 0.0% humidity is implausible, and both fields being exactly zero is
+    /// taken as the whole sensor failing.
+    fn infer_validity(&self) -> u8 {
+        let mut valid = 0;
+        if [self.bme_temperature, self.bme_pressure, self.bme_humidity]
+            .iter()
+            .all(|value| value.is_finite())
+        {
+            valid |= VALID_BME280;
+        }
+        if self.sgp30_eCO2 != 0 || self.sgp30_TVOC != 0 {
+            valid |= VALID_SGP30;
+        }
+        let dht22_failed = self.dht22_temperature == 0.0 && self.dht22_humidity == 0.0;
+        if self.dht22_temperature.is_finite() && !dht22_failed {
+            valid |= VALID_DHT22_TEMPERATURE;
+        }
+        if self.dht22_humidity.is_finite() && self.dht22_humidity != 0.0 {
+            valid |= VALID_DHT22_HUMIDITY;
+        }
+        valid
+    }
+
+    /// Checks every float field marked as valid is finite, so NaN or infinity never reach the
+    /// database
+    fn check_valid_finite(&self) -> Result<(), ParseError> {
+        let fields = [
+            ("bme_temperature", self.bme_temperature, VALID_BME280),
+            ("bme_pressure", self.bme_pressure, VALID_BME280),
+            ("bme_humidity", self.bme_humidity, VALID_BME280),
+            (
+                "dht22_temperature",
+                self.dht22_temperature,
+                VALID_DHT22_TEMPERATURE,
+            ),
+            ("dht22_humidity", self.dht22_humidity, VALID_DHT22_HUMIDITY),
+        ];
+        match fields
+            .iter()
+            .find(|(_, value, bit)| self.valid & bit != 0 && !value.is_finite())
+        {
+            Some((name, _, _)) => Err(ParseError::NonFiniteFloat(name)),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns `value` if the readings under validity bit `bit` can be trusted
+    fn if_valid<T>(&self, bit: u8, value: T) -> Option<T> {
+        (self.valid & bit != 0).then_some(value)
+    }
+
+    /// The BME280's temperature, if it can be trusted
+    pub fn temperature_bme(&self) -> Option<Celsius> {
+        self.if_valid(VALID_BME280, Celsius(self.bme_temperature))
+    }
+
+    /// The DHT22's temperature, if it can be trusted
+    pub fn temperature_dht22(&self) -> Option<Celsius> {
+        self.if_valid(VALID_DHT22_TEMPERATURE, Celsius(self.dht22_temperature))
+    }
+
+    /// The BME280's pressure, if it can be trusted
+    pub fn pressure_bme(&self) -> Option<Pascal> {
+        self.if_valid(VALID_BME280, Pascal(self.bme_pressure))
+    }
+
+    /// The BME280's humidity, if it can be trusted
+    pub fn humidity_bme(&self) -> Option<RelativeHumidity> {
+        self.if_valid(VALID_BME280, RelativeHumidity(self.bme_humidity))
+    }
+
+    /// The DHT22's humidity, if it can be trusted
+    pub fn humidity_dht22(&self) -> Option<RelativeHumidity> {
+        self.if_valid(VALID_DHT22_HUMIDITY, RelativeHumidity(self.dht22_humidity))
+    }
+
+    /// The SGP30's equivalent CO2, if it can be trusted
+    pub fn eco2_sgp30(&self) -> Option<Ppm> {
+        self.if_valid(VALID_SGP30, Ppm(self.sgp30_eCO2 as f32))
+    }
+
+    /// The SGP30's total volatile organic compounds, if they can be trusted
+    pub fn tvoc_sgp30(&self) -> Option<Ppb> {
+        self.if_valid(VALID_SGP30, Ppb(self.sgp30_TVOC as f32))
+    }
+
+    /// Scales the payload to the integer columns of `weather_data`, with `None` (stored as NULL)
+    /// for readings from a sensor that failed. The scale factors are those of `units::FixedPoint`.
+    #[allow(clippy::type_complexity)]
+    pub fn to_sql_tuple<'a>(
         &self,
         received_time: i64,
-    ) -> (i64, i64, i32, i32, i32, i32, i32, i32, i32) {
+        station: &'a str,
+    ) -> (
+        i64,
+        i64,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        &'a str,
+    ) {
         (
             self.posix_time,
             received_time,
-            (self.bme_temperature * 10.0) as i32,
-            (self.dht22_temperature * 10.0) as i32,
-            self.bme_pressure as i32,
-            (self.bme_humidity * 100.0) as i32,
-            (self.dht22_humidity * 100.0) as i32,
-            self.sgp30_eCO2 as i32,
-            self.sgp30_TVOC as i32,
+            self.temperature_bme().map(FixedPoint::to_fixed),
+            self.temperature_dht22().map(FixedPoint::to_fixed),
+            self.pressure_bme().map(FixedPoint::to_fixed),
+            self.humidity_bme().map(FixedPoint::to_fixed),
+            self.humidity_dht22().map(FixedPoint::to_fixed),
+            self.eco2_sgp30().map(FixedPoint::to_fixed),
+            self.tvoc_sgp30().map(FixedPoint::to_fixed),
+            station,
         )
     }
 
@@ -81,6 +1294,7 @@ impl SensorMessagePayload {
             sgp30_TVOC: 25,
             dht22_temperature: 95f32,
             dht22_humidity: 20f32,
+            valid: VALID_BME280 | VALID_SGP30 | VALID_DHT22_TEMPERATURE | VALID_DHT22_HUMIDITY,
         }
     }
 }
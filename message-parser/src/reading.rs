@@ -0,0 +1,197 @@
+//! Typed rows read back from `weather_data`, for the dashboards and services that query the
+//! database rather than writing their own SQL.
+
+use crate::clock::ClockProblem;
+use crate::database::IngestSettings;
+use crate::derived::DerivedQuantities;
+use crate::mqtt_message::SensorMessagePayload;
+use crate::rollup::ROLLUP_COLUMNS;
+use crate::units::{AbsoluteHumidity, Celsius, FixedPoint, Pascal, Ppb, Ppm, RelativeHumidity};
+use rusqlite::{Result, Row};
+
+/// Selects the columns `Reading::from_row` reads
+pub(crate) const SELECT_READING_SQL: &str = "SELECT Station, MeasurementTime, ReceivedTime,
+    TemperatureBME, TemperatureDHT22, PressureBME, HumidityBME, HumidityDHT22, eCO2SGP30,
+    TVOCSGP30, DewPoint, AbsoluteHumidity, HeatIndex, Humidex, PressureSeaLevel, ClockStatus
+FROM weather_data";
+
+/// A row of `weather_data`, with readings from a failed sensor as `None`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reading {
+    pub station: String,
+    pub measurement_time: i64,
+    pub received_time: i64,
+    pub temperature_bme: Option<Celsius>,
+    pub temperature_dht22: Option<Celsius>,
+    pub pressure_bme: Option<Pascal>,
+    pub humidity_bme: Option<RelativeHumidity>,
+    pub humidity_dht22: Option<RelativeHumidity>,
+    pub eco2_sgp30: Option<Ppm>,
+    pub tvoc_sgp30: Option<Ppb>,
+    pub derived: DerivedQuantities,
+    /// Why the measurement time can't be trusted, if it can't
+    pub clock_status: Option<ClockProblem>,
+}
+
+/// Reads a fixed-point column as its quantity
+fn quantity<T: FixedPoint>(row: &Row, index: usize) -> Result<Option<T>> {
+    Ok(row.get::<_, Option<i32>>(index)?.map(T::from_fixed))
+}
+
+/// `quantity` as the database stores it, truncated to its scale
+fn stored<T: FixedPoint>(quantity: Option<T>) -> Option<T> {
+    quantity.map(|quantity| T::from_fixed(quantity.to_fixed()))
+}
+
+impl Reading {
+    /// The reading `WeatherDatabase` would store for `payload` from `station`, received at
+    /// `received_time`: its measurement time is checked by `settings.clock`, and its values are
+    /// truncated to the database's scale. `buffered` is as for `ClockConfig::check`.
+    pub fn from_payload(
+        station: &str,
+        received_time: i64,
+        payload: &SensorMessagePayload,
+        settings: &IngestSettings,
+        buffered: bool,
+    ) -> Self {
+        let check = settings
+            .clock
+            .check(payload.posix_time, received_time, buffered);
+        let derived = DerivedQuantities::from_payload(payload, settings.altitude);
+        Reading {
+            station: station.to_string(),
+            measurement_time: check.measurement_time,
+            received_time,
+            temperature_bme: stored(payload.temperature_bme()),
+            temperature_dht22: stored(payload.temperature_dht22()),
+            pressure_bme: stored(payload.pressure_bme()),
+            humidity_bme: stored(payload.humidity_bme()),
+            humidity_dht22: stored(payload.humidity_dht22()),
+            eco2_sgp30: stored(payload.eco2_sgp30()),
+            tvoc_sgp30: stored(payload.tvoc_sgp30()),
+            derived: DerivedQuantities {
+                dew_point: stored(derived.dew_point),
+                absolute_humidity: stored(derived.absolute_humidity),
+                heat_index: stored(derived.heat_index),
+                humidex: stored(derived.humidex),
+                sea_level_pressure: stored(derived.sea_level_pressure),
+            },
+            clock_status: check.problem,
+        }
+    }
+
+    /// Reads a row selected by `SELECT_READING_SQL`
+    pub(crate) fn from_row(row: &Row) -> Result<Self> {
+        let clock_status: Option<String> = row.get(15)?;
+        Ok(Reading {
+            station: row.get(0)?,
+            measurement_time: row.get(1)?,
+            received_time: row.get(2)?,
+            temperature_bme: quantity(row, 3)?,
+            temperature_dht22: quantity(row, 4)?,
+            pressure_bme: quantity(row, 5)?,
+            humidity_bme: quantity(row, 6)?,
+            humidity_dht22: quantity(row, 7)?,
+            eco2_sgp30: quantity(row, 8)?,
+            tvoc_sgp30: quantity(row, 9)?,
+            derived: DerivedQuantities {
+                dew_point: quantity(row, 10)?,
+                absolute_humidity: quantity(row, 11)?,
+                heat_index: quantity(row, 12)?,
+                humidex: quantity(row, 13)?,
+                sea_level_pressure: quantity(row, 14)?,
+            },
+            clock_status: clock_status.as_deref().and_then(ClockProblem::from_kind),
+        })
+    }
+}
+
+/// The spread of one column's readings over a time range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats<T> {
+    pub min: T,
+    pub max: T,
+    pub mean: T,
+    /// Readings of the column, leaving out those from a failed sensor
+    pub count: i64,
+}
+
+/// Every summarised column of `weather_data` over a time range, `None` where it has no readings.
+///
+/// The minimum and maximum are stored values, the mean isn't truncated to the database's scale.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadingAggregate {
+    pub station: String,
+    /// Start of the range, a POSIX time
+    pub start: i64,
+    /// End of the range, not included
+    pub end: i64,
+    pub reading_count: i64,
+    pub temperature_bme: Option<Stats<Celsius>>,
+    pub temperature_dht22: Option<Stats<Celsius>>,
+    pub pressure_bme: Option<Stats<Pascal>>,
+    pub humidity_bme: Option<Stats<RelativeHumidity>>,
+    pub humidity_dht22: Option<Stats<RelativeHumidity>>,
+    pub eco2_sgp30: Option<Stats<Ppm>>,
+    pub tvoc_sgp30: Option<Stats<Ppb>>,
+    pub dew_point: Option<Stats<Celsius>>,
+    pub absolute_humidity: Option<Stats<AbsoluteHumidity>>,
+    pub heat_index: Option<Stats<Celsius>>,
+    pub humidex: Option<Stats<Celsius>>,
+    pub sea_level_pressure: Option<Stats<Pascal>>,
+}
+
+/// Selects the reading count, then the minimum, maximum, mean and count of each of
+/// `ROLLUP_COLUMNS`, over the readings from station `?1` measured from `?2` up to `?3`
+pub(crate) fn aggregate_sql() -> String {
+    let mut values = vec!["COUNT(*)".to_string()];
+    for column in ROLLUP_COLUMNS {
+        values.push(format!("MIN({0}), MAX({0}), AVG({0}), COUNT({0})", column));
+    }
+    format!(
+        "SELECT {} FROM weather_data
+        WHERE Station = ?1 AND MeasurementTime >= ?2 AND MeasurementTime < ?3",
+        values.join(", ")
+    )
+}
+
+/// Reads the statistics of the `column`th of `ROLLUP_COLUMNS` from a row selected by
+/// `aggregate_sql`
+fn stats<T: FixedPoint>(row: &Row, column: usize) -> Result<Option<Stats<T>>> {
+    let index = 1 + column * 4;
+    let count: i64 = row.get(index + 3)?;
+    if count == 0 {
+        return Ok(None);
+    }
+    let mean: f64 = row.get(index + 2)?;
+    Ok(Some(Stats {
+        min: T::from_fixed(row.get(index)?),
+        max: T::from_fixed(row.get(index + 1)?),
+        mean: T::from_value((mean / T::SCALE as f64) as f32),
+        count,
+    }))
+}
+
+impl ReadingAggregate {
+    /// Reads a row selected by `aggregate_sql`
+    pub(crate) fn from_row(station: &str, start: i64, end: i64, row: &Row) -> Result<Self> {
+        Ok(ReadingAggregate {
+            station: station.to_string(),
+            start,
+            end,
+            reading_count: row.get(0)?,
+            temperature_bme: stats(row, 0)?,
+            temperature_dht22: stats(row, 1)?,
+            pressure_bme: stats(row, 2)?,
+            humidity_bme: stats(row, 3)?,
+            humidity_dht22: stats(row, 4)?,
+            eco2_sgp30: stats(row, 5)?,
+            tvoc_sgp30: stats(row, 6)?,
+            dew_point: stats(row, 7)?,
+            absolute_humidity: stats(row, 8)?,
+            heat_index: stats(row, 9)?,
+            humidex: stats(row, 10)?,
+            sea_level_pressure: stats(row, 11)?,
+        })
+    }
+}
@@ -0,0 +1,255 @@
+//! The `reprocess` subcommand, which decodes archived messages again and rewrites their readings.
+//!
+//! Every payload the parser receives is kept in `raw_messages`, so once a decoder or scaling bug
+//! is fixed, the readings stored from those payloads can be replaced by what the fixed parser makes
+//! of them. Readings imported from files, or stored before the archive existed, are left alone.
+
+use crate::config::Config;
+use crate::database::WeatherDatabase;
+use crate::mqtt_message::{check_sequence, MqttMessage};
+use crate::timestamp::{format_iso8601, now, parse_duration, parse_iso8601};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+pub const USAGE: &str = "Usage: message_parser reprocess [options]
+
+Decodes the messages archived in raw_messages again, with the current decoders and config, and
+replaces the readings stored from each with the new ones. The hourly and daily rollups are rebuilt
+afterwards. Dead letters of the messages that decode now are deleted. Messages that no longer
+decode, and repeats of a sequence number, are reported and their readings kept. Each station's
+first message is checked against the last it sent before them. Telemetry isn't reprocessed.
+
+Options:
+  --last DURATION     only messages received in the last DURATION, e.g. 90m, 24h, 7d or 2w
+  --since TIME        only messages received from TIME on, an ISO 8601 date such as 2025-03-15
+                      or 2025-03-15T12:00:00Z, or a POSIX time
+  --until TIME        only messages received before TIME
+  --station NAME      only messages from station NAME";
+
+/// Why `reprocess` failed
+#[derive(Debug)]
+pub enum ReprocessError {
+    /// The command line doesn't make sense, with a description of what's wrong
+    Usage(String),
+    Database(rusqlite::Error),
+}
+
+impl fmt::Display for ReprocessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReprocessError::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            ReprocessError::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ReprocessError {}
+
+impl From<rusqlite::Error> for ReprocessError {
+    fn from(err: rusqlite::Error) -> Self {
+        ReprocessError::Database(err)
+    }
+}
+
+/// Which archived messages to reprocess
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReprocessOptions {
+    /// Earliest POSIX time a message was received
+    pub since: Option<i64>,
+    /// POSIX time before which messages were received
+    pub until: Option<i64>,
+    pub station: Option<String>,
+}
+
+impl ReprocessOptions {
+    /// Parses the arguments that follow `reprocess`
+    pub fn parse(args: &[String]) -> Result<Self, ReprocessError> {
+        let mut options = ReprocessOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ReprocessError::Usage(format!("{} needs a value", arg)))
+            };
+            match arg.as_str() {
+                "--last" => {
+                    let duration = value()?;
+                    let seconds = parse_duration(duration).ok_or_else(|| {
+                        ReprocessError::Usage(format!("Invalid duration '{}'", duration))
+                    })?;
+                    options.since = Some(now() - seconds);
+                }
+                "--since" => options.since = Some(parse_time(value()?)?),
+                "--until" => options.until = Some(parse_time(value()?)?),
+                "--station" => options.station = Some(value()?.clone()),
+                _ => {
+                    return Err(ReprocessError::Usage(format!(
+                        "Unexpected argument '{}'",
+                        arg
+                    )))
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn parse_time(time: &str) -> Result<i64, ReprocessError> {
+    parse_iso8601(time).ok_or_else(|| ReprocessError::Usage(format!("Invalid time '{}'", time)))
+}
+
+/// What reprocessing did
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReprocessReport {
+    /// Messages whose readings were replaced
+    pub messages: usize,
+    /// Readings deleted, stored from those messages before
+    pub replaced: usize,
+    /// Readings stored in their place
+    pub inserted: usize,
+    /// Dead letters deleted, as their messages decode now
+    pub dead_letters: usize,
+    /// Messages that failed to decode or repeated a sequence number
+    pub rejected: usize,
+    /// Telemetry, and messages from other stations than the one asked for
+    pub skipped: usize,
+}
+
+impl fmt::Display for ReprocessReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Reprocessed {} messages, replacing {} readings with {} and deleting {} dead letters; \
+            rejected {} and skipped {} messages",
+            self.messages,
+            self.replaced,
+            self.inserted,
+            self.dead_letters,
+            self.rejected,
+            self.skipped
+        )
+    }
+}
+
+/// Archived messages read at a time while looking for the sequence numbers before `--since`
+const LOOKBACK_PAGE_SIZE: usize = 100;
+
+/// Decodes the archived messages `options` selects again, replacing the readings stored from them,
+/// then rebuilds the rollups. Messages that were kept as dead letters but decode now have their
+/// dead letters deleted, and those that are rejected now are logged to stderr.
+///
+/// Sequence numbers are checked among the messages reprocessed, as the parser checked them when
+/// they arrived, so repeated deliveries of a message don't store its readings twice. Each station's
+/// first message is checked against the last one archived from it before `since`.
+pub fn reprocess(
+    database: &mut WeatherDatabase,
+    config: &Config,
+    options: &ReprocessOptions,
+) -> Result<ReprocessReport, ReprocessError> {
+    let messages = database.raw_messages(
+        options.since.unwrap_or(i64::MIN),
+        options.until.unwrap_or(i64::MAX),
+    )?;
+    let mut report = ReprocessReport::default();
+    let wanted = |station: &str| {
+        options
+            .station
+            .as_deref()
+            .is_none_or(|name| name == station)
+    };
+    let mut last_sequences = match options.since {
+        Some(since) => {
+            let stations = messages
+                .iter()
+                .map(|message| config.route(&message.topic).0)
+                .filter(|station| wanted(station))
+                .collect();
+            sequences_before(database, config, since, stations)?
+        }
+        None => HashMap::new(),
+    };
+    for message in &messages {
+        let (station, format) = config.route(&message.topic);
+        if !wanted(station) {
+            report.skipped += 1;
+            continue;
+        }
+        let decoded = config
+            .decode_message(station, format, &message.payload)
+            .and_then(|decoded| {
+                if let Some(sequence) = decoded.sequence() {
+                    check_sequence(sequence, last_sequences.get(station).copied())?;
+                    last_sequences.insert(station.to_string(), sequence);
+                }
+                Ok(decoded)
+            });
+        let (payloads, buffered) = match decoded {
+            Ok(MqttMessage::Reading(reading)) => (vec![reading.payload], false),
+            Ok(MqttMessage::Batch(batch)) => (batch.payloads, true),
+            Ok(MqttMessage::Telemetry(_)) => {
+                report.skipped += 1;
+                continue;
+            }
+            Err(error) => {
+                eprintln!(
+                    "Message {} on '{}', received {}: {}",
+                    message.id,
+                    message.topic,
+                    format_iso8601(message.received_time),
+                    error
+                );
+                report.rejected += 1;
+                continue;
+            }
+        };
+        report.replaced += database.replace_readings(
+            message,
+            station,
+            &config.ingest_settings(station),
+            &payloads,
+            buffered,
+        )?;
+        report.dead_letters += database.remove_dead_letters_of(message.id)?;
+        report.messages += 1;
+        report.inserted += payloads.len();
+    }
+    if report.messages > 0 {
+        database.rebuild_rollups()?;
+    }
+    Ok(report)
+}
+
+/// The sequence number of the last message each of `stations` sent before `since`, found by
+/// decoding the archive backwards from there. Stations without one are left out.
+fn sequences_before(
+    database: &WeatherDatabase,
+    config: &Config,
+    since: i64,
+    mut stations: HashSet<&str>,
+) -> Result<HashMap<String, u32>, ReprocessError> {
+    let mut sequences = HashMap::new();
+    let mut before_id = i64::MAX;
+    while !stations.is_empty() {
+        let messages = database.raw_messages_before(since, before_id, LOOKBACK_PAGE_SIZE)?;
+        let Some(last) = messages.last() else {
+            break;
+        };
+        before_id = last.id;
+        for message in &messages {
+            let (station, format) = config.route(&message.topic);
+            if !stations.contains(station) {
+                continue;
+            }
+            // Messages that failed to decode when they arrived didn't move the sequence on
+            let sequence = config
+                .decode_message(station, format, &message.payload)
+                .ok()
+                .and_then(|decoded| decoded.sequence());
+            if let Some(sequence) = sequence {
+                stations.remove(station);
+                sequences.insert(station.to_string(), sequence);
+            }
+        }
+    }
+    Ok(sequences)
+}
@@ -0,0 +1,161 @@
+//! Deletes rows older than their retention period, so the database doesn't fill the SD card.
+//!
+//! Pruning runs on a schedule, a bounded batch of rows at a time, so the parser keeps storing
+//! readings in between. Once every table is pruned the freed pages are vacuumed, also a bounded
+//! number at a time.
+
+use crate::database::WeatherDatabase;
+use crate::timestamp::now;
+use rusqlite::Result;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/// How long to keep each kind of row, in days. Rows without a retention period are kept forever.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Readings in `weather_data`
+    pub raw_days: Option<u32>,
+    /// Payloads archived in `raw_messages`
+    pub raw_messages_days: Option<u32>,
+    /// Payloads that couldn't be decoded, in `dead_letters`
+    pub dead_letters_days: Option<u32>,
+    /// Rows of `device_telemetry`
+    pub telemetry_days: Option<u32>,
+    pub hourly_days: Option<u32>,
+    pub daily_days: Option<u32>,
+    /// Most rows deleted in one transaction
+    pub batch_size: usize,
+    /// Most free pages returned to the file system at a time, once rows are deleted
+    pub vacuum_pages: usize,
+    /// Time between pruning runs
+    pub interval_hours: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            raw_days: None,
+            raw_messages_days: None,
+            dead_letters_days: None,
+            telemetry_days: None,
+            hourly_days: None,
+            daily_days: None,
+            batch_size: 1000,
+            vacuum_pages: 1000,
+            interval_hours: 24,
+        }
+    }
+}
+
+/// A table to prune, by the POSIX time in `time_column`
+struct PruneTarget {
+    table: &'static str,
+    time_column: &'static str,
+    days: u32,
+}
+
+/// Prunes the database by a `RetentionConfig`, one batch per `step`
+pub struct Pruner {
+    targets: Vec<PruneTarget>,
+    batch_size: usize,
+    vacuum_pages: usize,
+    interval: Duration,
+    next_run: Instant,
+    /// The target being pruned and the rows deleted from it so far, while a run is under way
+    progress: Option<(usize, usize)>,
+}
+
+impl Pruner {
+    /// A pruner whose first run is due straight away
+    pub fn new(config: &RetentionConfig) -> Self {
+        let targets = [
+            ("weather_data", "MeasurementTime", config.raw_days),
+            ("raw_messages", "ReceivedTime", config.raw_messages_days),
+            ("dead_letters", "ReceivedTime", config.dead_letters_days),
+            ("device_telemetry", "MeasurementTime", config.telemetry_days),
+            ("weather_hourly", "PeriodStart", config.hourly_days),
+            ("weather_daily", "PeriodStart", config.daily_days),
+        ]
+        .into_iter()
+        .filter_map(|(table, time_column, days)| {
+            days.map(|days| PruneTarget {
+                table,
+                time_column,
+                days,
+            })
+        })
+        .collect();
+        Self {
+            targets,
+            batch_size: config.batch_size.max(1),
+            vacuum_pages: config.vacuum_pages.max(1),
+            interval: Duration::from_secs(config.interval_hours * 3600),
+            next_run: Instant::now(),
+            progress: None,
+        }
+    }
+
+    /// Deletes the next batch of expired rows if a run is due, or once a run has pruned every table,
+    /// vacuums the next `vacuum_pages` free pages. Returns whether the run has more to do.
+    pub fn step(&mut self, database: &mut WeatherDatabase) -> Result<bool> {
+        if self.targets.is_empty() {
+            return Ok(false);
+        }
+        let (index, deleted) = match self.progress {
+            Some(progress) => progress,
+            None if Instant::now() >= self.next_run => {
+                self.next_run = Instant::now() + self.interval;
+                (0, 0)
+            }
+            None => return Ok(false),
+        };
+
+        // A failed run is abandoned until the next is due
+        self.progress = None;
+        let Some(target) = self.targets.get(index) else {
+            // A full vacuum would lock the database until it finished, and stop ingest meanwhile
+            let remaining = database.incremental_vacuum(self.vacuum_pages)?;
+            if remaining > 0 {
+                self.progress = Some((index, 0));
+            }
+            return Ok(remaining > 0);
+        };
+        let batch = database.prune_before(
+            target.table,
+            target.time_column,
+            cutoff(target.days),
+            self.batch_size,
+        )?;
+        let deleted = deleted + batch;
+        if batch < self.batch_size {
+            if deleted > 0 {
+                println!(
+                    "Pruned {} rows older than {} days from {}",
+                    deleted, target.days, target.table
+                );
+            }
+            self.progress = Some((index + 1, 0));
+        } else {
+            self.progress = Some((index, deleted));
+        }
+        Ok(true)
+    }
+
+    /// Prunes every table and vacuums, regardless of the schedule. Unlike `step`, this converts a
+    /// database from before incremental vacuuming with a full vacuum.
+    pub fn run(&mut self, database: &mut WeatherDatabase) -> Result<()> {
+        self.next_run = Instant::now();
+        while self.step(database)? {}
+        database.vacuum()
+    }
+}
+
+/// The POSIX time before which rows kept for `days` have expired. It falls on a UTC day boundary,
+/// so whole rollup periods expire together.
+fn cutoff(days: u32) -> i64 {
+    let cutoff = now() - days as i64 * SECONDS_PER_DAY;
+    cutoff - cutoff.rem_euclid(SECONDS_PER_DAY)
+}
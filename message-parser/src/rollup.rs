@@ -0,0 +1,166 @@
+//! Hourly and daily summaries of `weather_data`, so dashboards covering months don't have to scan
+//! every reading.
+//!
+//! Each rollup table has a row per station and period, with the minimum, maximum, mean and count
+//! of every summarised column. Periods start on multiples of their length in POSIX time, so days
+//! are UTC days. The values are in the same fixed-point units as `weather_data`.
+
+use rusqlite::{Connection, Result};
+
+/// Columns of `weather_data` that are summarised
+pub const ROLLUP_COLUMNS: [&str; 12] = [
+    "TemperatureBME",
+    "TemperatureDHT22",
+    "PressureBME",
+    "HumidityBME",
+    "HumidityDHT22",
+    "eCO2SGP30",
+    "TVOCSGP30",
+    "DewPoint",
+    "AbsoluteHumidity",
+    "HeatIndex",
+    "Humidex",
+    "PressureSeaLevel",
+];
+
+/// A table summarising `weather_data` over periods of `period` seconds
+pub struct Rollup {
+    pub table: &'static str,
+    pub period: i64,
+}
+
+pub const ROLLUPS: [Rollup; 2] = [
+    Rollup {
+        table: "weather_hourly",
+        period: 3600,
+    },
+    Rollup {
+        table: "weather_daily",
+        period: 86400,
+    },
+];
+
+impl Rollup {
+    /// The start of the period containing `column`, as SQL
+    fn period_start(&self, column: &str) -> String {
+        format!("{} - {} % {}", column, column, self.period)
+    }
+
+    /// The columns of the table, in the order the SELECTs below produce them
+    fn columns(&self) -> String {
+        let mut columns = vec![
+            "Station".to_string(),
+            "PeriodStart".to_string(),
+            "ReadingCount".to_string(),
+        ];
+        for column in ROLLUP_COLUMNS {
+            for suffix in ["Min", "Max", "Mean", "Count"] {
+                columns.push(format!("{}{}", column, suffix));
+            }
+        }
+        columns.join(", ")
+    }
+
+    fn create_sql(&self) -> String {
+        let mut sql = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+            Station TEXT NOT NULL,
+            PeriodStart INTEGER NOT NULL,
+            ReadingCount INTEGER NOT NULL",
+            self.table
+        );
+        for column in ROLLUP_COLUMNS {
+            sql += &format!(
+                ",
+            {0}Min INTEGER,
+            {0}Max INTEGER,
+            {0}Mean REAL,
+            {0}Count INTEGER NOT NULL",
+                column
+            );
+        }
+        sql + ",
+            PRIMARY KEY (Station, PeriodStart)
+            )"
+    }
+
+    /// Adds the `weather_data` row with rowid `?1` to its period
+    fn record_sql(&self) -> String {
+        let mut values = vec![
+            "Station".to_string(),
+            self.period_start("MeasurementTime"),
+            "1".to_string(),
+        ];
+        let mut updates = vec!["ReadingCount = ReadingCount + 1".to_string()];
+        for column in ROLLUP_COLUMNS {
+            values.push(format!("{0}, {0}, {0}, {0} IS NOT NULL", column));
+            updates.push(format!(
+                "{0}Min = COALESCE(MIN({0}Min, excluded.{0}Min), {0}Min, excluded.{0}Min),
+                {0}Max = COALESCE(MAX({0}Max, excluded.{0}Max), {0}Max, excluded.{0}Max),
+                {0}Mean = CASE WHEN excluded.{0}Count = 0 THEN {0}Mean
+                    ELSE (COALESCE({0}Mean, 0) * {0}Count + excluded.{0}Mean) / ({0}Count + 1.0) END,
+                {0}Count = {0}Count + excluded.{0}Count",
+                column
+            ));
+        }
+        // The WHERE keeps SQLite from reading ON CONFLICT as part of a join
+        format!(
+            "INSERT INTO {} ({}) SELECT {} FROM weather_data WHERE rowid = ?1
+            ON CONFLICT (Station, PeriodStart) DO UPDATE SET {}",
+            self.table,
+            self.columns(),
+            values.join(", "),
+            updates.join(", ")
+        )
+    }
+
+    /// Recomputes every period that has readings in `weather_data`
+    fn rebuild_sql(&self) -> String {
+        let mut values = vec![
+            "Station".to_string(),
+            self.period_start("MeasurementTime"),
+            "COUNT(*)".to_string(),
+        ];
+        for column in ROLLUP_COLUMNS {
+            values.push(format!("MIN({0}), MAX({0}), AVG({0}), COUNT({0})", column));
+        }
+        format!(
+            "INSERT OR REPLACE INTO {} ({}) SELECT {} FROM weather_data
+            WHERE Station IS NOT NULL AND MeasurementTime IS NOT NULL
+            GROUP BY Station, {}",
+            self.table,
+            self.columns(),
+            values.join(", "),
+            self.period_start("MeasurementTime")
+        )
+    }
+}
+
+/// Creates the rollup tables, if they don't exist
+pub(crate) fn create_tables(conn: &Connection) -> Result<()> {
+    for rollup in &ROLLUPS {
+        conn.execute(&rollup.create_sql(), [])?;
+    }
+    Ok(())
+}
+
+/// Adds the `weather_data` row with `rowid` to every rollup
+pub(crate) fn record(conn: &Connection, rowid: i64) -> Result<()> {
+    for rollup in &ROLLUPS {
+        conn.prepare_cached(&rollup.record_sql())?
+            .execute([rowid])?;
+    }
+    Ok(())
+}
+
+/// Recomputes every rollup period that has readings in `weather_data`, returning how many rows
+/// were written.
+///
+/// Periods whose readings have all been deleted are kept, so rollups outlive pruned readings.
+pub(crate) fn rebuild(conn: &Connection) -> Result<usize> {
+    let mut rows = 0;
+    for rollup in &ROLLUPS {
+        rows += conn.execute(&rollup.rebuild_sql(), [])?;
+    }
+    Ok(rows)
+}
@@ -0,0 +1,266 @@
+//! Where the ingest loop stores what the stations send.
+//!
+//! `ReadingStore` is the part of `WeatherDatabase` that `on_message` uses, so readings can go
+//! somewhere other than SQLite: `MemoryStore` keeps them for tests, and `influx::InfluxStore`
+//! writes them to InfluxDB. The `store` setting in the config picks one.
+
+use crate::database::{IngestSettings, TelemetryRecord, WeatherDatabase};
+use crate::mqtt_message::{ParseError, SensorMessagePayload, TelemetryPayload};
+use crate::reading::Reading;
+use crate::timestamp::now;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Which `ReadingStore` the parser writes to
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    /// `WeatherDatabase`, at the config's `database_path`
+    #[default]
+    Sqlite,
+    /// `influx::InfluxStore`, with the config's `[influxdb]` settings
+    Influxdb,
+}
+
+/// Why a store couldn't store or read something
+#[derive(Debug)]
+pub enum StoreError {
+    Database(rusqlite::Error),
+    /// The request to a remote store failed before it answered, e.g. it isn't running
+    Connection(String),
+    /// A remote store answered with an HTTP error status
+    Rejected {
+        status: u16,
+        message: String,
+    },
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Database(err) => write!(f, "Database error: {}", err),
+            StoreError::Connection(message) => write!(f, "Could not connect: {}", message),
+            StoreError::Rejected { status, message } => {
+                write!(f, "Rejected with HTTP status {}: {}", status, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        StoreError::Database(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, StoreError>;
+
+/// Stores readings and telemetry from the stations, and the sequence numbers that catch repeated
+/// messages.
+///
+/// Stores may hold writes back to group them, so `commit_if_due` should be called regularly and
+/// `flush` before exiting.
+pub trait ReadingStore {
+    /// Keeps an MQTT `payload` as it was received on `topic`, before it is decoded, so it can be
+    /// decoded again later. Stores without an archive drop it.
+    fn archive_message(&mut self, _topic: &str, _payload: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Keeps a `payload` received on `topic` that couldn't be decoded, with the `error` it was
+    /// rejected for, so it can be looked into and tried again. Stores without a dead-letter table
+    /// drop it.
+    fn insert_dead_letter(
+        &mut self,
+        _topic: &str,
+        _payload: &[u8],
+        _error: &ParseError,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stores one reading from `station`
+    fn insert_sensor_data(
+        &mut self,
+        station: &str,
+        settings: &IngestSettings,
+        payload: &SensorMessagePayload,
+    ) -> Result<()>;
+
+    /// Stores the readings of a batch message from `station`, all of them or none
+    fn insert_sensor_batch(
+        &mut self,
+        station: &str,
+        settings: &IngestSettings,
+        payloads: &[SensorMessagePayload],
+    ) -> Result<()>;
+
+    fn insert_telemetry(&mut self, station: &str, telemetry: &TelemetryPayload) -> Result<()>;
+
+    /// The sequence number of the latest message stored from `station`, if it has sent any
+    fn last_sequence(&self, station: &str) -> Result<Option<u32>>;
+
+    /// Records `sequence` as the latest message stored from `station`
+    fn set_last_sequence(&mut self, station: &str, sequence: u32) -> Result<()>;
+
+    /// Writes out held-back writes if they have waited long enough
+    fn commit_if_due(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Writes out every held-back write
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ReadingStore for WeatherDatabase {
+    fn archive_message(&mut self, topic: &str, payload: &[u8]) -> Result<()> {
+        WeatherDatabase::archive_message(self, topic, now(), payload)?;
+        Ok(())
+    }
+
+    fn insert_dead_letter(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        error: &ParseError,
+    ) -> Result<()> {
+        WeatherDatabase::insert_dead_letter(self, topic, now(), payload, error)?;
+        Ok(())
+    }
+
+    fn insert_sensor_data(
+        &mut self,
+        station: &str,
+        settings: &IngestSettings,
+        payload: &SensorMessagePayload,
+    ) -> Result<()> {
+        Ok(WeatherDatabase::insert_sensor_data(
+            self, station, settings, payload,
+        )?)
+    }
+
+    fn insert_sensor_batch(
+        &mut self,
+        station: &str,
+        settings: &IngestSettings,
+        payloads: &[SensorMessagePayload],
+    ) -> Result<()> {
+        Ok(WeatherDatabase::insert_sensor_batch(
+            self, station, settings, payloads,
+        )?)
+    }
+
+    fn insert_telemetry(&mut self, station: &str, telemetry: &TelemetryPayload) -> Result<()> {
+        Ok(WeatherDatabase::insert_telemetry(self, station, telemetry)?)
+    }
+
+    fn last_sequence(&self, station: &str) -> Result<Option<u32>> {
+        Ok(WeatherDatabase::last_sequence(self, station)?)
+    }
+
+    fn set_last_sequence(&mut self, station: &str, sequence: u32) -> Result<()> {
+        Ok(WeatherDatabase::set_last_sequence(self, station, sequence)?)
+    }
+
+    fn commit_if_due(&mut self) -> Result<()> {
+        Ok(WeatherDatabase::commit_if_due(self)?)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(WeatherDatabase::flush(self)?)
+    }
+}
+
+/// Keeps everything in memory, in the order it was stored, as `WeatherDatabase` would store it.
+/// For tests of code that writes to a `ReadingStore`.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    /// The topic and payload of each message archived
+    pub raw_messages: Vec<(String, Vec<u8>)>,
+    /// The topic and payload of each message that couldn't be decoded, with the error
+    pub dead_letters: Vec<(String, Vec<u8>, ParseError)>,
+    pub readings: Vec<Reading>,
+    pub telemetry: Vec<TelemetryRecord>,
+    pub sequences: HashMap<String, u32>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert_readings(
+        &mut self,
+        station: &str,
+        settings: &IngestSettings,
+        payloads: &[SensorMessagePayload],
+        buffered: bool,
+    ) {
+        let received_time = now();
+        self.readings.extend(payloads.iter().map(|payload| {
+            Reading::from_payload(station, received_time, payload, settings, buffered)
+        }));
+    }
+}
+
+impl ReadingStore for MemoryStore {
+    fn archive_message(&mut self, topic: &str, payload: &[u8]) -> Result<()> {
+        self.raw_messages
+            .push((topic.to_string(), payload.to_vec()));
+        Ok(())
+    }
+
+    fn insert_dead_letter(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        error: &ParseError,
+    ) -> Result<()> {
+        self.dead_letters
+            .push((topic.to_string(), payload.to_vec(), error.clone()));
+        Ok(())
+    }
+
+    fn insert_sensor_data(
+        &mut self,
+        station: &str,
+        settings: &IngestSettings,
+        payload: &SensorMessagePayload,
+    ) -> Result<()> {
+        self.insert_readings(station, settings, std::slice::from_ref(payload), false);
+        Ok(())
+    }
+
+    fn insert_sensor_batch(
+        &mut self,
+        station: &str,
+        settings: &IngestSettings,
+        payloads: &[SensorMessagePayload],
+    ) -> Result<()> {
+        self.insert_readings(station, settings, payloads, true);
+        Ok(())
+    }
+
+    fn insert_telemetry(&mut self, station: &str, telemetry: &TelemetryPayload) -> Result<()> {
+        self.telemetry.push(TelemetryRecord {
+            station: station.to_string(),
+            received_time: now(),
+            telemetry: telemetry.clone(),
+        });
+        Ok(())
+    }
+
+    fn last_sequence(&self, station: &str) -> Result<Option<u32>> {
+        Ok(self.sequences.get(station).copied())
+    }
+
+    fn set_last_sequence(&mut self, station: &str, sequence: u32) -> Result<()> {
+        self.sequences.insert(station.to_string(), sequence);
+        Ok(())
+    }
+}
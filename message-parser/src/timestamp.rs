@@ -0,0 +1,157 @@
+//! Converts between the POSIX times stored in the database and the ISO 8601 dates people type.
+//!
+//! Every time is UTC. The calendar arithmetic is Howard Hinnant's `days_from_civil` and
+//! `civil_from_days`, valid for any year.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/// The current POSIX time
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time predates unix epoch???")
+        .as_secs() as i64
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The (year, month, day) that is `days` since 1970-01-01
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+/// Formats a POSIX time as ISO 8601, e.g. `2025-03-15T20:19:32Z`
+pub fn format_iso8601(posix_time: i64) -> String {
+    let (year, month, day) = civil_from_days(posix_time.div_euclid(SECONDS_PER_DAY));
+    let seconds = posix_time.rem_euclid(SECONDS_PER_DAY);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Parses `digits` as a number between `min` and `max`
+fn number(digits: &str, min: i64, max: i64) -> Option<i64> {
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    digits
+        .parse()
+        .ok()
+        .filter(|number| (min..=max).contains(number))
+}
+
+/// Parses a UTC offset such as `Z`, `+01:00` or `-0530` into seconds east of UTC
+fn utc_offset(offset: &str) -> Option<i64> {
+    if offset.is_empty() || offset == "Z" {
+        return Some(0);
+    }
+    let sign = match offset.as_bytes()[0] {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let offset = offset[1..].replace(':', "");
+    if offset.len() != 4 || !offset.is_ascii() {
+        return None;
+    }
+    let hours = number(&offset[..2], 0, 23)?;
+    let minutes = number(&offset[2..], 0, 59)?;
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Parses an ISO 8601 date, or date and time, into a POSIX time.
+///
+/// Accepts `2025-03-15`, `2025-03-15T20:19`, `2025-03-15T20:19:32`, with a space allowed in place
+/// of the `T`, and a `Z` or UTC offset allowed after the time. Times without an offset are UTC. A
+/// bare integer is taken as a POSIX time already.
+pub fn parse_iso8601(text: &str) -> Option<i64> {
+    let text = text.trim();
+    if let Ok(posix_time) = text.parse() {
+        return Some(posix_time);
+    }
+    let (date, time) = match text.find(['T', ' ']) {
+        Some(index) => (&text[..index], Some(&text[index + 1..])),
+        None => (text, None),
+    };
+
+    let mut fields = date.split('-');
+    let year = number(fields.next()?, 0, 9999)?;
+    let month = number(fields.next()?, 1, 12)?;
+    let day = number(fields.next()?, 1, 31)?;
+    if fields.next().is_some() || day > days_in_month(year, month) {
+        return None;
+    }
+    let mut posix_time = days_from_civil(year, month, day) * SECONDS_PER_DAY;
+
+    if let Some(time) = time {
+        let offset_start = time.find(['Z', '+', '-']).unwrap_or(time.len());
+        let (time, offset) = time.split_at(offset_start);
+        let mut fields = time.split(':');
+        let hour = number(fields.next()?, 0, 23)?;
+        let minute = number(fields.next()?, 0, 59)?;
+        let second = match fields.next() {
+            Some(second) => number(second, 0, 59)?,
+            None => 0,
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+        posix_time += hour * 3600 + minute * 60 + second - utc_offset(offset)?;
+    }
+    Some(posix_time)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    let next_month = if month == 12 {
+        days_from_civil(year + 1, 1, 1)
+    } else {
+        days_from_civil(year, month + 1, 1)
+    };
+    next_month - days_from_civil(year, month, 1)
+}
+
+/// Parses a length of time such as `90s`, `30m`, `24h`, `7d` or `2w` into seconds
+pub fn parse_duration(text: &str) -> Option<i64> {
+    let text = text.trim();
+    let unit_start = text.find(|c: char| !c.is_ascii_digit())?;
+    let (count, unit) = text.split_at(unit_start);
+    let unit_seconds = match unit {
+        "s" => 1,
+        "m" | "min" => 60,
+        "h" => 3600,
+        "d" => SECONDS_PER_DAY,
+        "w" => 7 * SECONDS_PER_DAY,
+        _ => return None,
+    };
+    number(count, 0, i64::MAX)?.checked_mul(unit_seconds)
+}
@@ -0,0 +1,135 @@
+//! Physical quantities measured by the stations, and how they are stored in the database.
+//!
+//! Each sensor reading gets a newtype in the unit the sensor reports it in. The database stores
+//! them as fixed-point integers, with the scale factors in one place here: `FixedPoint::SCALE`.
+
+use std::fmt;
+
+/// Pascals in one inch of mercury at 0 °C
+const PASCALS_PER_INCH_OF_MERCURY: f32 = 3386.389;
+
+/// A quantity stored in the database as an integer count of `1 / SCALE` units
+pub trait FixedPoint: Copy {
+    /// Database integers per unit, e.g. 10 for a column in tenths
+    const SCALE: f32;
+
+    /// The symbol of the unit, e.g. `°C`
+    const SYMBOL: &'static str;
+
+    /// The value in the unit of this type
+    fn value(self) -> f32;
+
+    /// Builds the quantity from a value in the unit of this type
+    fn from_value(value: f32) -> Self;
+
+    /// The integer the database stores, truncated toward zero
+    fn to_fixed(self) -> i32 {
+        (self.value() * Self::SCALE) as i32
+    }
+
+    /// The quantity an integer from the database stands for
+    fn from_fixed(fixed: i32) -> Self {
+        Self::from_value(fixed as f32 / Self::SCALE)
+    }
+}
+
+macro_rules! quantity {
+    ($(#[$doc:meta])* $name:ident, $symbol:literal, $scale:literal) => {
+        $(#[$doc])*
+        #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+        pub struct $name(pub f32);
+
+        impl FixedPoint for $name {
+            const SCALE: f32 = $scale;
+            const SYMBOL: &'static str = $symbol;
+
+            fn value(self) -> f32 {
+                self.0
+            }
+
+            fn from_value(value: f32) -> Self {
+                $name(value)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match f.precision() {
+                    Some(precision) => write!(f, "{:.*} {}", precision, self.0, Self::SYMBOL),
+                    None => write!(f, "{} {}", self.0, Self::SYMBOL),
+                }
+            }
+        }
+    };
+}
+
+quantity!(
+    /// Temperature in degrees Celsius, stored in tenths
+    Celsius,
+    "°C",
+    10.0
+);
+quantity!(
+    /// Pressure in pascals, stored whole
+    Pascal,
+    "Pa",
+    1.0
+);
+quantity!(
+    /// Relative humidity in percent, stored in hundredths
+    RelativeHumidity,
+    "%",
+    100.0
+);
+quantity!(
+    /// Absolute humidity in grams of water vapour per cubic metre of air, stored in hundredths
+    AbsoluteHumidity,
+    "g/m³",
+    100.0
+);
+quantity!(
+    /// Concentration in parts per million (the SGP30's eCO2), stored whole
+    Ppm,
+    "ppm",
+    1.0
+);
+quantity!(
+    /// Concentration in parts per billion (the SGP30's TVOC), stored whole
+    Ppb,
+    "ppb",
+    1.0
+);
+
+impl Celsius {
+    pub fn from_fahrenheit(fahrenheit: f32) -> Self {
+        Celsius((fahrenheit - 32.0) / 1.8)
+    }
+
+    pub fn fahrenheit(self) -> f32 {
+        self.0 * 1.8 + 32.0
+    }
+
+    /// The temperature in kelvin
+    pub fn kelvin(self) -> f32 {
+        self.0 + 273.15
+    }
+}
+
+impl Pascal {
+    pub fn from_hectopascals(hectopascals: f32) -> Self {
+        Pascal(hectopascals * 100.0)
+    }
+
+    /// The pressure in hectopascals, the same as millibars
+    pub fn hectopascals(self) -> f32 {
+        self.0 / 100.0
+    }
+
+    pub fn from_inches_of_mercury(inches: f32) -> Self {
+        Pascal(inches * PASCALS_PER_INCH_OF_MERCURY)
+    }
+
+    pub fn inches_of_mercury(self) -> f32 {
+        self.0 / PASCALS_PER_INCH_OF_MERCURY
+    }
+}
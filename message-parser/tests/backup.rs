@@ -0,0 +1,202 @@
+//! Tests that backups are consistent copies that restore, and that rotation keeps the right ones
+
+mod common;
+
+use common::{TempDatabase, TempPath};
+use message_parser::backup::{self, BackupConfig, BackupError};
+use message_parser::clock::{ClockConfig, ClockPolicy};
+use message_parser::database::{IngestSettings, WeatherDatabase};
+use message_parser::mqtt_message::SensorMessagePayload;
+use message_parser::timestamp::parse_iso8601;
+
+/// Stores `count` readings from "garden", ten minutes apart from `start`
+fn insert_readings(database: &mut WeatherDatabase, start: i64, count: i64) {
+    let clock = ClockConfig {
+        policy: ClockPolicy::Flag,
+        ..ClockConfig::default()
+    };
+    let settings = IngestSettings {
+        altitude: None,
+        clock: &clock,
+    };
+    let payloads: Vec<SensorMessagePayload> = (0..count)
+        .map(|index| SensorMessagePayload {
+            posix_time: start + index * 600,
+            ..SensorMessagePayload::create_dummy()
+        })
+        .collect();
+    database
+        .insert_sensor_batch("garden", &settings, &payloads)
+        .unwrap();
+}
+
+fn reading_count(database: &WeatherDatabase) -> i64 {
+    database
+        .station_summaries()
+        .unwrap()
+        .iter()
+        .map(|summary| summary.reading_count)
+        .sum()
+}
+
+fn time(iso8601: &str) -> i64 {
+    parse_iso8601(iso8601).unwrap()
+}
+
+#[test]
+fn backups_restore_the_database_as_it_was() {
+    let database = TempDatabase::new("backup-source");
+    let directory = TempPath::new("backup-directory");
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    insert_readings(&mut weather_database, 1742068800, 50);
+
+    let path = backup::backup(&mut weather_database, directory.path()).unwrap();
+    let name = path.file_name().unwrap().to_str().unwrap();
+    assert!(
+        name.starts_with("weather-") && name.ends_with("Z.db"),
+        "{}",
+        name
+    );
+    backup::check_integrity(&path).unwrap();
+    // A single file, without a write-ahead log or a partial copy next to it
+    assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 1);
+
+    let copy = WeatherDatabase::new(path.to_str().unwrap()).unwrap();
+    assert_eq!(reading_count(&copy), 50);
+    assert_eq!(
+        copy.latest_reading("garden").unwrap(),
+        weather_database.latest_reading("garden").unwrap()
+    );
+    drop(copy);
+
+    insert_readings(&mut weather_database, 1742100000, 20);
+    assert_eq!(reading_count(&weather_database), 70);
+    backup::restore(&mut weather_database, &path).unwrap();
+    assert_eq!(reading_count(&weather_database), 50);
+    drop(weather_database);
+
+    // The restored database opens as usual
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    assert_eq!(
+        weather_database.integrity_check().unwrap(),
+        Vec::<String>::new()
+    );
+    insert_readings(&mut weather_database, 1742200000, 1);
+    assert_eq!(reading_count(&weather_database), 51);
+}
+
+#[test]
+fn damaged_or_newer_backups_are_not_restored() {
+    let database = TempDatabase::new("restore-target");
+    let directory = TempPath::new("restore-backups");
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    insert_readings(&mut weather_database, 1742068800, 10);
+    let path = backup::backup(&mut weather_database, directory.path()).unwrap();
+
+    // Overwrite part of a table's pages, past the header
+    let mut bytes = std::fs::read(&path).unwrap();
+    let length = bytes.len();
+    bytes[length - 3000..length - 1000].fill(0x55);
+    let damaged = directory.path().join("damaged.db");
+    std::fs::write(&damaged, &bytes).unwrap();
+    // SQLite may find the damage while checking, or report it as an error
+    assert!(matches!(
+        backup::restore(&mut weather_database, &damaged),
+        Err(BackupError::Corrupt { .. } | BackupError::Database(_))
+    ));
+
+    let not_a_database = directory.path().join("notes.db");
+    std::fs::write(&not_a_database, "not a database").unwrap();
+    assert!(matches!(
+        backup::restore(&mut weather_database, &not_a_database),
+        Err(BackupError::Database(_))
+    ));
+
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    conn.pragma_update(None, "user_version", 999).unwrap();
+    drop(conn);
+    assert!(matches!(
+        backup::restore(&mut weather_database, &path),
+        Err(BackupError::TooNew { version: 999, .. })
+    ));
+    assert_eq!(reading_count(&weather_database), 10);
+}
+
+#[test]
+fn rotation_keeps_the_newest_of_each_day_week_and_month() {
+    // A backup every six hours for a year, up to the morning of Wednesday 2025-03-19
+    let newest = time("2025-03-19T06:00:00Z");
+    let times: Vec<i64> = (0..4 * 365)
+        .map(|index| newest - index * 6 * 3600)
+        .collect();
+    let config = BackupConfig {
+        daily: 3,
+        weekly: 2,
+        monthly: 3,
+        ..BackupConfig::default()
+    };
+    let mut kept: Vec<i64> = backup::backups_to_keep(&times, &config)
+        .into_iter()
+        .collect();
+    kept.sort_unstable();
+    let expected: Vec<i64> = [
+        // Newest of February and January
+        "2025-01-31T18:00:00Z",
+        "2025-02-28T18:00:00Z",
+        // Newest of the week before
+        "2025-03-16T18:00:00Z",
+        // Newest of the two days before
+        "2025-03-17T18:00:00Z",
+        "2025-03-18T18:00:00Z",
+        // The newest is the newest of its day, week and month too
+        "2025-03-19T06:00:00Z",
+    ]
+    .iter()
+    .map(|iso8601| time(iso8601))
+    .collect();
+    assert_eq!(kept, expected);
+
+    let none = BackupConfig {
+        daily: 0,
+        weekly: 0,
+        monthly: 0,
+        ..BackupConfig::default()
+    };
+    assert_eq!(backup::backups_to_keep(&times, &none).len(), 1);
+}
+
+#[test]
+fn rotation_only_deletes_backups() {
+    let directory = TempPath::new("backup-rotation");
+    std::fs::create_dir_all(directory.path()).unwrap();
+    for iso8601 in [
+        "2025-03-17T06:00:00Z",
+        "2025-03-18T06:00:00Z",
+        "2025-03-18T12:00:00Z",
+    ] {
+        let name = backup::backup_name(time(iso8601));
+        std::fs::write(directory.path().join(name), "").unwrap();
+    }
+    std::fs::write(directory.path().join("weather-notes.db"), "").unwrap();
+    std::fs::write(directory.path().join("README"), "").unwrap();
+
+    let config = BackupConfig {
+        daily: 1,
+        weekly: 0,
+        monthly: 0,
+        ..BackupConfig::default()
+    };
+    let removed = backup::rotate(directory.path(), &config).unwrap();
+    assert_eq!(removed.len(), 2);
+    let mut remaining: Vec<String> = std::fs::read_dir(directory.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    remaining.sort();
+    assert_eq!(
+        remaining,
+        ["README", "weather-20250318T120000Z.db", "weather-notes.db"]
+    );
+}
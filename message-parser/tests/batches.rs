@@ -0,0 +1,108 @@
+//! Tests that the readings in a batch message are stored together in one transaction, each at its
+//! own measurement time, or not at all
+
+mod common;
+
+use common::{payload, TempDatabase, CLOCK};
+use message_parser::database::{CommitPolicy, IngestSettings, WeatherDatabase};
+use message_parser::mqtt_message::{
+    MqttMessage, SensorBatchMessage, SensorMessageHeader, CURRENT_VERSION,
+};
+
+const SETTINGS: IngestSettings = IngestSettings {
+    altitude: None,
+    clock: &CLOCK,
+};
+
+fn open(database: &TempDatabase, max_rows: usize) -> WeatherDatabase {
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    weather_database.with_commit_policy(CommitPolicy {
+        max_rows,
+        max_delay_ms: 3_600_000,
+    })
+}
+
+/// Decodes a batch message of `readings`, each a measurement time and BME280 temperature
+fn decode_batch(readings: &[(i64, f32)]) -> SensorBatchMessage {
+    let header = SensorMessageHeader::batch(CURRENT_VERSION, 0)
+        .unwrap()
+        .with_sequence(1);
+    let payloads = readings
+        .iter()
+        .map(|&(posix_time, temperature)| payload(posix_time, temperature))
+        .collect();
+    let data = SensorBatchMessage::new(header, payloads)
+        .unwrap()
+        .to_bytes();
+    match MqttMessage::from_bytes(&data).unwrap() {
+        MqttMessage::Batch(batch) => batch,
+        message => panic!("Expected a batch, got {:?}", message),
+    }
+}
+
+/// The measurement times committed, as another connection sees them
+fn committed(database: &TempDatabase) -> Vec<i64> {
+    let conn = rusqlite::Connection::open(database.path()).unwrap();
+    let mut stmt = conn
+        .prepare("SELECT MeasurementTime FROM weather_data ORDER BY MeasurementTime")
+        .unwrap();
+    stmt.query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+#[test]
+fn batches_are_committed_together() {
+    let database = TempDatabase::new("batch");
+    let mut weather_database = open(&database, 4);
+    let batch = decode_batch(&[(1742068800, 10.0), (1742069400, 10.5), (1742070000, 11.0)]);
+    weather_database
+        .insert_sensor_batch("garden", &SETTINGS, &batch.payloads)
+        .unwrap();
+    assert!(committed(&database).is_empty());
+
+    // Each reading of the batch counts towards `max_rows`
+    let next = decode_batch(&[(1742070600, 11.5), (1742071200, 12.0)]);
+    weather_database
+        .insert_sensor_batch("garden", &SETTINGS, &next.payloads)
+        .unwrap();
+    assert_eq!(
+        committed(&database),
+        [1742068800, 1742069400, 1742070000, 1742070600, 1742071200]
+    );
+}
+
+#[test]
+fn a_bad_reading_stores_none_of_its_batch() {
+    let database = TempDatabase::new("batch-rollback");
+    let mut weather_database = open(&database, 1);
+    // Temperatures are stored in tenths of a degree
+    rusqlite::Connection::open(database.path())
+        .unwrap()
+        .execute_batch(
+            "CREATE TRIGGER too_hot BEFORE INSERT ON weather_data WHEN NEW.TemperatureBME > 1000
+            BEGIN SELECT RAISE(ABORT, 'too hot'); END",
+        )
+        .unwrap();
+
+    let batch = decode_batch(&[(1742068800, 10.0), (1742069400, 150.0), (1742070000, 11.0)]);
+    assert!(weather_database
+        .insert_sensor_batch("garden", &SETTINGS, &batch.payloads)
+        .is_err());
+    weather_database.flush().unwrap();
+    assert!(committed(&database).is_empty());
+    let conn = rusqlite::Connection::open(database.path()).unwrap();
+    let rollups: i64 = conn
+        .query_row("SELECT COUNT(*) FROM weather_hourly", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(rollups, 0);
+
+    // The next batch is stored as usual
+    let batch = decode_batch(&[(1742068800, 10.0), (1742069400, 10.5)]);
+    weather_database
+        .insert_sensor_batch("garden", &SETTINGS, &batch.payloads)
+        .unwrap();
+    assert_eq!(committed(&database), [1742068800, 1742069400]);
+}
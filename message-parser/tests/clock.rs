@@ -0,0 +1,125 @@
+//! Tests that measurement times from unsynced or skewed station clocks are caught, handled as the
+//! policy says, and counted in the station's clock statistics
+
+use message_parser::clock::{ClockCheck, ClockConfig, ClockPolicy, ClockProblem};
+use message_parser::database::{IngestSettings, WeatherDatabase};
+use message_parser::mqtt_message::SensorMessagePayload;
+use message_parser::reading::Reading;
+use message_parser::timestamp::now;
+
+const RECEIVED: i64 = 1742068800;
+
+fn config(policy: ClockPolicy) -> ClockConfig {
+    ClockConfig {
+        policy,
+        ..ClockConfig::default()
+    }
+}
+
+#[test]
+fn untrustworthy_times_are_caught() {
+    let flag = config(ClockPolicy::Flag);
+    let problem =
+        |measurement_time, buffered| flag.check(measurement_time, RECEIVED, buffered).problem;
+
+    assert_eq!(problem(RECEIVED - 30, false), None);
+    assert_eq!(problem(RECEIVED + 300, false), None);
+    assert_eq!(problem(RECEIVED - 300, false), None);
+    assert_eq!(problem(86400, false), Some(ClockProblem::Unsynced));
+    assert_eq!(problem(1577836799, true), Some(ClockProblem::Unsynced));
+    assert_eq!(problem(RECEIVED + 301, false), Some(ClockProblem::Future));
+    assert_eq!(problem(RECEIVED + 301, true), Some(ClockProblem::Future));
+    assert_eq!(problem(RECEIVED - 301, false), Some(ClockProblem::Behind));
+
+    assert_eq!(problem(RECEIVED - 30, true), None);
+    // A station holds buffered readings for a while, so being behind is expected of them
+    assert_eq!(problem(RECEIVED - 3600, true), None);
+}
+
+#[test]
+fn policy_decides_the_stored_time() {
+    assert_eq!(
+        config(ClockPolicy::Substitute).check(RECEIVED + 3600, RECEIVED, false),
+        ClockCheck {
+            skew: -3600,
+            problem: Some(ClockProblem::Future),
+            measurement_time: RECEIVED,
+        }
+    );
+    assert_eq!(
+        config(ClockPolicy::Flag).check(RECEIVED + 3600, RECEIVED, false),
+        ClockCheck {
+            skew: -3600,
+            problem: Some(ClockProblem::Future),
+            measurement_time: RECEIVED + 3600,
+        }
+    );
+
+    // Trusted times are kept whatever the policy
+    for policy in [ClockPolicy::Substitute, ClockPolicy::Flag] {
+        let check = config(policy).check(RECEIVED - 30, RECEIVED, false);
+        assert_eq!(check.measurement_time, RECEIVED - 30);
+        assert_eq!(check.skew, 30);
+    }
+}
+
+#[test]
+fn clock_statistics_are_recorded() {
+    let clock = config(ClockPolicy::Substitute);
+    let settings = IngestSettings {
+        altitude: None,
+        clock: &clock,
+    };
+    let payload = |posix_time| SensorMessagePayload {
+        posix_time,
+        ..SensorMessagePayload::create_dummy()
+    };
+    let mut database = WeatherDatabase::new(":memory:").unwrap();
+    database.migrate("default").unwrap();
+
+    // The received time is now, so each skew may come out a second larger than asked for
+    let start = now();
+    for offset in [-100, 50, -1000] {
+        database
+            .insert_sensor_data("garden", &settings, &payload(start + offset))
+            .unwrap();
+    }
+    database
+        .insert_sensor_data("garden", &settings, &payload(0))
+        .unwrap();
+    // Buffered readings are neither sampled nor counted as skewed
+    database
+        .insert_sensor_batch("garden", &settings, &[payload(start - 3600)])
+        .unwrap();
+    database
+        .insert_sensor_data("shed", &settings, &payload(start - 10))
+        .unwrap();
+
+    let stats = database.clock_stats().unwrap();
+    assert_eq!(stats.len(), 2);
+    let garden = &stats[0];
+    assert_eq!(garden.station, "garden");
+    assert_eq!(garden.samples, 3);
+    assert!((350.0..=351.0).contains(&garden.mean_skew.unwrap()));
+    assert!((-50..=-49).contains(&garden.min_skew.unwrap()));
+    assert!((1000..=1001).contains(&garden.max_skew.unwrap()));
+    assert!((1000..=1001).contains(&garden.last_skew.unwrap()));
+    assert_eq!(garden.unsynced_count, 1);
+    assert_eq!(garden.skewed_count, 1);
+
+    let shed = &stats[1];
+    assert_eq!((shed.station.as_str(), shed.samples), ("shed", 1));
+    assert_eq!((shed.unsynced_count, shed.skewed_count), (0, 0));
+
+    // The readings with problems were stored at the time they were received
+    let readings = database.readings_in_range("garden", 0, i64::MAX).unwrap();
+    assert_eq!(readings.len(), 5);
+    let problems: Vec<&Reading> = readings
+        .iter()
+        .filter(|reading| reading.clock_status.is_some())
+        .collect();
+    assert_eq!(problems.len(), 2);
+    for reading in problems {
+        assert_eq!(reading.measurement_time, reading.received_time);
+    }
+}
@@ -0,0 +1,125 @@
+//! Tests that writes are committed when the `CommitPolicy` says, and that a failed write is rolled
+//! back without losing the others waiting to be committed
+
+mod common;
+
+use common::{payload, TempDatabase, CLOCK};
+use message_parser::database::{CommitPolicy, IngestSettings, WeatherDatabase};
+use std::thread;
+use std::time::Duration;
+
+const SETTINGS: IngestSettings = IngestSettings {
+    altitude: None,
+    clock: &CLOCK,
+};
+
+fn open(database: &TempDatabase, commit_policy: CommitPolicy) -> WeatherDatabase {
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    weather_database.with_commit_policy(commit_policy)
+}
+
+fn insert(database: &mut WeatherDatabase, posix_time: i64) {
+    database
+        .insert_sensor_data("garden", &SETTINGS, &payload(posix_time, 12.5))
+        .unwrap();
+}
+
+/// The readings committed, as another connection sees them
+fn committed(database: &TempDatabase) -> Vec<i64> {
+    let conn = rusqlite::Connection::open(database.path()).unwrap();
+    let mut stmt = conn
+        .prepare("SELECT MeasurementTime FROM weather_data ORDER BY MeasurementTime")
+        .unwrap();
+    stmt.query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+#[test]
+fn rows_are_committed_once_max_rows_are_waiting() {
+    let database = TempDatabase::new("commit-rows");
+    let mut weather_database = open(
+        &database,
+        CommitPolicy {
+            max_rows: 3,
+            max_delay_ms: 3_600_000,
+        },
+    );
+    insert(&mut weather_database, 1742068800);
+    insert(&mut weather_database, 1742069400);
+    weather_database.commit_if_due().unwrap();
+    assert!(committed(&database).is_empty());
+
+    insert(&mut weather_database, 1742070000);
+    assert_eq!(committed(&database), [1742068800, 1742069400, 1742070000]);
+}
+
+#[test]
+fn rows_are_committed_once_max_delay_has_passed() {
+    let database = TempDatabase::new("commit-delay");
+    let mut weather_database = open(
+        &database,
+        CommitPolicy {
+            max_rows: 1000,
+            max_delay_ms: 200,
+        },
+    );
+    insert(&mut weather_database, 1742068800);
+    weather_database.commit_if_due().unwrap();
+    assert!(committed(&database).is_empty());
+
+    thread::sleep(Duration::from_millis(250));
+    weather_database.commit_if_due().unwrap();
+    assert_eq!(committed(&database), [1742068800]);
+}
+
+#[test]
+fn a_failed_write_only_rolls_back_itself() {
+    let database = TempDatabase::new("commit-rollback");
+    let mut weather_database = open(
+        &database,
+        CommitPolicy {
+            max_rows: 1000,
+            max_delay_ms: 3_600_000,
+        },
+    );
+    // Temperatures are stored in tenths of a degree
+    rusqlite::Connection::open(database.path())
+        .unwrap()
+        .execute_batch(
+            "CREATE TRIGGER too_hot BEFORE INSERT ON weather_data WHEN NEW.TemperatureBME > 1000
+            BEGIN SELECT RAISE(ABORT, 'too hot'); END",
+        )
+        .unwrap();
+
+    insert(&mut weather_database, 1742068800);
+    let batch = [payload(1742069400, 12.5), payload(1742070000, 150.0)];
+    assert!(weather_database
+        .insert_sensor_batch("garden", &SETTINGS, &batch)
+        .is_err());
+    insert(&mut weather_database, 1742070600);
+    weather_database.flush().unwrap();
+
+    assert_eq!(committed(&database), [1742068800, 1742070600]);
+    let conn = rusqlite::Connection::open(database.path()).unwrap();
+    let rolled_up: i64 = conn
+        .query_row("SELECT SUM(ReadingCount) FROM weather_hourly", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(rolled_up, 2);
+}
+
+#[test]
+fn waiting_rows_are_committed_on_drop() {
+    let database = TempDatabase::new("commit-drop");
+    let mut weather_database = open(&database, CommitPolicy::default());
+    insert(&mut weather_database, 1742068800);
+    insert(&mut weather_database, 1742069400);
+    assert!(committed(&database).is_empty());
+
+    drop(weather_database);
+    assert_eq!(committed(&database), [1742068800, 1742069400]);
+}
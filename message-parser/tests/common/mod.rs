@@ -0,0 +1,96 @@
+//! Helpers shared by the integration tests
+
+// Each test file uses some of the helpers
+#![allow(dead_code)]
+
+use message_parser::clock::{ClockConfig, ClockPolicy};
+use message_parser::config::Config;
+use message_parser::mqtt_message::{SensorMessagePayload, VALID_BME280, VALID_SGP30};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Keeps every measurement time as sent, however far it is from now
+pub const CLOCK: ClockConfig = ClockConfig {
+    policy: ClockPolicy::Flag,
+    earliest_time: 1577836800,
+    max_skew: 300,
+};
+
+/// The default config, but keeping every measurement time as sent
+pub fn config() -> Config {
+    Config {
+        clock: CLOCK,
+        ..Config::default()
+    }
+}
+
+/// A reading at `posix_time` with the BME280 reading `temperature` and the DHT22 failed
+pub fn payload(posix_time: i64, temperature: f32) -> SensorMessagePayload {
+    SensorMessagePayload {
+        posix_time,
+        bme_temperature: temperature,
+        bme_pressure: 101300.0,
+        bme_humidity: 50.0,
+        sgp30_eCO2: 450,
+        sgp30_TVOC: 25,
+        dht22_temperature: 0.0,
+        dht22_humidity: 0.0,
+        valid: VALID_BME280 | VALID_SGP30,
+    }
+}
+
+/// A path in the temporary directory, unique to this test run, that is deleted when dropped along
+/// with whatever was created there
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "message-parser-{}-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed),
+            name
+        ));
+        let temp_path = TempPath(path);
+        temp_path.remove();
+        temp_path
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn to_str(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    /// Deletes the file or directory, and a database's write-ahead log and shared memory files
+    fn remove(&self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.0.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// A database file that is deleted when dropped
+pub struct TempDatabase(TempPath);
+
+impl TempDatabase {
+    pub fn new(name: &str) -> Self {
+        TempDatabase(TempPath::new(&format!("{}.db", name)))
+    }
+
+    pub fn path(&self) -> &str {
+        self.0.to_str()
+    }
+}
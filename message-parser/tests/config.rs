@@ -0,0 +1,118 @@
+//! Tests that messages are routed to the station and payload format their topic calls for, and
+//! that HMAC keys too short to be safe are refused
+
+use message_parser::config::Config;
+use message_parser::mqtt_message::PayloadFormat;
+
+fn config(toml: &str) -> Config {
+    toml::from_str(toml).unwrap()
+}
+
+#[test]
+fn stations_are_taken_from_their_topics() {
+    let config = config("default_station = \"garden\"");
+    assert_eq!(
+        config.route("weather/shed/reading"),
+        ("shed", PayloadFormat::Binary)
+    );
+    assert_eq!(
+        config.route("weather/shed/telemetry"),
+        ("shed", PayloadFormat::Binary)
+    );
+    // Firmware that predates per-station topics publishes on the legacy topics
+    assert_eq!(
+        config.route("weather/station"),
+        ("garden", PayloadFormat::Binary)
+    );
+    assert_eq!(
+        config.route("weather/test"),
+        ("garden", PayloadFormat::Binary)
+    );
+    for topic in [
+        "weather//reading",
+        "weather/shed/status",
+        "weather/shed/reading/extra",
+    ] {
+        assert_eq!(config.route(topic).0, "garden", "{}", topic);
+    }
+}
+
+#[test]
+fn topic_entries_match_with_wildcards() {
+    let config = config(
+        r#"
+        [[topics]]
+        filter = "tele/+/SENSOR"
+        format = "json"
+
+        [[topics]]
+        filter = "cbor/#"
+        format = "cbor"
+        "#,
+    );
+    assert_eq!(
+        config.route("tele/garden-node/SENSOR"),
+        ("default", PayloadFormat::Json)
+    );
+    assert_eq!(
+        config.route("cbor/shed/reading"),
+        ("default", PayloadFormat::Cbor)
+    );
+    // `#` also matches its parent level
+    assert_eq!(config.route("cbor"), ("default", PayloadFormat::Cbor));
+    // `+` matches exactly one level
+    assert_eq!(
+        config.route("tele/garden/node/SENSOR"),
+        ("default", PayloadFormat::Binary)
+    );
+    assert_eq!(
+        config.route("tele/garden-node/STATE"),
+        ("default", PayloadFormat::Binary)
+    );
+}
+
+#[test]
+fn topic_entries_can_name_the_station() {
+    let config = config(
+        r#"
+        [[topics]]
+        filter = "weather/shed/reading"
+        station = "greenhouse"
+
+        [[topics]]
+        filter = "weather/+/reading"
+        format = "json"
+        station = "garden"
+        "#,
+    );
+    // The first matching entry decides
+    assert_eq!(
+        config.route("weather/shed/reading"),
+        ("greenhouse", PayloadFormat::Binary)
+    );
+    assert_eq!(
+        config.route("weather/pond/reading"),
+        ("garden", PayloadFormat::Json)
+    );
+    assert_eq!(
+        config.route("weather/pond/telemetry"),
+        ("pond", PayloadFormat::Binary)
+    );
+}
+
+#[test]
+fn short_hmac_keys_are_rejected() {
+    let key = |hex: &str| {
+        toml::from_str::<Config>(&format!("[stations.garden]\nhmac_key = \"{}\"", hex))
+            .map(|config| config.hmac_key("garden").map(<[u8]>::to_vec))
+    };
+    assert_eq!(
+        key("000102030405060708090a0b0c0d0e0f").unwrap(),
+        Some((0..16).collect())
+    );
+    for hex in ["", "00", "000102030405060708090a0b0c0d0e"] {
+        let error = key(hex).err().unwrap().to_string();
+        assert!(error.contains("at least 16 bytes"), "{}", error);
+    }
+    assert!(key("000102030405060708090a0b0c0d0e0g").is_err());
+}
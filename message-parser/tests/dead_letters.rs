@@ -0,0 +1,322 @@
+//! Tests that payloads which couldn't be decoded are kept as dead letters, can be listed and
+//! dumped, and are stored once retrying decodes them
+
+mod common;
+
+use common::{config, TempDatabase};
+use message_parser::clock::{ClockConfig, ClockPolicy};
+use message_parser::config::Config;
+use message_parser::database::WeatherDatabase;
+use message_parser::dead_letter::{self, Command, DeadLetterError, ListOptions, RetryReport};
+use message_parser::mqtt_message::{
+    ParseError, SensorMessage, SensorMessageHeader, SensorMessagePayload, TelemetryMessage,
+    TelemetryPayload, CURRENT_VERSION, FLAG_CRC32,
+};
+use message_parser::store::{MemoryStore, ReadingStore};
+use message_parser::timestamp::parse_iso8601;
+
+const TOPIC: &str = "weather/garden/reading";
+
+fn reading(posix_time: i64) -> Vec<u8> {
+    let header = SensorMessageHeader::reading(CURRENT_VERSION, FLAG_CRC32).unwrap();
+    let payload = SensorMessagePayload {
+        posix_time,
+        ..SensorMessagePayload::create_dummy()
+    };
+    SensorMessage::new(header, payload).unwrap().to_bytes()
+}
+
+fn telemetry(posix_time: i64) -> Vec<u8> {
+    let header = SensorMessageHeader::telemetry(CURRENT_VERSION, FLAG_CRC32).unwrap();
+    let payload = TelemetryPayload {
+        posix_time,
+        battery_millivolts: 3712,
+        wifi_rssi: -67,
+        reset_reason: 5,
+        wake_count: 1042,
+        firmware_version: "1.4.0".to_string(),
+    };
+    TelemetryMessage::new(header, payload).unwrap().to_bytes()
+}
+
+/// Archives `payload` and keeps it as a dead letter, as `on_message` does when decoding fails
+fn reject(database: &mut WeatherDatabase, received_time: i64, payload: &[u8], error: ParseError) {
+    database
+        .archive_message(TOPIC, received_time, payload)
+        .unwrap();
+    database
+        .insert_dead_letter(TOPIC, received_time, payload, &error)
+        .unwrap();
+}
+
+fn run(database: &mut WeatherDatabase, args: &[&str]) -> Result<String, DeadLetterError> {
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    let command = Command::parse(&args)?;
+    let mut out = Vec::new();
+    dead_letter::run(database, &config(), &command, &mut out)?;
+    Ok(String::from_utf8(out).unwrap())
+}
+
+#[test]
+fn retrying_stores_dead_letters_that_decode_now() {
+    let database = TempDatabase::new("dead-letters");
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    let received = parse_iso8601("2025-03-15T20:00:00Z").unwrap();
+
+    // Rejected while the config required HMAC tags, twice for the redelivered reading
+    reject(
+        &mut weather_database,
+        received,
+        &reading(received - 30),
+        ParseError::NoHmacKey,
+    );
+    reject(
+        &mut weather_database,
+        received + 5,
+        &reading(received - 30),
+        ParseError::NoHmacKey,
+    );
+    reject(
+        &mut weather_database,
+        received + 60,
+        &telemetry(received + 55),
+        ParseError::NoHmacKey,
+    );
+    // Damaged in transit, so it will never decode
+    let mut damaged = reading(received + 570);
+    damaged[20] ^= 0xff;
+    reject(
+        &mut weather_database,
+        received + 600,
+        &damaged,
+        ParseError::NoHmacKey,
+    );
+    assert_eq!(
+        weather_database
+            .dead_letters(i64::MIN, i64::MAX)
+            .unwrap()
+            .len(),
+        4
+    );
+
+    let report = dead_letter::retry(&mut weather_database, &config(), &[]).unwrap();
+    assert_eq!(
+        report,
+        RetryReport {
+            stored: 3,
+            readings: 1,
+            duplicates: 1,
+            failed: 1,
+        }
+    );
+    let readings = weather_database
+        .readings_in_range("garden", 0, i64::MAX)
+        .unwrap();
+    assert_eq!(readings.len(), 1);
+    assert_eq!(readings[0].measurement_time, received - 30);
+    assert_eq!(readings[0].received_time, received);
+    let telemetry = weather_database
+        .latest_telemetry("garden")
+        .unwrap()
+        .unwrap();
+    assert_eq!(telemetry.received_time, received + 60);
+
+    // The damaged message is kept with the error it fails with now
+    let remaining = weather_database.dead_letters(i64::MIN, i64::MAX).unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].error_kind, "checksum_mismatch");
+    assert_eq!(remaining[0].payload, damaged);
+
+    // The reading is linked to its archived message, so it can be reprocessed
+    let conn = rusqlite::Connection::open(database.path()).unwrap();
+    let (raw_message, topic): (i64, String) = conn
+        .query_row(
+            "SELECT RawMessageId, Topic FROM weather_data
+            JOIN raw_messages ON raw_messages.Id = RawMessageId",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!((raw_message, topic.as_str()), (1, TOPIC));
+
+    assert!(matches!(
+        dead_letter::retry(&mut weather_database, &config(), &[99]),
+        Err(DeadLetterError::NotFound(99))
+    ));
+}
+
+#[test]
+fn redeliveries_are_stored_once_when_times_are_substituted() {
+    let mut weather_database = WeatherDatabase::new(":memory:").unwrap();
+    weather_database.migrate("default").unwrap();
+    let received = parse_iso8601("2025-03-15T20:00:00Z").unwrap();
+    // Sent before the station's clock synced, so stored at the time it was received
+    for delay in [0, 5] {
+        reject(
+            &mut weather_database,
+            received + delay,
+            &reading(0),
+            ParseError::NoHmacKey,
+        );
+    }
+    // Another message on another topic, with the same payload
+    weather_database
+        .archive_message("weather/shed/reading", received + 10, &reading(0))
+        .unwrap();
+    weather_database
+        .insert_dead_letter(
+            "weather/shed/reading",
+            received + 10,
+            &reading(0),
+            &ParseError::NoHmacKey,
+        )
+        .unwrap();
+
+    let substitute = Config {
+        clock: ClockConfig {
+            policy: ClockPolicy::Substitute,
+            ..ClockConfig::default()
+        },
+        ..Config::default()
+    };
+    let report = dead_letter::retry(&mut weather_database, &substitute, &[]).unwrap();
+    assert_eq!(
+        report,
+        RetryReport {
+            stored: 3,
+            readings: 2,
+            duplicates: 1,
+            failed: 0,
+        }
+    );
+    let readings = weather_database
+        .readings_in_range("garden", 0, i64::MAX)
+        .unwrap();
+    assert_eq!(readings.len(), 1);
+    assert_eq!(readings[0].measurement_time, received);
+    assert_eq!(
+        weather_database
+            .readings_in_range("shed", 0, i64::MAX)
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn redelivered_telemetry_is_stored_once() {
+    let mut weather_database = WeatherDatabase::new(":memory:").unwrap();
+    weather_database.migrate("default").unwrap();
+    let received = parse_iso8601("2025-03-15T20:00:00Z").unwrap();
+    for delay in [0, 5] {
+        reject(
+            &mut weather_database,
+            received + delay,
+            &telemetry(received - 5),
+            ParseError::NoHmacKey,
+        );
+    }
+
+    let report = dead_letter::retry(&mut weather_database, &config(), &[]).unwrap();
+    assert_eq!(
+        report,
+        RetryReport {
+            stored: 2,
+            readings: 0,
+            duplicates: 1,
+            failed: 0,
+        }
+    );
+    let telemetry = weather_database
+        .telemetry_in_range("garden", 0, i64::MAX)
+        .unwrap();
+    assert_eq!(telemetry.len(), 1);
+    assert_eq!(telemetry[0].received_time, received);
+    assert!(weather_database
+        .dead_letters(i64::MIN, i64::MAX)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn dead_letters_are_listed_and_dumped() {
+    let mut weather_database = WeatherDatabase::new(":memory:").unwrap();
+    weather_database.migrate("default").unwrap();
+    let received = parse_iso8601("2025-03-15T20:00:00Z").unwrap();
+    reject(
+        &mut weather_database,
+        received,
+        b"hello, weather station!",
+        ParseError::BadMagic(0x6c6c6568),
+    );
+    reject(
+        &mut weather_database,
+        received + 3600,
+        &reading(received),
+        ParseError::HmacMismatch,
+    );
+
+    let list = run(&mut weather_database, &["list"]).unwrap();
+    let lines: Vec<&str> = list.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with("Id  ReceivedTime"), "{}", list);
+    assert!(lines[2].starts_with("1   2025-03-15T20:00:00Z  weather/garden/reading  bad_magic "));
+    assert!(
+        lines[2].ends_with("  23     Magic number error: 0x6c6c6568"),
+        "{}",
+        list
+    );
+    assert!(lines[3].contains("hmac_mismatch"));
+
+    let list = run(&mut weather_database, &["list", "--kind", "hmac_mismatch"]).unwrap();
+    assert_eq!(list.lines().count(), 3);
+    let list = run(
+        &mut weather_database,
+        &["list", "--until", "2025-03-15T20:30:00Z"],
+    )
+    .unwrap();
+    assert_eq!(list.lines().count(), 3);
+
+    let shown = run(&mut weather_database, &["show", "1"]).unwrap();
+    assert_eq!(
+        shown.lines().skip(2).collect::<Vec<_>>(),
+        [
+            "00000000  68 65 6c 6c 6f 2c 20 77  65 61 74 68 65 72 20 73  |hello, weather s|",
+            "00000010  74 61 74 69 6f 6e 21                              |tation!|",
+        ]
+    );
+    assert!(shown.starts_with(
+        "Dead letter 1, received 2025-03-15T20:00:00Z on 'weather/garden/reading', 23 bytes\n\
+        Rejected as bad_magic: "
+    ));
+
+    assert!(matches!(
+        run(&mut weather_database, &["show", "3"]),
+        Err(DeadLetterError::NotFound(3))
+    ));
+    assert!(matches!(
+        Command::parse(&["show".to_string()]),
+        Err(DeadLetterError::Usage(_))
+    ));
+    assert!(matches!(
+        Command::parse(&["retry".to_string(), "one".to_string()]),
+        Err(DeadLetterError::Usage(_))
+    ));
+    assert_eq!(
+        Command::parse(&["list".to_string()]).unwrap(),
+        Command::List(ListOptions::default())
+    );
+}
+
+#[test]
+fn memory_store_keeps_dead_letters() {
+    let mut store = MemoryStore::new();
+    store
+        .insert_dead_letter(TOPIC, &[1, 2, 3], &ParseError::MissingHmac)
+        .unwrap();
+    assert_eq!(
+        store.dead_letters,
+        [(TOPIC.to_string(), vec![1, 2, 3], ParseError::MissingHmac)]
+    );
+}
@@ -0,0 +1,138 @@
+//! Tests that `JsonDecoder` and `CborDecoder` read messages into the same payloads as the binary
+//! format, and reject what they can't trust
+
+use message_parser::mqtt_message::{
+    CborDecoder, JsonDecoder, MqttMessage, ParseError, PayloadDecoder, SensorMessagePayload,
+    VALID_BME280, VALID_DHT22_HUMIDITY, VALID_DHT22_TEMPERATURE, VALID_SGP30,
+};
+use serde_json::json;
+
+/// Every sensor of a reading, as a station would publish it
+fn fields() -> serde_json::Value {
+    json!({
+        "posix_time": 1742069972,
+        "bme_temperature": 21.5,
+        "bme_pressure": 101325.0,
+        "bme_humidity": 45.0,
+        "sgp30_eco2": 450,
+        "sgp30_tvoc": 25,
+        "dht22_temperature": 21.0,
+        "dht22_humidity": 47.5,
+    })
+}
+
+fn expected() -> SensorMessagePayload {
+    SensorMessagePayload {
+        posix_time: 1742069972,
+        bme_temperature: 21.5,
+        bme_pressure: 101325.0,
+        bme_humidity: 45.0,
+        sgp30_eCO2: 450,
+        sgp30_TVOC: 25,
+        dht22_temperature: 21.0,
+        dht22_humidity: 47.5,
+        valid: VALID_BME280 | VALID_SGP30 | VALID_DHT22_TEMPERATURE | VALID_DHT22_HUMIDITY,
+    }
+}
+
+fn cbor(value: &serde_json::Value) -> Vec<u8> {
+    let mut data = Vec::new();
+    ciborium::ser::into_writer(value, &mut data).unwrap();
+    data
+}
+
+/// Decodes `value` as JSON and as CBOR, checking both give the same result
+fn decode(value: &serde_json::Value) -> Result<MqttMessage, ParseError> {
+    let json = JsonDecoder.decode(value.to_string().as_bytes(), None);
+    let cbor = CborDecoder.decode(&cbor(value), None);
+    match (&json, &cbor) {
+        (Ok(json), Ok(cbor)) => assert_eq!(json, cbor),
+        (Err(json), Err(cbor)) => assert_eq!(json.kind(), cbor.kind()),
+        _ => panic!("JSON gave {:?} but CBOR gave {:?}", json, cbor),
+    }
+    json
+}
+
+fn decode_payload(value: &serde_json::Value) -> SensorMessagePayload {
+    let message = decode(value).unwrap();
+    assert_eq!(message.sequence(), None);
+    match message {
+        MqttMessage::Reading(message) => message.payload,
+        message => panic!("Expected a reading, got {:?}", message),
+    }
+}
+
+#[test]
+fn readings_decode_to_payloads() {
+    assert_eq!(decode_payload(&fields()), expected());
+
+    let mut later = fields();
+    later["posix_time"] = json!(1742070572);
+    match decode(&json!([fields(), later])).unwrap() {
+        MqttMessage::Batch(batch) => {
+            assert_eq!(batch.payloads.len(), 2);
+            assert_eq!(batch.payloads[0], expected());
+            assert_eq!(batch.payloads[1].posix_time, 1742070572);
+        }
+        message => panic!("Expected a batch, got {:?}", message),
+    }
+}
+
+#[test]
+fn missing_sensors_are_marked_invalid() {
+    // The BME280 is missing its humidity, the SGP30 and DHT22 temperature are missing entirely
+    let payload = decode_payload(&json!({
+        "posix_time": 1742069972,
+        "bme_temperature": 21.5,
+        "bme_pressure": 101325.0,
+        "dht22_humidity": 47.5,
+    }));
+    assert_eq!(payload.valid, VALID_DHT22_HUMIDITY);
+    assert_eq!(payload.temperature_bme(), None);
+    assert_eq!(payload.eco2_sgp30(), None);
+    assert_eq!(payload.dht22_humidity, 47.5);
+
+    let payload = decode_payload(&json!({
+        "posix_time": 1742069972,
+        "sgp30_eco2": 450,
+        "sgp30_tvoc": 25,
+        "dht22_temperature": 21.0,
+    }));
+    assert_eq!(payload.valid, VALID_SGP30 | VALID_DHT22_TEMPERATURE);
+
+    let payload = decode_payload(&json!({ "posix_time": 1742069972 }));
+    assert_eq!(payload.valid, 0);
+}
+
+#[test]
+fn unknown_fields_and_missing_times_are_rejected() {
+    let mut misspelled = fields();
+    misspelled["bme_temprature"] = json!(21.5);
+    let error = decode(&misspelled).unwrap_err();
+    assert_eq!(error.kind(), "invalid_encoding");
+
+    let mut untimed = fields();
+    untimed.as_object_mut().unwrap().remove("posix_time");
+    let error = decode(&untimed).unwrap_err();
+    assert_eq!(error.kind(), "invalid_encoding");
+
+    // One bad reading rejects the whole batch
+    assert!(decode(&json!([fields(), untimed])).is_err());
+
+    let mut infinite = fields();
+    infinite["bme_pressure"] = json!(1e39);
+    assert!(decode(&infinite).is_err());
+}
+
+#[test]
+fn stations_with_keys_cannot_send_json_or_cbor() {
+    let key = [0x5a; 32];
+    assert_eq!(
+        JsonDecoder.decode(fields().to_string().as_bytes(), Some(&key)),
+        Err(ParseError::MissingHmac)
+    );
+    assert_eq!(
+        CborDecoder.decode(&cbor(&fields()), Some(&key)),
+        Err(ParseError::MissingHmac)
+    );
+}
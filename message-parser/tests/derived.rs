@@ -0,0 +1,139 @@
+//! Tests the derived quantities against published reference values
+
+use message_parser::derived::{
+    absolute_humidity, dew_point, heat_index, humidex, sea_level_pressure,
+};
+use message_parser::units::{Celsius, Pascal, RelativeHumidity};
+
+fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+    assert!(
+        (actual - expected).abs() <= tolerance,
+        "{} is not within {} of {}",
+        actual,
+        tolerance,
+        expected
+    );
+}
+
+/// The heat index in °F of air at `fahrenheit` and `humidity` %
+fn heat_index_f(fahrenheit: f32, humidity: f32) -> f32 {
+    heat_index(
+        Celsius::from_fahrenheit(fahrenheit),
+        RelativeHumidity(humidity),
+    )
+    .fahrenheit()
+}
+
+#[test]
+fn dew_point_follows_the_magnus_formula() {
+    // Dew point tables, to a tenth of a degree
+    assert_close(
+        dew_point(Celsius(20.0), RelativeHumidity(50.0)).0,
+        9.3,
+        0.05,
+    );
+    assert_close(
+        dew_point(Celsius(25.0), RelativeHumidity(60.0)).0,
+        16.7,
+        0.05,
+    );
+    assert_close(
+        dew_point(Celsius(30.0), RelativeHumidity(80.0)).0,
+        26.2,
+        0.05,
+    );
+    // Saturated air is at its dew point
+    assert_close(
+        dew_point(Celsius(0.0), RelativeHumidity(100.0)).0,
+        0.0,
+        0.001,
+    );
+    assert_close(
+        dew_point(Celsius(-10.0), RelativeHumidity(100.0)).0,
+        -10.0,
+        0.001,
+    );
+}
+
+#[test]
+fn absolute_humidity_matches_saturation_tables() {
+    // Saturated air holds 17.3 g/m³ at 20 °C and 30.4 g/m³ at 30 °C, which the Magnus formula
+    // matches to within 1 %
+    assert_close(
+        absolute_humidity(Celsius(20.0), RelativeHumidity(100.0)).0,
+        17.3,
+        0.1,
+    );
+    assert_close(
+        absolute_humidity(Celsius(30.0), RelativeHumidity(100.0)).0,
+        30.4,
+        0.3,
+    );
+    assert_close(
+        absolute_humidity(Celsius(25.0), RelativeHumidity(50.0)).0,
+        11.5,
+        0.1,
+    );
+}
+
+#[test]
+fn heat_index_matches_the_nws_chart() {
+    // The NWS heat index chart, rounded to whole degrees Fahrenheit
+    for (fahrenheit, humidity, expected) in [
+        (90.0, 70.0, 106.0),
+        (100.0, 40.0, 109.0),
+        (96.0, 20.0, 93.0),
+        (90.0, 40.0, 91.0),
+    ] {
+        assert_close(heat_index_f(fahrenheit, humidity), expected, 0.5);
+    }
+}
+
+#[test]
+fn heat_index_uses_steadmans_formula_when_mild() {
+    // Below 80 °F the simple formula, 0.5·(T + 61 + 1.2·(T − 68) + 0.094·RH), is used alone
+    assert_close(heat_index_f(70.0, 50.0), 69.05, 0.01);
+    assert_close(heat_index_f(80.0, 40.0), 79.58, 0.01);
+}
+
+#[test]
+fn heat_index_adjusts_for_very_dry_and_humid_air() {
+    // Below 13 % humidity between 80 and 112 °F, the NWS subtracts
+    // ((13 − RH) / 4)·√((17 − |T − 95|) / 17) from the regression's 94.75 °F
+    assert_close(heat_index_f(100.0, 10.0), 94.75 - 0.63, 0.02);
+    // Above 85 % humidity between 80 and 87 °F, it adds ((RH − 85) / 10)·((87 − T) / 5) to the
+    // regression's 101.58 °F
+    assert_close(heat_index_f(85.0, 90.0), 101.58 + 0.2, 0.02);
+    // The NWS chart gives 105 °F at 86 °F and 90 %
+    assert_close(heat_index_f(86.0, 90.0), 105.0, 0.5);
+}
+
+#[test]
+fn humidex_matches_environment_canada() {
+    // Environment Canada's humidex table, by air temperature and dew point
+    assert_close(humidex(Celsius(30.0), Celsius(15.0)).0, 34.0, 0.5);
+    assert_close(humidex(Celsius(35.0), Celsius(25.0)).0, 47.0, 0.5);
+    assert_close(humidex(Celsius(25.0), Celsius(20.0)).0, 33.0, 0.5);
+}
+
+#[test]
+fn sea_level_pressure_reverses_the_standard_atmosphere() {
+    // At 1000 m the International Standard Atmosphere has 898.746 hPa and 8.5 °C, which reduce to
+    // its 1013.25 hPa at sea level
+    assert_close(
+        sea_level_pressure(Pascal(89874.6), Celsius(8.5), 1000.0).0,
+        101325.0,
+        5.0,
+    );
+    assert_close(
+        sea_level_pressure(Pascal(95460.8), Celsius(11.75), 500.0).0,
+        101325.0,
+        5.0,
+    );
+    // A station at sea level reads sea-level pressure
+    assert_close(
+        sea_level_pressure(Pascal(100000.0), Celsius(20.0), 0.0).0,
+        100000.0,
+        0.01,
+    );
+}
@@ -0,0 +1,287 @@
+//! Tests that `import` validates rows, stores them like readings from MQTT, and skips duplicates
+
+mod common;
+
+use common::{TempDatabase, TempPath};
+use message_parser::clock::{ClockConfig, ClockPolicy};
+use message_parser::config::Config;
+use message_parser::database::{IngestSettings, WeatherDatabase};
+use message_parser::import::{self, ImportFormat, ImportOptions, ImportReport};
+use message_parser::inspect::{self, Format, InspectOptions};
+use message_parser::mqtt_message::{
+    SensorMessagePayload, VALID_BME280, VALID_DHT22_HUMIDITY, VALID_DHT22_TEMPERATURE, VALID_SGP30,
+};
+use message_parser::reading::Reading;
+
+const EARLIEST_TIME: i64 = 1577836800;
+
+/// Readings from "garden" every ten minutes, with values that aren't round in any unit
+fn payloads(count: i64) -> Vec<SensorMessagePayload> {
+    (0..count)
+        .map(|index| SensorMessagePayload {
+            posix_time: 1742068800 + index * 600,
+            bme_temperature: -5.0 + index as f32 * 0.17,
+            bme_pressure: 99000.0 + index as f32 * 7.3,
+            bme_humidity: 40.0 + index as f32 * 0.13,
+            sgp30_eCO2: 400 + index as u16 * 3,
+            sgp30_TVOC: index as u16,
+            dht22_temperature: -4.5 + index as f32 * 0.07,
+            dht22_humidity: 42.0 + index as f32 * 0.11,
+            // Every fourth reading has failed sensors
+            valid: if index % 4 == 3 {
+                VALID_BME280 | VALID_DHT22_HUMIDITY
+            } else {
+                VALID_BME280 | VALID_SGP30 | VALID_DHT22_TEMPERATURE | VALID_DHT22_HUMIDITY
+            },
+        })
+        .collect()
+}
+
+fn open(database: &TempDatabase) -> WeatherDatabase {
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    weather_database
+}
+
+/// Everything stored from `station`
+fn readings(database: &WeatherDatabase, station: &str) -> Vec<Reading> {
+    database.readings_in_range(station, 0, i64::MAX).unwrap()
+}
+
+/// Imports `contents` as a file in `format`
+fn import(
+    database: &mut WeatherDatabase,
+    contents: &str,
+    format: ImportFormat,
+    station: Option<&str>,
+) -> ImportReport {
+    let file = TempPath::new("import");
+    std::fs::write(file.path(), contents).unwrap();
+    let options = ImportOptions {
+        station: station.map(str::to_string),
+        format: Some(format),
+        paths: vec![file.to_str().to_string()],
+    };
+    let config = Config {
+        default_station: "default".to_string(),
+        ..Config::default()
+    };
+    import::import_file(database, &config, &options, file.to_str()).unwrap()
+}
+
+fn parse(text: &str) -> Vec<Result<import::ImportRow, import::InvalidRow>> {
+    import::parse(text, ImportFormat::Csv, EARLIEST_TIME)
+}
+
+#[test]
+fn exported_readings_import_unchanged() {
+    let source = TempDatabase::new("import-source");
+    let mut source_database = open(&source);
+    let clock = ClockConfig {
+        policy: ClockPolicy::Flag,
+        ..ClockConfig::default()
+    };
+    let settings = IngestSettings {
+        altitude: None,
+        clock: &clock,
+    };
+    source_database
+        .insert_sensor_batch("garden", &settings, &payloads(400))
+        .unwrap();
+    let expected = readings(&source_database, "garden");
+
+    for (format, import_format) in [
+        (Format::Csv, ImportFormat::Csv),
+        (Format::Ndjson, ImportFormat::Ndjson),
+    ] {
+        let options = InspectOptions {
+            table: Some("weather_data".to_string()),
+            limit: None,
+            format,
+            ..InspectOptions::default()
+        };
+        let mut export = Vec::new();
+        inspect::run(
+            &inspect::open(source.path()).unwrap(),
+            &options,
+            &mut export,
+        )
+        .unwrap();
+
+        let target = TempDatabase::new(&format!("import-target-{:?}", format));
+        let mut target_database = open(&target);
+        let report = import(
+            &mut target_database,
+            &String::from_utf8(export).unwrap(),
+            import_format,
+            None,
+        );
+        assert_eq!(
+            report,
+            ImportReport {
+                inserted: 400,
+                duplicates: 0,
+                invalid: 0
+            }
+        );
+
+        // The derived quantities are computed again from the stored readings, so may differ in
+        // their last digit from ones computed from what the station sent
+        let imported = readings(&target_database, "garden");
+        assert_eq!(imported.len(), expected.len());
+        for (imported, expected) in imported.iter().zip(&expected) {
+            let measured = |reading: &Reading| {
+                (
+                    reading.measurement_time,
+                    reading.received_time,
+                    reading.temperature_bme,
+                    reading.temperature_dht22,
+                    reading.pressure_bme,
+                    reading.humidity_bme,
+                    reading.humidity_dht22,
+                    reading.eco2_sgp30,
+                    reading.tvoc_sgp30,
+                )
+            };
+            assert_eq!(measured(imported), measured(expected), "{:?}", format);
+        }
+    }
+}
+
+#[test]
+fn readings_at_a_stored_time_are_skipped() {
+    let database = TempDatabase::new("import-duplicates");
+    let mut weather_database = open(&database);
+    let csv = "MeasurementTime,Station,TemperatureBME,PressureBME,HumidityBME
+2025-03-15T20:00:00Z,garden,10.5,101325,50
+2025-03-15T20:10:00Z,garden,10.6,101325,50
+2025-03-15T20:10:00Z,garden,10.7,101325,50
+2025-03-15T20:10:00Z,shed,10.7,101325,50
+";
+    let report = import(&mut weather_database, csv, ImportFormat::Csv, None);
+    assert_eq!(
+        report,
+        ImportReport {
+            inserted: 3,
+            duplicates: 1,
+            invalid: 0
+        }
+    );
+    let garden = readings(&weather_database, "garden");
+    assert_eq!(garden[1].temperature_bme.unwrap().0, 10.6);
+
+    let report = import(&mut weather_database, csv, ImportFormat::Csv, None);
+    assert_eq!(report.inserted, 0);
+    assert_eq!(report.duplicates, 4);
+
+    // Importing again stores nothing new
+    let summaries = weather_database.station_summaries().unwrap();
+    assert_eq!(summaries[0].reading_count, 2);
+}
+
+#[test]
+fn rows_without_a_station_use_the_option_then_the_default() {
+    let database = TempDatabase::new("import-stations");
+    let mut weather_database = open(&database);
+    let ndjson = r#"{"MeasurementTime": 1742068800, "TemperatureDHT22": 12.5}
+{"MeasurementTime": 1742069400, "TemperatureDHT22": 12.6, "Station": "shed"}
+"#;
+    import(
+        &mut weather_database,
+        ndjson,
+        ImportFormat::Ndjson,
+        Some("porch"),
+    );
+    import(&mut weather_database, ndjson, ImportFormat::Ndjson, None);
+
+    let stations: Vec<(String, i64)> = weather_database
+        .station_summaries()
+        .unwrap()
+        .into_iter()
+        .map(|summary| (summary.station, summary.reading_count))
+        .collect();
+    assert_eq!(
+        stations,
+        [
+            ("default".to_string(), 1),
+            ("porch".to_string(), 1),
+            ("shed".to_string(), 1)
+        ]
+    );
+}
+
+#[test]
+fn missing_readings_are_failed_sensors() {
+    let rows = parse(
+        "MeasurementTime,TemperatureBME,PressureBME,HumidityBME,eCO2SGP30,TVOCSGP30,TemperatureDHT22,HumidityDHT22
+2025-03-15T20:00:00Z,,,,450,25,,55.5
+2025-03-15T20:10:00Z,10,101325,50,,,11,
+",
+    );
+    let first = rows[0].as_ref().unwrap();
+    assert_eq!(first.line, 2);
+    assert_eq!(first.payload.valid, VALID_SGP30 | VALID_DHT22_HUMIDITY);
+    assert_eq!(first.payload.sgp30_eCO2, 450);
+    assert_eq!(first.payload.posix_time, 1742068800);
+    let second = rows[1].as_ref().unwrap();
+    assert_eq!(second.payload.valid, VALID_BME280 | VALID_DHT22_TEMPERATURE);
+    assert_eq!(second.station, None);
+    assert_eq!(second.received_time, None);
+}
+
+#[test]
+fn invalid_rows_are_reported_with_their_line() {
+    let rows = parse(
+        r#"MeasurementTime,Station,TemperatureBME,PressureBME,HumidityBME,TemperatureDHT22
+2025-03-15T20:00:00Z,garden,10,101325,50,
+2025-03-15T20:00:00Z,garden,10,,50,
+
+not a time,garden,,,,10
+,garden,,,,10
+1000,garden,,,,10
+2025-03-15T20:00:00Z,garden,,,,hot
+2025-03-15T20:00:00Z,garden,,,,120
+2025-03-15T20:00:00Z,garden,10,101325,101,
+2025-03-15T20:00:00Z,garden,,,,
+2025-03-15T20:00:00Z,garden
+2025-03-15T20:00:00Z,"garden, by the shed",,,,10
+"#,
+    );
+    let results: Vec<Result<usize, (usize, &str)>> = rows
+        .iter()
+        .map(|row| match row {
+            Ok(row) => Ok(row.line),
+            Err(invalid) => Err((invalid.line, invalid.reason.as_str())),
+        })
+        .collect();
+    assert_eq!(
+        results,
+        [
+            Ok(2),
+            Err((3, "has some BME280 readings but not all of them")),
+            Err((5, "MeasurementTime is not a time: 'not a time'")),
+            Err((6, "MeasurementTime is missing")),
+            Err((
+                7,
+                "MeasurementTime 1000 is before the clock's earliest_time"
+            )),
+            Err((8, "TemperatureDHT22 is not a number: \"hot\"")),
+            Err((9, "TemperatureDHT22 120 is outside -40 to 85")),
+            Err((10, "HumidityBME 101 is outside 0 to 100")),
+            Err((11, "has no readings")),
+            Err((12, "has 2 fields but the header has 6")),
+            Ok(13),
+        ]
+    );
+    assert_eq!(
+        rows[10].as_ref().unwrap().station.as_deref(),
+        Some("garden, by the shed")
+    );
+
+    let rows = import::parse("{\"MeasurementTime\": ", ImportFormat::Ndjson, 0);
+    assert!(rows[0]
+        .as_ref()
+        .unwrap_err()
+        .reason
+        .starts_with("is not a JSON object"));
+}
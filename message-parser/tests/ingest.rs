@@ -0,0 +1,105 @@
+//! Tests that the ingest loop drops redelivered and replayed messages by their sequence numbers,
+//! and keeps those it can't decode as dead letters
+
+use message_parser::clock::{ClockConfig, ClockPolicy};
+use message_parser::config::Config;
+use message_parser::ingest::on_message;
+use message_parser::mqtt_message::{
+    check_sequence, ParseError, ParseErrorCounts, SensorMessage, SensorMessageHeader,
+    SensorMessagePayload, CURRENT_VERSION, FLAG_CRC32,
+};
+use message_parser::store::{MemoryStore, ReadingStore};
+
+const TOPIC: &str = "weather/garden/reading";
+
+/// Keeps every measurement time as sent
+fn config() -> Config {
+    Config {
+        clock: ClockConfig {
+            policy: ClockPolicy::Flag,
+            ..ClockConfig::default()
+        },
+        ..Config::default()
+    }
+}
+
+fn reading(sequence: u32, posix_time: i64) -> Vec<u8> {
+    let header = SensorMessageHeader::reading(CURRENT_VERSION, FLAG_CRC32)
+        .unwrap()
+        .with_sequence(sequence);
+    let payload = SensorMessagePayload {
+        posix_time,
+        ..SensorMessagePayload::create_dummy()
+    };
+    SensorMessage::new(header, payload).unwrap().to_bytes()
+}
+
+#[test]
+fn sequence_numbers_must_increase() {
+    assert_eq!(check_sequence(0, None), Ok(()));
+    assert_eq!(check_sequence(7, Some(6)), Ok(()));
+    assert_eq!(
+        check_sequence(7, Some(7)),
+        Err(ParseError::DuplicateSequence(7))
+    );
+    assert_eq!(
+        check_sequence(5, Some(7)),
+        Err(ParseError::StaleSequence {
+            sequence: 5,
+            last: 7
+        })
+    );
+}
+
+#[test]
+fn duplicate_and_stale_messages_are_dropped() {
+    let config = config();
+    let mut store = MemoryStore::new();
+    let mut error_counts = ParseErrorCounts::default();
+    for (sequence, posix_time) in [
+        (5, 1742068800),
+        (5, 1742068800),
+        (4, 1742068500),
+        (6, 1742069100),
+    ] {
+        on_message(
+            &config,
+            &mut store,
+            TOPIC,
+            &reading(sequence, posix_time),
+            &mut error_counts,
+        );
+    }
+
+    let times: Vec<i64> = store
+        .readings
+        .iter()
+        .map(|reading| reading.measurement_time)
+        .collect();
+    assert_eq!(times, [1742068800, 1742069100]);
+    assert_eq!(store.last_sequence("garden").unwrap(), Some(6));
+    assert_eq!(
+        error_counts.to_string(),
+        "duplicate_sequence=1, stale_sequence=1"
+    );
+    // Every delivery is archived, but only undecodable ones become dead letters
+    assert_eq!(store.raw_messages.len(), 4);
+    assert!(store.dead_letters.is_empty());
+}
+
+#[test]
+fn undecodable_messages_become_dead_letters() {
+    let config = config();
+    let mut store = MemoryStore::new();
+    let mut error_counts = ParseErrorCounts::default();
+    let mut damaged = reading(1, 1742068800);
+    damaged[20] ^= 0xff;
+    on_message(&config, &mut store, TOPIC, &damaged, &mut error_counts);
+
+    assert!(store.readings.is_empty());
+    assert_eq!(store.last_sequence("garden").unwrap(), None);
+    assert_eq!(store.dead_letters.len(), 1);
+    assert_eq!(store.dead_letters[0].1, damaged);
+    assert_eq!(store.dead_letters[0].2.kind(), "checksum_mismatch");
+    assert_eq!(error_counts.to_string(), "checksum_mismatch=1");
+}
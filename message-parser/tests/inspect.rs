@@ -0,0 +1,245 @@
+//! Tests the `inspect` subcommand's options, time parsing and output formats
+
+mod common;
+
+use common::TempDatabase;
+use message_parser::clock::{ClockConfig, ClockPolicy};
+use message_parser::database::{IngestSettings, WeatherDatabase};
+use message_parser::inspect::{self, Format, InspectError, InspectOptions};
+use message_parser::mqtt_message::{SensorMessagePayload, VALID_BME280};
+use message_parser::timestamp::{format_iso8601, parse_duration, parse_iso8601};
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// A database with a reading from "garden" every ten minutes from 2025-03-15T20:00:00Z, the
+/// BME280's temperature rising by a degree each time
+fn database(name: &str, count: i64) -> TempDatabase {
+    let database = TempDatabase::new(name);
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    let clock = ClockConfig {
+        policy: ClockPolicy::Flag,
+        ..ClockConfig::default()
+    };
+    let settings = IngestSettings {
+        altitude: None,
+        clock: &clock,
+    };
+    for index in 0..count {
+        let payload = SensorMessagePayload {
+            posix_time: 1742068800 + index * 600,
+            bme_temperature: 20.0 + index as f32,
+            bme_pressure: 101300.0,
+            bme_humidity: 45.5,
+            valid: VALID_BME280,
+            ..SensorMessagePayload::create_dummy()
+        };
+        weather_database
+            .insert_sensor_batch("garden", &settings, &[payload])
+            .unwrap();
+    }
+    database
+}
+
+/// What `inspect` prints for `args`
+fn inspect(database: &TempDatabase, arguments: &[&str]) -> Result<String, InspectError> {
+    let options = InspectOptions::parse(&args(arguments))?;
+    let conn = inspect::open(database.path())?;
+    let mut out = Vec::new();
+    inspect::run(&conn, &options, &mut out)?;
+    Ok(String::from_utf8(out).unwrap())
+}
+
+#[test]
+fn iso8601_times_round_trip() {
+    assert_eq!(format_iso8601(0), "1970-01-01T00:00:00Z");
+    assert_eq!(format_iso8601(1742069972), "2025-03-15T20:19:32Z");
+    assert_eq!(format_iso8601(951782400), "2000-02-29T00:00:00Z");
+    assert_eq!(format_iso8601(-1), "1969-12-31T23:59:59Z");
+    for time in [0, 1742069972, 951782400, -86401, 4102444800] {
+        assert_eq!(parse_iso8601(&format_iso8601(time)), Some(time));
+    }
+}
+
+#[test]
+fn iso8601_forms_are_accepted() {
+    assert_eq!(parse_iso8601("2025-03-15"), Some(1741996800));
+    assert_eq!(parse_iso8601("2025-03-15T20:19"), Some(1742069940));
+    assert_eq!(parse_iso8601("2025-03-15 20:19:32"), Some(1742069972));
+    assert_eq!(parse_iso8601("2025-03-15T21:19:32+01:00"), Some(1742069972));
+    assert_eq!(parse_iso8601("2025-03-15T15:19:32-0500"), Some(1742069972));
+    assert_eq!(parse_iso8601("1742069972"), Some(1742069972));
+
+    for invalid in [
+        "",
+        "2025-02-29",
+        "2025-13-01",
+        "2025-03-15T24:00",
+        "2025-03-15T20",
+        "2025-03-15T20:19:32+1",
+        "15/03/2025",
+        "yesterday",
+    ] {
+        assert_eq!(parse_iso8601(invalid), None, "{}", invalid);
+    }
+}
+
+#[test]
+fn durations_are_parsed() {
+    assert_eq!(parse_duration("90s"), Some(90));
+    assert_eq!(parse_duration("30m"), Some(1800));
+    assert_eq!(parse_duration("24h"), Some(86400));
+    assert_eq!(parse_duration("7d"), Some(604800));
+    assert_eq!(parse_duration("2w"), Some(1209600));
+    assert_eq!(parse_duration("24"), None);
+    assert_eq!(parse_duration("h"), None);
+    assert_eq!(parse_duration("24y"), None);
+}
+
+#[test]
+fn options_are_parsed() {
+    let options = InspectOptions::parse(&args(&[
+        "weather_data",
+        "--limit",
+        "0",
+        "--since",
+        "2025-03-15",
+        "--until",
+        "2025-03-16",
+        "--station",
+        "garden",
+        "--format",
+        "ndjson",
+        "--raw",
+    ]))
+    .unwrap();
+    assert_eq!(options.table.as_deref(), Some("weather_data"));
+    assert_eq!(options.limit, None);
+    assert_eq!(options.since, Some(1741996800));
+    assert_eq!(options.until, Some(1742083200));
+    assert_eq!(options.station.as_deref(), Some("garden"));
+    assert_eq!(options.format, Format::Ndjson);
+    assert!(options.raw);
+
+    assert_eq!(
+        InspectOptions::parse(&[]).unwrap(),
+        InspectOptions::default()
+    );
+    for invalid in [
+        &["--limit"][..],
+        &["--limit", "ten"],
+        &["--format", "xml"],
+        &["--since", "soon"],
+        &["--last", "a while"],
+        &["--verbose"],
+        &["weather_data", "device_telemetry"],
+    ] {
+        assert!(matches!(
+            InspectOptions::parse(&args(invalid)),
+            Err(InspectError::Usage(_))
+        ));
+    }
+}
+
+#[test]
+fn tables_are_listed_with_their_schema() {
+    let database = database("inspect-tables", 3);
+    let output = inspect(&database, &[]).unwrap();
+    assert!(output.starts_with("Schema version "));
+    assert!(output.contains("\nweather_data (3 rows)\n"));
+    assert!(output.contains("CREATE TABLE weather_data"));
+    assert!(output.contains("CREATE INDEX weather_data_station_time"));
+    assert!(output.contains("\nweather_hourly (1 rows)\n"));
+}
+
+#[test]
+fn unknown_tables_are_rejected() {
+    let database = database("inspect-unknown", 1);
+    match inspect(&database, &["weather_data; DROP TABLE weather_data"]) {
+        Err(InspectError::UnknownTable { tables, .. }) => {
+            assert!(tables.contains(&"weather_data".to_string()))
+        }
+        result => panic!("expected an unknown table, got {:?}", result),
+    }
+}
+
+#[test]
+fn latest_rows_are_printed_oldest_first() {
+    let database = database("inspect-latest", 5);
+    let output = inspect(
+        &database,
+        &["weather_data", "--limit", "2", "--format", "csv"],
+    )
+    .unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("MeasurementTime,ReceivedTime,TemperatureBME,"));
+    assert!(lines[1].starts_with("2025-03-15T20:30:00Z,"));
+    assert!(lines[2].starts_with("2025-03-15T20:40:00Z,"));
+}
+
+#[test]
+fn rows_are_filtered_by_time_and_station() {
+    let database = database("inspect-filter", 6);
+    let output = inspect(
+        &database,
+        &[
+            "weather_data",
+            "--since",
+            "2025-03-15T20:10:00Z",
+            "--until",
+            "2025-03-15T20:30:00Z",
+            "--format",
+            "ndjson",
+        ],
+    )
+    .unwrap();
+    let rows: Vec<serde_json::Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0]["MeasurementTime"], "2025-03-15T20:10:00Z");
+    assert_eq!(rows[1]["MeasurementTime"], "2025-03-15T20:20:00Z");
+
+    let output = inspect(&database, &["weather_data", "--station", "shed"]).unwrap();
+    assert_eq!(output.lines().count(), 2, "only the header and rule");
+    assert!(matches!(
+        inspect(&database, &["station_sequence", "--since", "2025-03-15"]),
+        Err(InspectError::Usage(_))
+    ));
+}
+
+#[test]
+fn json_has_human_units_unless_raw() {
+    let database = database("inspect-json", 1);
+    let output = inspect(&database, &["weather_data", "--format", "json"]).unwrap();
+    let rows: serde_json::Value = serde_json::from_str(&output).unwrap();
+    let row = &rows[0];
+    assert_eq!(row["MeasurementTime"], "2025-03-15T20:00:00Z");
+    assert_eq!(row["TemperatureBME"], 20.0);
+    assert_eq!(row["PressureBME"], 101300);
+    assert_eq!(row["HumidityBME"], 45.5);
+    assert_eq!(row["TemperatureDHT22"], serde_json::Value::Null);
+    assert_eq!(row["Station"], "garden");
+
+    let output = inspect(&database, &["weather_data", "--format", "json", "--raw"]).unwrap();
+    let rows: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(rows[0]["MeasurementTime"], 1742068800);
+    assert_eq!(rows[0]["TemperatureBME"], 200);
+    assert_eq!(rows[0]["HumidityBME"], 4550);
+}
+
+#[test]
+fn table_headers_have_units() {
+    let database = database("inspect-table", 2);
+    let output = inspect(&database, &["weather_hourly"]).unwrap();
+    let header = output.lines().next().unwrap();
+    assert!(header.starts_with("Station  PeriodStart "));
+    assert!(header.contains("TemperatureBMEMean (°C)"));
+    assert!(header.contains("HumidityBMEMin (%)"));
+    assert!(header.contains("TemperatureBMECount "));
+    assert!(!header.contains("TemperatureBMECount ("));
+}
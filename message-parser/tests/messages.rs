@@ -0,0 +1,327 @@
+//! Tests that hand-written binary messages decode to the readings and telemetry they hold, and that
+//! each way a message can be malformed is rejected with its own error
+
+mod common;
+
+use common::CLOCK;
+use message_parser::database::{IngestSettings, WeatherDatabase};
+use message_parser::mqtt_message::{
+    MqttMessage, ParseError, SensorMessage, SensorMessageHeader, SensorMessagePayload,
+    TelemetryMessage, TelemetryPayload, CURRENT_VERSION, VALID_BME280, VALID_DHT22_HUMIDITY,
+    VALID_DHT22_TEMPERATURE, VALID_SGP30,
+};
+use message_parser::units::FixedPoint;
+
+/// Measurement time of the readings below, 2025-03-15T20:19:32Z
+const POSIX_TIME: i64 = 1742069972;
+
+/// "WEAT" as a little-endian `u32`
+const MAGIC: [u8; 4] = *b"WEAT";
+
+/// The header of a version 3 reading with `flags` and sequence number 7
+fn header(flags: u8) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    data.extend([3, flags]);
+    data.extend(7u32.to_le_bytes());
+    data
+}
+
+/// A payload in the layout of versions 0 and 1, without the `valid` byte, of BME280 temperature,
+/// pressure and humidity, SGP30 eCO2 and TVOC, and DHT22 temperature and humidity
+fn legacy_payload(bme280: [f32; 3], sgp30: [u16; 2], dht22: [f32; 2]) -> Vec<u8> {
+    let mut data = POSIX_TIME.to_le_bytes().to_vec();
+    for float in bme280 {
+        data.extend(float.to_le_bytes());
+    }
+    for value in sgp30 {
+        data.extend(value.to_le_bytes());
+    }
+    for float in dht22 {
+        data.extend(float.to_le_bytes());
+    }
+    data
+}
+
+/// A version 2 or later payload, with `valid` bits and `temperature` from the BME280
+fn payload(temperature: f32, valid: u8) -> Vec<u8> {
+    let mut data = legacy_payload([temperature, 101325.0, 45.0], [450, 25], [21.0, 47.5]);
+    data.push(valid);
+    data
+}
+
+/// A version 1 reading of `legacy_payload`
+fn v1_reading(payload: Vec<u8>) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    data.extend([1, 0]);
+    data.extend(payload);
+    data
+}
+
+fn decode_payload(data: &[u8]) -> SensorMessagePayload {
+    match MqttMessage::from_bytes(data).unwrap() {
+        MqttMessage::Reading(message) => message.payload,
+        message => panic!("Expected a reading, got {:?}", message),
+    }
+}
+
+/// A version 3 reading without trailers
+fn reading(temperature: f32, valid: u8) -> Vec<u8> {
+    let mut data = header(0);
+    data.extend(payload(temperature, valid));
+    data
+}
+
+fn error_kind(data: &[u8]) -> &'static str {
+    MqttMessage::from_bytes(data).unwrap_err().kind()
+}
+
+#[test]
+fn short_messages_are_the_wrong_length() {
+    let data = reading(21.5, 0x0f);
+    let error = SensorMessage::from_bytes(&data[..data.len() - 1]).unwrap_err();
+    assert_eq!(error.kind(), "wrong_length");
+    assert_eq!(
+        error,
+        ParseError::WrongLength {
+            expected: data.len(),
+            actual: data.len() - 1
+        }
+    );
+    assert_eq!(error_kind(&data[..3]), "wrong_length");
+}
+
+#[test]
+fn unknown_magic_numbers_are_bad_magic() {
+    let mut data = reading(21.5, 0x0f);
+    data[..4].copy_from_slice(b"WXYZ");
+    assert_eq!(error_kind(&data), "bad_magic");
+    assert_eq!(
+        MqttMessage::from_bytes(&data),
+        Err(ParseError::BadMagic(u32::from_le_bytes(*b"WXYZ")))
+    );
+}
+
+#[test]
+fn big_endian_magic_numbers_are_byte_swapped() {
+    let mut data = reading(21.5, 0x0f);
+    data[..4].copy_from_slice(b"TAEW");
+    assert_eq!(error_kind(&data), "byte_swapped_magic");
+}
+
+#[test]
+fn extra_bytes_are_trailing() {
+    let mut data = reading(21.5, 0x0f);
+    data.extend([0, 0, 0]);
+    assert_eq!(error_kind(&data), "trailing_bytes");
+    assert_eq!(
+        MqttMessage::from_bytes(&data),
+        Err(ParseError::TrailingBytes(3))
+    );
+}
+
+#[test]
+fn non_finite_valid_readings_are_rejected() {
+    for temperature in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+        let data = reading(temperature, 0x0f);
+        assert_eq!(error_kind(&data), "non_finite_float");
+        assert_eq!(
+            MqttMessage::from_bytes(&data),
+            Err(ParseError::NonFiniteFloat("bme_temperature"))
+        );
+    }
+
+    // Readings marked invalid can hold anything
+    let data = reading(f32::NAN, 0x0e);
+    assert!(MqttMessage::from_bytes(&data).is_ok());
+}
+
+#[test]
+fn readings_with_cleared_validity_bits_are_stored_as_null() {
+    let mut data = MAGIC.to_vec();
+    data.extend([2, 0]);
+    data.extend(payload(21.5, VALID_SGP30 | VALID_DHT22_HUMIDITY));
+    let payload = decode_payload(&data);
+    assert_eq!(payload.valid, VALID_SGP30 | VALID_DHT22_HUMIDITY);
+
+    let mut database = WeatherDatabase::new(":memory:").unwrap();
+    database.migrate("default").unwrap();
+    let settings = IngestSettings {
+        altitude: Some(50.0),
+        clock: &CLOCK,
+    };
+    database
+        .insert_sensor_data("garden", &settings, &payload)
+        .unwrap();
+    let reading = &database.readings_in_range("garden", 0, i64::MAX).unwrap()[0];
+    assert_eq!(reading.temperature_bme, None);
+    assert_eq!(reading.pressure_bme, None);
+    assert_eq!(reading.humidity_bme, None);
+    assert_eq!(reading.temperature_dht22, None);
+    assert_eq!(reading.humidity_dht22.map(FixedPoint::value), Some(47.5));
+    assert_eq!(reading.eco2_sgp30.map(FixedPoint::value), Some(450.0));
+    assert_eq!(reading.tvoc_sgp30.map(FixedPoint::value), Some(25.0));
+    // Without a trusted temperature to go with the humidity, nothing is derived
+    assert_eq!(reading.derived.dew_point, None);
+    assert_eq!(reading.derived.sea_level_pressure, None);
+}
+
+#[test]
+fn validity_of_legacy_readings_is_inferred_from_failure_values() {
+    let valid = |bme280, sgp30, dht22| {
+        decode_payload(&v1_reading(legacy_payload(bme280, sgp30, dht22))).valid
+    };
+    let bme280 = [21.5, 101325.0, 45.0];
+
+    assert_eq!(
+        valid(bme280, [450, 25], [21.0, 47.5]),
+        VALID_BME280 | VALID_SGP30 | VALID_DHT22_TEMPERATURE | VALID_DHT22_HUMIDITY
+    );
+    // A failed BME280 read gives NaN, in any of its fields
+    assert_eq!(
+        valid([21.5, f32::NAN, 45.0], [450, 25], [21.0, 47.5]) & VALID_BME280,
+        0
+    );
+    // A failed SGP30 reads 0 for both, but a TVOC of 0 alone is clean air
+    assert_eq!(valid(bme280, [0, 0], [21.0, 47.5]) & VALID_SGP30, 0);
+    assert_ne!(valid(bme280, [400, 0], [21.0, 47.5]) & VALID_SGP30, 0);
+    // A failed DHT22 leaves both fields at 0
+    assert_eq!(
+        valid(bme280, [450, 25], [0.0, 0.0]),
+        VALID_BME280 | VALID_SGP30
+    );
+    // 0 % humidity is a failed read, but 0 °C is a cold morning
+    assert_eq!(
+        valid(bme280, [450, 25], [5.0, 0.0]),
+        VALID_BME280 | VALID_SGP30 | VALID_DHT22_TEMPERATURE
+    );
+    assert_eq!(
+        valid(bme280, [450, 25], [0.0, 80.0]),
+        VALID_BME280 | VALID_SGP30 | VALID_DHT22_TEMPERATURE | VALID_DHT22_HUMIDITY
+    );
+    assert_eq!(
+        valid(bme280, [450, 25], [f32::NAN, 47.5]),
+        VALID_BME280 | VALID_SGP30 | VALID_DHT22_HUMIDITY
+    );
+}
+
+#[test]
+fn version_0_messages_decode() {
+    #[rustfmt::skip]
+    let data = [
+        0x78, 0x56, 0x34, 0x12, // Magic number 0x12345678
+        0xd4, 0xe0, 0xd5, 0x67, 0x00, 0x00, 0x00, 0x00, // posix_time 1742069972
+        0x00, 0x00, 0xac, 0x41, // BME280 temperature 21.5
+        0x80, 0xe6, 0xc5, 0x47, // BME280 pressure 101325.0
+        0x00, 0x00, 0x34, 0x42, // BME280 humidity 45.0
+        0xc2, 0x01, // eCO2 450
+        0x19, 0x00, // TVOC 25
+        0x00, 0x00, 0xa8, 0x41, // DHT22 temperature 21.0
+        0x00, 0x00, 0x3e, 0x42, // DHT22 humidity 47.5
+    ];
+    let message = MqttMessage::from_bytes(&data).unwrap();
+    assert_eq!(message.sequence(), None);
+    assert_eq!(
+        decode_payload(&data),
+        SensorMessagePayload {
+            posix_time: POSIX_TIME,
+            bme_temperature: 21.5,
+            bme_pressure: 101325.0,
+            bme_humidity: 45.0,
+            sgp30_eCO2: 450,
+            sgp30_TVOC: 25,
+            dht22_temperature: 21.0,
+            dht22_humidity: 47.5,
+            valid: VALID_BME280 | VALID_SGP30 | VALID_DHT22_TEMPERATURE | VALID_DHT22_HUMIDITY,
+        }
+    );
+}
+
+#[test]
+fn version_1_messages_decode() {
+    #[rustfmt::skip]
+    let data = [
+        0x57, 0x45, 0x41, 0x54, // Magic number "WEAT"
+        0x01, // Version 1
+        0x00, // Flags
+        0x2c, 0xe3, 0xd5, 0x67, 0x00, 0x00, 0x00, 0x00, // posix_time 1742070572
+        0x00, 0x00, 0x50, 0xc0, // BME280 temperature -3.25
+        0x00, 0xe4, 0xc0, 0x47, // BME280 pressure 98760.0
+        0x00, 0x00, 0xb1, 0x42, // BME280 humidity 88.5
+        0x00, 0x00, // eCO2 0
+        0x00, 0x00, // TVOC 0
+        0x00, 0x00, 0x00, 0x00, // DHT22 temperature 0.0
+        0x00, 0x00, 0x00, 0x00, // DHT22 humidity 0.0
+    ];
+    let message = MqttMessage::from_bytes(&data).unwrap();
+    assert_eq!(message.sequence(), None);
+    // The SGP30 and DHT22 had failed, and wrote zeros
+    assert_eq!(
+        decode_payload(&data),
+        SensorMessagePayload {
+            posix_time: 1742070572,
+            bme_temperature: -3.25,
+            bme_pressure: 98760.0,
+            bme_humidity: 88.5,
+            sgp30_eCO2: 0,
+            sgp30_TVOC: 0,
+            dht22_temperature: 0.0,
+            dht22_humidity: 0.0,
+            valid: VALID_BME280,
+        }
+    );
+}
+
+/// Telemetry from a station running `firmware_version`
+fn telemetry(firmware_version: &str) -> TelemetryMessage {
+    let header = SensorMessageHeader::telemetry(CURRENT_VERSION, 0)
+        .unwrap()
+        .with_sequence(7);
+    let payload = TelemetryPayload {
+        posix_time: POSIX_TIME,
+        battery_millivolts: 3712,
+        wifi_rssi: -67,
+        reset_reason: 5,
+        wake_count: 1042,
+        firmware_version: String::new(),
+    };
+    let mut message = TelemetryMessage::new(header, payload).unwrap();
+    message.payload.firmware_version = firmware_version.to_string();
+    message
+}
+
+#[test]
+fn long_firmware_versions_are_cut_short() {
+    let decode = |message: TelemetryMessage| {
+        TelemetryMessage::from_bytes(&message.to_bytes())
+            .unwrap()
+            .payload
+            .firmware_version
+    };
+    assert_eq!(decode(telemetry("1.4.0")), "1.4.0");
+    assert_eq!(decode(telemetry("1.4.0-rc.1+abcdef")), "1.4.0-rc.1+abcde");
+    // "é" takes two bytes, so the one that would straddle the limit is left out
+    assert_eq!(decode(telemetry("1.4.0-pré-éééééé")), "1.4.0-pré-éé");
+}
+
+#[test]
+fn version_0_telemetry_is_unsupported() {
+    assert_eq!(
+        SensorMessageHeader::telemetry(0, 0),
+        Err(ParseError::UnsupportedVersion(0))
+    );
+    let mut data = b"WTEL".to_vec();
+    data.extend([0, 0]);
+    data.extend(POSIX_TIME.to_le_bytes());
+    data.extend(3712u16.to_le_bytes());
+    data.extend([(-67i8) as u8, 5]);
+    data.extend(1042u32.to_le_bytes());
+    data.extend(*b"1.4.0\0\0\0\0\0\0\0\0\0\0\0");
+    assert_eq!(
+        MqttMessage::from_bytes(&data),
+        Err(ParseError::UnsupportedVersion(0))
+    );
+    // The same payload with a version 1 header is accepted
+    data[4] = 1;
+    let message = TelemetryMessage::from_bytes(&data).unwrap();
+    assert_eq!(message.payload.firmware_version, "1.4.0");
+}
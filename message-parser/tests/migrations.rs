@@ -0,0 +1,165 @@
+//! Tests that the schema migrations upgrade databases from every earlier version
+
+mod common;
+
+use common::TempDatabase;
+use message_parser::database::WeatherDatabase;
+use message_parser::migrations::SCHEMA_VERSION;
+use rusqlite::Connection;
+
+/// `weather_data` as the first release of the parser created it, before schema versions
+const ORIGINAL_SCHEMA: &str = "CREATE TABLE weather_data (
+MeasurementTime INTEGER,
+ReceivedTime INTEGER,
+TemperatureBME INTEGER,
+TemperatureDHT22 INTEGER,
+PressureBME INTEGER,
+HumidityBME INTEGER,
+HumidityDHT22 INTEGER,
+eCO2SGP30 INTEGER,
+TVOCSGP30 INTEGER
+)";
+
+/// A copy of the original schema holding two readings
+fn original_database(name: &str) -> TempDatabase {
+    let database = TempDatabase::new(name);
+    let conn = Connection::open(database.path()).unwrap();
+    conn.execute(ORIGINAL_SCHEMA, []).unwrap();
+    conn.execute(
+        "INSERT INTO weather_data VALUES (1742069972, 1742069973, 215, 210, 101325, 4500, 4750, 450, 25),
+        (1742070572, 1742070573, 216, NULL, 101300, 4510, NULL, 451, 26)",
+        [],
+    )
+    .unwrap();
+    database
+}
+
+/// Every table and index definition, and every column, in a stable order
+fn schema(path: &str) -> Vec<String> {
+    let conn = Connection::open(path).unwrap();
+    let mut schema: Vec<String> = conn
+        .prepare("SELECT type, name, sql FROM sqlite_master ORDER BY type, name")
+        .unwrap()
+        .query_map([], |row| {
+            Ok(format!(
+                "{} {}: {}",
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?.unwrap_or_default()
+            ))
+        })
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    let columns = conn
+        .prepare(
+            "SELECT m.name, p.name, p.type FROM sqlite_master m, pragma_table_info(m.name) p
+            WHERE m.type = 'table' ORDER BY m.name, p.cid",
+        )
+        .unwrap()
+        .query_map([], |row| {
+            Ok(format!(
+                "column {}.{} {}",
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?
+            ))
+        })
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    schema.extend(columns);
+    schema
+}
+
+/// The schema a new database gets
+fn fresh_schema() -> Vec<String> {
+    let database = TempDatabase::new("fresh");
+    WeatherDatabase::new(database.path())
+        .unwrap()
+        .migrate("default")
+        .unwrap();
+    schema(database.path())
+}
+
+#[test]
+fn every_migration_applies_to_the_original_schema() {
+    let fresh = fresh_schema();
+    for version in 0..=SCHEMA_VERSION {
+        let database = original_database(&format!("original-{}", version));
+        let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+
+        let applied = weather_database.migrate_to(version, "default").unwrap();
+        assert_eq!(applied.len(), version as usize);
+        assert_eq!(weather_database.schema_version().unwrap(), version);
+
+        weather_database.migrate("default").unwrap();
+        assert_eq!(weather_database.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(weather_database.station_summaries().unwrap().len(), 1);
+        drop(weather_database);
+
+        // The copy's CREATE statement is laid out differently from the migration's, so the columns
+        // and indexes are compared rather than the statements
+        let mut migrated: Vec<String> = schema(database.path())
+            .into_iter()
+            .filter(|line| line.starts_with("column") || line.starts_with("index"))
+            .collect();
+        let mut expected: Vec<String> = fresh
+            .iter()
+            .filter(|line| line.starts_with("column") || line.starts_with("index"))
+            .cloned()
+            .collect();
+        migrated.sort();
+        expected.sort();
+        assert_eq!(migrated, expected, "migrating from version {}", version);
+    }
+}
+
+#[test]
+fn existing_readings_are_kept_and_assigned_the_default_station() {
+    let database = original_database("readings");
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("garden").unwrap();
+
+    let summaries = weather_database.station_summaries().unwrap();
+    assert_eq!(summaries.len(), 1);
+    assert_eq!(summaries[0].station, "garden");
+    assert_eq!(summaries[0].reading_count, 2);
+    assert_eq!(summaries[0].last_measurement_time, Some(1742070572));
+}
+
+#[test]
+fn migrating_twice_does_nothing() {
+    let database = original_database("twice");
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    assert_eq!(
+        weather_database.migrate("default").unwrap().len(),
+        SCHEMA_VERSION as usize
+    );
+    assert!(weather_database.migrate("default").unwrap().is_empty());
+}
+
+#[test]
+fn partly_upgraded_unversioned_databases_migrate() {
+    // Before schema versions, `create_tables` added the Station column and station_sequence
+    let database = original_database("unversioned");
+    let conn = Connection::open(database.path()).unwrap();
+    conn.execute("ALTER TABLE weather_data ADD COLUMN Station TEXT", [])
+        .unwrap();
+    conn.execute("UPDATE weather_data SET Station = 'shed'", [])
+        .unwrap();
+    conn.execute(
+        "CREATE TABLE station_sequence (Station TEXT PRIMARY KEY, LastSequence INTEGER NOT NULL)",
+        [],
+    )
+    .unwrap();
+    drop(conn);
+
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    assert_eq!(weather_database.schema_version().unwrap(), SCHEMA_VERSION);
+    assert_eq!(
+        weather_database.station_summaries().unwrap()[0].station,
+        "shed"
+    );
+}
@@ -0,0 +1,164 @@
+//! Tests that the typed queries read back what the parser stored
+
+mod common;
+
+use common::{payload, CLOCK};
+use message_parser::clock::ClockProblem;
+use message_parser::database::{IngestSettings, WeatherDatabase};
+use message_parser::derived::DerivedQuantities;
+use message_parser::mqtt_message::SensorMessagePayload;
+use message_parser::units::{Celsius, FixedPoint, Pascal, Ppb, Ppm, RelativeHumidity};
+
+const ALTITUDE: f32 = 50.0;
+
+/// An in-memory database holding `readings` from each station, stored as a batch
+fn database(readings: &[(&str, SensorMessagePayload)]) -> WeatherDatabase {
+    let mut database = WeatherDatabase::new(":memory:").unwrap();
+    database.migrate("default").unwrap();
+    let settings = IngestSettings {
+        altitude: Some(ALTITUDE),
+        clock: &CLOCK,
+    };
+    for (station, payload) in readings {
+        database
+            .insert_sensor_batch(station, &settings, std::slice::from_ref(payload))
+            .unwrap();
+    }
+    database
+}
+
+#[test]
+fn readings_come_back_in_their_units() {
+    let payload = payload(1742069972, 21.5);
+    let database = database(&[("garden", payload.clone())]);
+
+    let reading = database.latest_reading("garden").unwrap().unwrap();
+    assert_eq!(reading.station, "garden");
+    assert_eq!(reading.measurement_time, 1742069972);
+    assert_eq!(reading.temperature_bme, Some(Celsius(21.5)));
+    assert_eq!(reading.pressure_bme, Some(Pascal(101300.0)));
+    assert_eq!(reading.humidity_bme, Some(RelativeHumidity(50.0)));
+    assert_eq!(reading.eco2_sgp30, Some(Ppm(450.0)));
+    assert_eq!(reading.tvoc_sgp30, Some(Ppb(25.0)));
+    assert_eq!(reading.temperature_dht22, None);
+    assert_eq!(reading.humidity_dht22, None);
+    assert_eq!(reading.clock_status, None);
+
+    // Derived quantities are truncated to the database's scale
+    let derived = DerivedQuantities::from_payload(&payload, Some(ALTITUDE));
+    let dew_point = derived.dew_point.unwrap();
+    assert_eq!(
+        reading.derived.dew_point,
+        Some(Celsius::from_fixed(dew_point.to_fixed()))
+    );
+    assert!(reading.derived.sea_level_pressure.is_some());
+}
+
+#[test]
+fn latest_reading_is_by_measurement_time() {
+    let database = database(&[
+        ("garden", payload(1742070000, 20.0)),
+        ("garden", payload(1742070600, 21.0)),
+        // Arrives last, but was measured first
+        ("garden", payload(1742069400, 19.0)),
+        ("shed", payload(1742080000, 15.0)),
+    ]);
+
+    let latest = database.latest_reading("garden").unwrap().unwrap();
+    assert_eq!(latest.measurement_time, 1742070600);
+    assert_eq!(latest.temperature_bme, Some(Celsius(21.0)));
+    assert_eq!(database.latest_reading("attic").unwrap(), None);
+}
+
+#[test]
+fn latest_readings_has_one_per_station() {
+    let database = database(&[
+        ("shed", payload(1742080000, 15.0)),
+        ("garden", payload(1742070600, 21.0)),
+        ("garden", payload(1742070000, 20.0)),
+        ("shed", payload(1742079400, 14.0)),
+    ]);
+
+    let latest: Vec<_> = database
+        .latest_readings()
+        .unwrap()
+        .into_iter()
+        .map(|reading| (reading.station, reading.measurement_time))
+        .collect();
+    assert_eq!(
+        latest,
+        [
+            ("garden".to_string(), 1742070600),
+            ("shed".to_string(), 1742080000)
+        ]
+    );
+}
+
+#[test]
+fn range_includes_start_but_not_end() {
+    let database = database(&[
+        ("garden", payload(1742070600, 21.0)),
+        ("garden", payload(1742070000, 20.0)),
+        ("garden", payload(1742071200, 22.0)),
+        ("garden", payload(1742069400, 19.0)),
+        ("shed", payload(1742070300, 15.0)),
+    ]);
+
+    let times: Vec<i64> = database
+        .readings_in_range("garden", 1742070000, 1742071200)
+        .unwrap()
+        .into_iter()
+        .map(|reading| reading.measurement_time)
+        .collect();
+    assert_eq!(times, [1742070000, 1742070600]);
+    assert!(database
+        .readings_in_range("garden", 1742080000, 1742090000)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn aggregate_summarises_the_range() {
+    let database = database(&[
+        ("garden", payload(1742070000, 20.0)),
+        ("garden", payload(1742070600, 21.0)),
+        ("garden", payload(1742071200, 23.5)),
+        ("garden", payload(1742080000, 30.0)),
+        ("shed", payload(1742070300, 10.0)),
+    ]);
+
+    let aggregate = database
+        .aggregate("garden", 1742070000, 1742080000)
+        .unwrap();
+    assert_eq!(aggregate.reading_count, 3);
+    let temperature = aggregate.temperature_bme.unwrap();
+    assert_eq!(temperature.min, Celsius(20.0));
+    assert_eq!(temperature.max, Celsius(23.5));
+    assert_eq!(temperature.mean, Celsius(21.5));
+    assert_eq!(temperature.count, 3);
+    assert_eq!(aggregate.eco2_sgp30.unwrap().mean, Ppm(450.0));
+    assert!(aggregate.dew_point.is_some());
+    assert_eq!(aggregate.temperature_dht22, None);
+    assert_eq!(aggregate.humidity_dht22, None);
+}
+
+#[test]
+fn aggregate_of_an_empty_range_has_no_stats() {
+    let database = database(&[("garden", payload(1742070000, 20.0))]);
+
+    let aggregate = database.aggregate("garden", 0, 1742070000).unwrap();
+    assert_eq!(aggregate.station, "garden");
+    assert_eq!(aggregate.reading_count, 0);
+    assert_eq!(aggregate.temperature_bme, None);
+    assert_eq!(aggregate.sea_level_pressure, None);
+}
+
+#[test]
+fn clock_status_is_read_back() {
+    // Before `earliest_time`, so from a clock that hadn't synced
+    let database = database(&[("garden", payload(1000, 20.0))]);
+
+    let reading = database.latest_reading("garden").unwrap().unwrap();
+    assert_eq!(reading.measurement_time, 1000);
+    assert_eq!(reading.clock_status, Some(ClockProblem::Unsynced));
+}
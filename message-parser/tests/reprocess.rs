@@ -0,0 +1,326 @@
+//! Tests that archived messages are linked to their readings, and that `reprocess` replaces those
+//! readings, and only those, with what the messages decode to now
+
+mod common;
+
+use common::{config, TempDatabase};
+use message_parser::config::Config;
+use message_parser::database::WeatherDatabase;
+use message_parser::mqtt_message::{
+    ParseError, SensorBatchMessage, SensorMessage, SensorMessageHeader, SensorMessagePayload,
+    CURRENT_VERSION, FLAG_CRC32,
+};
+use message_parser::reprocess::{self, ReprocessError, ReprocessOptions, ReprocessReport};
+use message_parser::store::{MemoryStore, ReadingStore};
+use message_parser::timestamp::parse_iso8601;
+use message_parser::units::FixedPoint;
+
+const GARDEN: &str = "weather/garden/reading";
+const SHED: &str = "weather/shed/reading";
+
+fn payload(posix_time: i64, temperature: f32) -> SensorMessagePayload {
+    SensorMessagePayload {
+        posix_time,
+        bme_temperature: temperature,
+        ..SensorMessagePayload::create_dummy()
+    }
+}
+
+fn reading(sequence: u32, payload: &SensorMessagePayload) -> Vec<u8> {
+    let header = SensorMessageHeader::reading(CURRENT_VERSION, FLAG_CRC32)
+        .unwrap()
+        .with_sequence(sequence);
+    SensorMessage::new(header, payload.clone())
+        .unwrap()
+        .to_bytes()
+}
+
+fn batch(sequence: u32, payloads: &[SensorMessagePayload]) -> Vec<u8> {
+    let header = SensorMessageHeader::batch(CURRENT_VERSION, FLAG_CRC32)
+        .unwrap()
+        .with_sequence(sequence);
+    SensorBatchMessage::new(header, payloads.to_vec())
+        .unwrap()
+        .to_bytes()
+}
+
+/// The BME280 temperatures stored from `station`, in order of measurement
+fn temperatures(database: &WeatherDatabase, station: &str) -> Vec<Option<f32>> {
+    database
+        .readings_in_range(station, 0, i64::MAX)
+        .unwrap()
+        .iter()
+        .map(|reading| reading.temperature_bme.map(FixedPoint::value))
+        .collect()
+}
+
+/// Sets every stored BME280 temperature to 0, as a scaling bug might have
+fn corrupt_temperatures(database: &TempDatabase) {
+    let conn = rusqlite::Connection::open(database.path()).unwrap();
+    conn.execute("UPDATE weather_data SET TemperatureBME = 0", [])
+        .unwrap();
+}
+
+/// The reading count and maximum BME280 temperature of each hour from `station`, in order
+fn hourly(database: &TempDatabase, station: &str) -> Vec<(i64, i64)> {
+    let conn = rusqlite::Connection::open(database.path()).unwrap();
+    let mut stmt = conn
+        .prepare(
+            "SELECT ReadingCount, TemperatureBMEMax FROM weather_hourly
+            WHERE Station = ?1 ORDER BY PeriodStart",
+        )
+        .unwrap();
+    stmt.query_map([station], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+#[test]
+fn reprocessing_replaces_readings_from_archived_messages() {
+    let database = TempDatabase::new("reprocess");
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    let received = parse_iso8601("2025-03-15T20:00:00Z").unwrap();
+    let settings = config();
+    let settings = settings.ingest_settings("garden");
+
+    // A reading, a repeat of it that was dropped, and a batch, as the parser stores them
+    let first = payload(received - 600, 12.5);
+    weather_database
+        .archive_message(GARDEN, received, &reading(1, &first))
+        .unwrap();
+    weather_database
+        .insert_sensor_data("garden", &settings, &first)
+        .unwrap();
+    weather_database
+        .archive_message(GARDEN, received + 5, &reading(1, &first))
+        .unwrap();
+    let buffered = [payload(received, 13.0), payload(received + 600, 13.5)];
+    weather_database
+        .archive_message(GARDEN, received + 900, &batch(2, &buffered))
+        .unwrap();
+    weather_database
+        .insert_sensor_batch("garden", &settings, &buffered)
+        .unwrap();
+    // Stored without a message, as if imported
+    weather_database
+        .insert_sensor_data("garden", &settings, &payload(received + 1200, 14.0))
+        .unwrap();
+    assert_eq!(weather_database.raw_messages(0, i64::MAX).unwrap().len(), 3);
+
+    corrupt_temperatures(&database);
+    assert_eq!(
+        temperatures(&weather_database, "garden"),
+        [Some(0.0), Some(0.0), Some(0.0), Some(0.0)]
+    );
+
+    let report = reprocess::reprocess(
+        &mut weather_database,
+        &config(),
+        &ReprocessOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        report,
+        ReprocessReport {
+            messages: 2,
+            replaced: 3,
+            inserted: 3,
+            dead_letters: 0,
+            rejected: 1,
+            skipped: 0,
+        }
+    );
+    assert_eq!(
+        temperatures(&weather_database, "garden"),
+        [Some(12.5), Some(13.0), Some(13.5), Some(0.0)]
+    );
+    let stored = weather_database
+        .readings_in_range("garden", 0, i64::MAX)
+        .unwrap();
+    assert_eq!(stored[0].received_time, received);
+    assert_eq!(stored[1].received_time, received + 900);
+
+    // The rollups are rebuilt from the replaced readings, counting each once
+    assert_eq!(hourly(&database, "garden"), [(1, 125), (3, 135)]);
+
+    // Reprocessing again changes nothing
+    let again = reprocess::reprocess(
+        &mut weather_database,
+        &config(),
+        &ReprocessOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(again, report);
+    assert_eq!(
+        temperatures(&weather_database, "garden"),
+        [Some(12.5), Some(13.0), Some(13.5), Some(0.0)]
+    );
+}
+
+#[test]
+fn replacing_readings_leaves_the_rollups_until_rebuilt() {
+    let database = TempDatabase::new("replace-rollups");
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    let received = parse_iso8601("2025-03-15T20:30:00Z").unwrap();
+    let config = config();
+    let settings = config.ingest_settings("garden");
+    let first = payload(received - 60, 12.5);
+    weather_database
+        .archive_message(GARDEN, received, &reading(1, &first))
+        .unwrap();
+    weather_database
+        .insert_sensor_data("garden", &settings, &first)
+        .unwrap();
+    assert_eq!(hourly(&database, "garden"), [(1, 125)]);
+
+    let message = &weather_database.raw_messages(0, i64::MAX).unwrap()[0];
+    let replaced = weather_database
+        .replace_readings(
+            message,
+            "garden",
+            &settings,
+            &[payload(received - 60, 13.0)],
+            false,
+        )
+        .unwrap();
+    weather_database.flush().unwrap();
+    assert_eq!(replaced, 1);
+    assert_eq!(hourly(&database, "garden"), [(1, 125)]);
+
+    weather_database.rebuild_rollups().unwrap();
+    assert_eq!(hourly(&database, "garden"), [(1, 130)]);
+}
+
+#[test]
+fn only_the_messages_asked_for_are_reprocessed() {
+    let database = TempDatabase::new("reprocess-options");
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    let received = parse_iso8601("2025-03-15T20:00:00Z").unwrap();
+    let config = config();
+    for (index, topic) in [GARDEN, SHED, GARDEN].into_iter().enumerate() {
+        let time = received + index as i64 * 3600;
+        let payload = payload(time, 10.0 + index as f32);
+        let station = config.route(topic).0;
+        weather_database
+            .archive_message(topic, time, &reading(index as u32 + 1, &payload))
+            .unwrap();
+        weather_database
+            .insert_sensor_data(station, &config.ingest_settings(station), &payload)
+            .unwrap();
+    }
+    corrupt_temperatures(&database);
+
+    let options = ReprocessOptions::parse(&[
+        "--station".to_string(),
+        "garden".to_string(),
+        "--until".to_string(),
+        "2025-03-15T21:30:00Z".to_string(),
+    ])
+    .unwrap();
+    let report = reprocess::reprocess(&mut weather_database, &config, &options).unwrap();
+    assert_eq!((report.messages, report.skipped), (1, 1));
+    assert_eq!(
+        temperatures(&weather_database, "garden"),
+        [Some(10.0), Some(0.0)]
+    );
+    assert_eq!(temperatures(&weather_database, "shed"), [Some(0.0)]);
+
+    // Messages the current config rejects keep their readings
+    let strict = Config {
+        require_hmac: true,
+        ..Config::default()
+    };
+    let report =
+        reprocess::reprocess(&mut weather_database, &strict, &ReprocessOptions::default()).unwrap();
+    assert_eq!((report.messages, report.rejected), (0, 3));
+    assert_eq!(
+        temperatures(&weather_database, "garden"),
+        [Some(10.0), Some(0.0)]
+    );
+
+    assert!(matches!(
+        ReprocessOptions::parse(&["--since".to_string(), "yesterday".to_string()]),
+        Err(ReprocessError::Usage(_))
+    ));
+}
+
+#[test]
+fn sequences_are_checked_against_the_messages_before_since() {
+    let mut weather_database = WeatherDatabase::new(":memory:").unwrap();
+    weather_database.migrate("default").unwrap();
+    let received = parse_iso8601("2025-03-15T20:00:00Z").unwrap();
+    let config = config();
+    let settings = config.ingest_settings("garden");
+
+    // A reading from each station, and an hour later a repeat of the garden's that was dropped
+    let first = payload(received - 30, 12.5);
+    weather_database
+        .archive_message(GARDEN, received, &reading(7, &first))
+        .unwrap();
+    weather_database
+        .insert_sensor_data("garden", &settings, &first)
+        .unwrap();
+    weather_database
+        .archive_message(SHED, received + 60, &reading(3, &first))
+        .unwrap();
+    weather_database
+        .archive_message(GARDEN, received + 3600, &reading(7, &first))
+        .unwrap();
+    weather_database
+        .archive_message(
+            SHED,
+            received + 3660,
+            &reading(4, &payload(received + 3600, 8.0)),
+        )
+        .unwrap();
+
+    let options = ReprocessOptions {
+        since: Some(received + 1800),
+        ..ReprocessOptions::default()
+    };
+    let report = reprocess::reprocess(&mut weather_database, &config, &options).unwrap();
+    assert_eq!((report.messages, report.rejected), (1, 1));
+    assert_eq!(temperatures(&weather_database, "garden"), [Some(12.5)]);
+    assert_eq!(temperatures(&weather_database, "shed"), [Some(8.0)]);
+}
+
+#[test]
+fn dead_letters_are_deleted_once_their_messages_decode() {
+    let mut weather_database = WeatherDatabase::new(":memory:").unwrap();
+    weather_database.migrate("default").unwrap();
+    let received = parse_iso8601("2025-03-15T20:00:00Z").unwrap();
+    let data = reading(1, &payload(received - 30, 12.5));
+    weather_database
+        .archive_message(GARDEN, received, &data)
+        .unwrap();
+    weather_database
+        .insert_dead_letter(GARDEN, received, &data, &ParseError::NoHmacKey)
+        .unwrap();
+
+    let report = reprocess::reprocess(
+        &mut weather_database,
+        &config(),
+        &ReprocessOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        (report.messages, report.inserted, report.dead_letters),
+        (1, 1, 1)
+    );
+    assert_eq!(temperatures(&weather_database, "garden"), [Some(12.5)]);
+    assert!(weather_database
+        .dead_letters(i64::MIN, i64::MAX)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn memory_store_keeps_archived_messages() {
+    let mut store = MemoryStore::new();
+    store.archive_message(GARDEN, &[1, 2, 3]).unwrap();
+    assert_eq!(store.raw_messages, [(GARDEN.to_string(), vec![1, 2, 3])]);
+}
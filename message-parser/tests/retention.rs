@@ -0,0 +1,165 @@
+//! Tests that pruning deletes only expired rows, a batch at a time and on its schedule, and then
+//! releases the freed pages a few at a time
+
+mod common;
+
+use common::{payload, TempDatabase, CLOCK};
+use message_parser::database::{IngestSettings, WeatherDatabase};
+use message_parser::mqtt_message::TelemetryPayload;
+use message_parser::retention::{Pruner, RetentionConfig};
+use message_parser::timestamp::now;
+
+const DAY: i64 = 86400;
+
+const SETTINGS: IngestSettings = IngestSettings {
+    altitude: None,
+    clock: &CLOCK,
+};
+
+fn count(database: &TempDatabase, table: &str) -> i64 {
+    let conn = rusqlite::Connection::open(database.path()).unwrap();
+    conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+        row.get(0)
+    })
+    .unwrap()
+}
+
+fn free_pages(database: &TempDatabase) -> i64 {
+    let conn = rusqlite::Connection::open(database.path()).unwrap();
+    conn.pragma_query_value(None, "freelist_count", |row| row.get(0))
+        .unwrap()
+}
+
+/// Readings measured `days_ago`, an hour apart
+fn store_readings(database: &mut WeatherDatabase, days_ago: i64, count: i64) {
+    let start = now() - days_ago * DAY;
+    let payloads: Vec<_> = (0..count)
+        .map(|index| payload(start + index * 3600, 12.5))
+        .collect();
+    database
+        .insert_sensor_batch("garden", &SETTINGS, &payloads)
+        .unwrap();
+}
+
+fn store_telemetry(database: &mut WeatherDatabase, days_ago: i64) {
+    let telemetry = TelemetryPayload {
+        posix_time: now() - days_ago * DAY,
+        battery_millivolts: 3712,
+        wifi_rssi: -67,
+        reset_reason: 5,
+        wake_count: 1042,
+        firmware_version: "1.4.0".to_string(),
+    };
+    database
+        .insert_late_telemetry("garden", &telemetry, telemetry.posix_time)
+        .unwrap();
+}
+
+#[test]
+fn expired_rows_are_deleted_in_batches() {
+    let database = TempDatabase::new("retention");
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    store_readings(&mut weather_database, 10, 5);
+    store_readings(&mut weather_database, 1, 1);
+    for days_ago in [40, 10, 1] {
+        weather_database
+            .archive_message("weather/garden/reading", now() - days_ago * DAY, &[1, 2, 3])
+            .unwrap();
+        store_telemetry(&mut weather_database, days_ago);
+    }
+    weather_database.flush().unwrap();
+    let hourly = count(&database, "weather_hourly");
+
+    let mut pruner = Pruner::new(&RetentionConfig {
+        raw_days: Some(7),
+        raw_messages_days: Some(30),
+        batch_size: 2,
+        ..RetentionConfig::default()
+    });
+    let mut readings = Vec::new();
+    for _ in 0..3 {
+        assert!(pruner.step(&mut weather_database).unwrap());
+        readings.push(count(&database, "weather_data"));
+    }
+    assert_eq!(readings, [4, 2, 1]);
+    while pruner.step(&mut weather_database).unwrap() {}
+
+    assert_eq!(count(&database, "weather_data"), 1);
+    assert_eq!(count(&database, "raw_messages"), 2);
+    // Tables without a retention period are kept whole
+    assert_eq!(count(&database, "device_telemetry"), 3);
+    assert_eq!(count(&database, "weather_hourly"), hourly);
+}
+
+#[test]
+fn pruning_runs_on_its_schedule() {
+    let database = TempDatabase::new("retention-schedule");
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    let config = RetentionConfig {
+        telemetry_days: Some(7),
+        ..RetentionConfig::default()
+    };
+
+    // The first run is due straight away, the next a day later
+    let mut daily = Pruner::new(&config);
+    store_telemetry(&mut weather_database, 10);
+    while daily.step(&mut weather_database).unwrap() {}
+    assert_eq!(count(&database, "device_telemetry"), 0);
+    store_telemetry(&mut weather_database, 10);
+    assert!(!daily.step(&mut weather_database).unwrap());
+    assert_eq!(count(&database, "device_telemetry"), 1);
+
+    let mut constant = Pruner::new(&RetentionConfig {
+        interval_hours: 0,
+        ..config
+    });
+    while constant.step(&mut weather_database).unwrap() {}
+    store_telemetry(&mut weather_database, 10);
+    assert!(constant.step(&mut weather_database).unwrap());
+    assert_eq!(count(&database, "device_telemetry"), 0);
+
+    // Without any retention periods, nothing is ever deleted
+    let mut forever = Pruner::new(&RetentionConfig::default());
+    store_telemetry(&mut weather_database, 10000);
+    assert!(!forever.step(&mut weather_database).unwrap());
+    forever.run(&mut weather_database).unwrap();
+    assert_eq!(count(&database, "device_telemetry"), 1);
+}
+
+#[test]
+fn freed_pages_are_vacuumed_a_few_at_a_time() {
+    let database = TempDatabase::new("retention-vacuum");
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    store_readings(&mut weather_database, 200, 3000);
+    weather_database.flush().unwrap();
+
+    let mut pruner = Pruner::new(&RetentionConfig {
+        raw_days: Some(7),
+        hourly_days: Some(7),
+        daily_days: Some(7),
+        vacuum_pages: 5,
+        ..RetentionConfig::default()
+    });
+    let mut steps = 0;
+    let mut free = Vec::new();
+    while pruner.step(&mut weather_database).unwrap() {
+        steps += 1;
+        free.push(free_pages(&database));
+    }
+    assert_eq!(count(&database, "weather_data"), 0);
+    assert_eq!(free_pages(&database), 0);
+
+    // Once the last batch is deleted, each step releases at most five pages
+    let most = free
+        .iter()
+        .position(|&pages| pages == *free.iter().max().unwrap());
+    let vacuumed = &free[most.unwrap()..];
+    assert!(vacuumed.len() > 2, "{:?}", free);
+    for pages in vacuumed.windows(2) {
+        assert!(pages[0] - pages[1] <= 5, "{:?}", free);
+    }
+    assert!(steps > vacuumed.len());
+}
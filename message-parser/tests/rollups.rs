@@ -0,0 +1,150 @@
+//! Tests that readings are summarised into the hourly and daily rollups as they are stored, and that
+//! rebuilding the rollups from `weather_data` gives the same summaries
+
+mod common;
+
+use common::{payload, TempDatabase, CLOCK};
+use message_parser::database::{IngestSettings, WeatherDatabase};
+use message_parser::mqtt_message::VALID_SGP30;
+use message_parser::timestamp::{format_iso8601, parse_iso8601};
+use rusqlite::types::Value;
+
+const SETTINGS: IngestSettings = IngestSettings {
+    altitude: None,
+    clock: &CLOCK,
+};
+
+/// Readings from "garden" over two hours of 2025-03-15 and two of 2025-03-16, one of them with the
+/// BME280 failed
+fn store_readings(database: &mut WeatherDatabase) {
+    for (time, temperature) in [
+        ("2025-03-15T23:10:00Z", 10.0),
+        ("2025-03-15T23:40:00Z", 12.5),
+        ("2025-03-16T00:20:00Z", 14.0),
+        ("2025-03-16T02:10:00Z", 8.0),
+    ] {
+        database
+            .insert_sensor_data(
+                "garden",
+                &SETTINGS,
+                &payload(parse_iso8601(time).unwrap(), temperature),
+            )
+            .unwrap();
+    }
+    let mut failed = payload(parse_iso8601("2025-03-16T00:50:00Z").unwrap(), 0.0);
+    failed.valid = VALID_SGP30;
+    database
+        .insert_sensor_data("garden", &SETTINGS, &failed)
+        .unwrap();
+    database.flush().unwrap();
+}
+
+/// The period start, reading count and BME280 temperature summary of each period of `table`
+fn temperatures(
+    database: &TempDatabase,
+    table: &str,
+) -> Vec<(String, i64, Value, Value, Value, i64)> {
+    let conn = rusqlite::Connection::open(database.path()).unwrap();
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT PeriodStart, ReadingCount, TemperatureBMEMin, TemperatureBMEMax,
+            TemperatureBMEMean, TemperatureBMECount FROM {} ORDER BY PeriodStart",
+            table
+        ))
+        .unwrap();
+    stmt.query_map([], |row| {
+        Ok((
+            format_iso8601(row.get(0)?),
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+        ))
+    })
+    .unwrap()
+    .collect::<Result<_, _>>()
+    .unwrap()
+}
+
+/// Every row of both rollup tables
+fn rollups(database: &TempDatabase) -> Vec<Vec<Value>> {
+    let conn = rusqlite::Connection::open(database.path()).unwrap();
+    let mut rows = Vec::new();
+    for table in ["weather_hourly", "weather_daily"] {
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT * FROM {} ORDER BY Station, PeriodStart",
+                table
+            ))
+            .unwrap();
+        let columns = stmt.column_count();
+        let table_rows = stmt
+            .query_map([], |row| {
+                (0..columns)
+                    .map(|index| row.get(index))
+                    .collect::<Result<Vec<Value>, _>>()
+            })
+            .unwrap();
+        for row in table_rows {
+            rows.push(row.unwrap());
+        }
+    }
+    rows
+}
+
+#[test]
+fn readings_are_summarised_by_hour_and_day() {
+    let database = TempDatabase::new("rollups");
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    store_readings(&mut weather_database);
+
+    let summary = |start: &str, count, min: i64, max: i64, mean: f64, values| {
+        (
+            start.to_string(),
+            count,
+            Value::Integer(min),
+            Value::Integer(max),
+            Value::Real(mean),
+            values,
+        )
+    };
+    assert_eq!(
+        temperatures(&database, "weather_hourly"),
+        [
+            summary("2025-03-15T23:00:00Z", 2, 100, 125, 112.5, 2),
+            summary("2025-03-16T00:00:00Z", 2, 140, 140, 140.0, 1),
+            summary("2025-03-16T02:00:00Z", 1, 80, 80, 80.0, 1),
+        ]
+    );
+    assert_eq!(
+        temperatures(&database, "weather_daily"),
+        [
+            summary("2025-03-15T00:00:00Z", 2, 100, 125, 112.5, 2),
+            summary("2025-03-16T00:00:00Z", 3, 80, 140, 110.0, 2),
+        ]
+    );
+}
+
+#[test]
+fn recorded_rollups_match_a_rebuild() {
+    let database = TempDatabase::new("rollups-rebuild");
+    let mut weather_database = WeatherDatabase::new(database.path()).unwrap();
+    weather_database.migrate("default").unwrap();
+    store_readings(&mut weather_database);
+    weather_database
+        .insert_sensor_batch(
+            "shed",
+            &SETTINGS,
+            &[payload(1742068800, -2.5), payload(1742069400, -3.0)],
+        )
+        .unwrap();
+    weather_database.flush().unwrap();
+
+    let recorded = rollups(&database);
+    assert_eq!(recorded.len(), 7);
+    let rows = weather_database.rebuild_rollups().unwrap();
+    assert_eq!(rows, 7);
+    assert_eq!(rollups(&database), recorded);
+}
@@ -0,0 +1,165 @@
+//! Property tests that `to_bytes` and `from_bytes` agree on the sensor message format
+
+use message_parser::mqtt_message::{
+    MqttMessage, SensorBatchMessage, SensorMessage, SensorMessageHeader, SensorMessagePayload,
+    TelemetryMessage, TelemetryPayload, CURRENT_VERSION, FLAG_CRC32, FLAG_HMAC,
+};
+use proptest::prelude::*;
+
+/// Any payload with finite readings, valid or not
+fn payload() -> impl Strategy<Value = SensorMessagePayload> {
+    (
+        any::<i64>(),
+        prop::array::uniform5(-1.0e6f32..1.0e6),
+        any::<u16>(),
+        any::<u16>(),
+        0u8..16,
+    )
+        .prop_map(
+            |(posix_time, floats, eco2, tvoc, valid)| SensorMessagePayload {
+                posix_time,
+                bme_temperature: floats[0],
+                bme_pressure: floats[1],
+                bme_humidity: floats[2],
+                sgp30_eCO2: eco2,
+                sgp30_TVOC: tvoc,
+                dht22_temperature: floats[3],
+                dht22_humidity: floats[4],
+                valid,
+            },
+        )
+}
+
+/// Any device telemetry
+fn telemetry() -> impl Strategy<Value = TelemetryPayload> {
+    (
+        any::<i64>(),
+        any::<u16>(),
+        any::<i8>(),
+        any::<u8>(),
+        any::<u32>(),
+        "[ -~]{0,16}",
+    )
+        .prop_map(
+            |(
+                posix_time,
+                battery_millivolts,
+                wifi_rssi,
+                reset_reason,
+                wake_count,
+                firmware_version,
+            )| {
+                TelemetryPayload {
+                    posix_time,
+                    battery_millivolts,
+                    wifi_rssi,
+                    reset_reason,
+                    wake_count,
+                    firmware_version,
+                }
+            },
+        )
+}
+
+/// Header flags a message can be sent with
+fn flags() -> impl Strategy<Value = u8> {
+    prop_oneof![Just(0), Just(FLAG_CRC32)]
+}
+
+proptest! {
+    #[test]
+    fn reading_round_trips(payload in payload(), flags in flags(), sequence in any::<u32>()) {
+        let header = SensorMessageHeader::reading(CURRENT_VERSION, flags)
+            .unwrap()
+            .with_sequence(sequence);
+        let message = MqttMessage::Reading(SensorMessage::new(header, payload).unwrap());
+        let decoded = MqttMessage::from_bytes(&message.to_bytes()).unwrap();
+        prop_assert_eq!(decoded.sequence(), Some(sequence));
+        prop_assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn batch_round_trips(
+        payloads in prop::collection::vec(payload(), 0..16),
+        flags in flags(),
+        sequence in any::<u32>(),
+    ) {
+        let header = SensorMessageHeader::batch(CURRENT_VERSION, flags)
+            .unwrap()
+            .with_sequence(sequence);
+        let message = MqttMessage::Batch(SensorBatchMessage::new(header, payloads).unwrap());
+        let decoded = MqttMessage::from_bytes(&message.to_bytes()).unwrap();
+        prop_assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn telemetry_round_trips(telemetry in telemetry(), flags in flags(), sequence in any::<u32>()) {
+        let header = SensorMessageHeader::telemetry(CURRENT_VERSION, flags)
+            .unwrap()
+            .with_sequence(sequence);
+        let message = MqttMessage::Telemetry(TelemetryMessage::new(header, telemetry).unwrap());
+        let decoded = MqttMessage::from_bytes(&message.to_bytes()).unwrap();
+        prop_assert_eq!(decoded.sequence(), Some(sequence));
+        prop_assert_eq!(decoded, message);
+    }
+
+    /// Layouts without a validity bitmask infer it, so only the bytes are compared
+    #[test]
+    fn legacy_layouts_round_trip_byte_exact(payload in payload(), version in 0u8..2) {
+        let header = SensorMessageHeader::reading(version, 0).unwrap();
+        let bytes = SensorMessage::new(header, payload).unwrap().to_bytes();
+        let decoded = SensorMessage::from_bytes(&bytes).unwrap();
+        prop_assert_eq!(decoded.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn decoded_messages_re_encode_to_the_same_bytes(data in prop::collection::vec(any::<u8>(), 0..64)) {
+        if let Ok(message) = MqttMessage::from_bytes(&data) {
+            prop_assert_eq!(message.to_bytes(), data);
+        }
+    }
+
+    #[test]
+    fn checksum_catches_any_bit_flip(payload in payload(), bit in any::<prop::sample::Index>()) {
+        let header = SensorMessageHeader::reading(CURRENT_VERSION, FLAG_CRC32).unwrap();
+        let mut bytes = SensorMessage::new(header, payload).unwrap().to_bytes();
+        let bit = bit.index(bytes.len() * 8);
+        bytes[bit / 8] ^= 1 << (bit % 8);
+        prop_assert!(MqttMessage::from_bytes(&bytes).is_err());
+    }
+}
+
+proptest! {
+    // HMAC pads keys with zeros to the block size, so keys differing only in trailing zeros are the
+    // same key. Keys of one length can't differ that way.
+    #[test]
+    fn hmac_tagged_messages_only_verify_with_the_same_key(
+        payload in payload(),
+        flags in flags(),
+        key in any::<[u8; 32]>(),
+        other_key in any::<[u8; 32]>(),
+    ) {
+        let header = SensorMessageHeader::reading(CURRENT_VERSION, flags | FLAG_HMAC).unwrap();
+        let message = MqttMessage::Reading(SensorMessage::new(header, payload).unwrap());
+        let bytes = message.to_bytes_with_key(Some(&key));
+
+        let decoded = MqttMessage::from_bytes_with_key(&bytes, Some(&key)).unwrap();
+        prop_assert_eq!(decoded, message);
+        prop_assert!(MqttMessage::from_bytes(&bytes).is_err());
+        if other_key != key {
+            prop_assert!(MqttMessage::from_bytes_with_key(&bytes, Some(&other_key)).is_err());
+        }
+    }
+
+    #[test]
+    fn untagged_messages_are_rejected_when_the_station_has_a_key(payload in payload()) {
+        let header = SensorMessageHeader::reading(CURRENT_VERSION, FLAG_CRC32).unwrap();
+        let bytes = SensorMessage::new(header, payload).unwrap().to_bytes();
+        prop_assert!(MqttMessage::from_bytes_with_key(&bytes, Some(b"key")).is_err());
+    }
+}
+
+#[test]
+fn legacy_header_cannot_carry_flags() {
+    assert!(SensorMessageHeader::reading(0, FLAG_CRC32).is_err());
+}
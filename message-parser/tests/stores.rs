@@ -0,0 +1,319 @@
+//! Tests that every `ReadingStore` stores what `WeatherDatabase` does, and that `InfluxStore`
+//! writes line protocol that a mock InfluxDB server receives
+
+mod common;
+
+use common::{payload, CLOCK};
+use message_parser::clock::{ClockConfig, ClockPolicy};
+use message_parser::database::{CommitPolicy, IngestSettings, WeatherDatabase};
+use message_parser::influx::{self, InfluxConfig, InfluxStore};
+use message_parser::mqtt_message::{
+    TelemetryPayload, VALID_DHT22_HUMIDITY, VALID_DHT22_TEMPERATURE,
+};
+use message_parser::reading::Reading;
+use message_parser::store::{MemoryStore, ReadingStore, StoreError};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::thread::{self, JoinHandle};
+
+const SETTINGS: IngestSettings = IngestSettings {
+    altitude: Some(45.0),
+    clock: &CLOCK,
+};
+
+fn telemetry() -> TelemetryPayload {
+    TelemetryPayload {
+        posix_time: 1742068800,
+        battery_millivolts: 3712,
+        wifi_rssi: -67,
+        reset_reason: 5,
+        wake_count: 1042,
+        firmware_version: "1.4.0 \"beta\"".to_string(),
+    }
+}
+
+/// Stores a reading, a batch of two and some telemetry from "garden" through the trait
+fn store_messages(store: &mut impl ReadingStore) -> Result<(), StoreError> {
+    store.insert_sensor_data("garden", &SETTINGS, &payload(1742068800, 12.34))?;
+    store.set_last_sequence("garden", 7)?;
+    store.insert_sensor_batch(
+        "garden",
+        &SETTINGS,
+        &[payload(1742069400, 12.5), payload(1742070000, -3.21)],
+    )?;
+    store.insert_telemetry("garden", &telemetry())?;
+    store.flush()
+}
+
+/// `reading` with its received time cleared, which depends on when the test ran
+fn without_received_time(mut reading: Reading) -> Reading {
+    reading.received_time = 0;
+    reading
+}
+
+/// An HTTP request received by `MockServer`
+struct Request {
+    /// e.g. "POST /api/v2/write?org=home HTTP/1.1"
+    request_line: String,
+    /// Names in lower case
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Answers one HTTP request per status in turn, then returns the requests it received
+struct MockServer {
+    url: String,
+    handle: JoinHandle<Vec<Request>>,
+}
+
+impl MockServer {
+    fn start(statuses: &[u16]) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let statuses = statuses.to_vec();
+        let handle = thread::spawn(move || {
+            statuses
+                .into_iter()
+                .map(|status| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let request = read_request(&mut BufReader::new(&stream));
+                    let body = if status < 300 {
+                        ""
+                    } else {
+                        "{\"message\":\"no\"}"
+                    };
+                    write!(
+                        stream,
+                        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    )
+                    .unwrap();
+                    request
+                })
+                .collect()
+        });
+        MockServer { url, handle }
+    }
+
+    fn requests(self) -> Vec<Request> {
+        self.handle.join().unwrap()
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> Request {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).unwrap();
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        match line.trim_end().split_once(':') {
+            Some((name, value)) => {
+                headers.push((name.to_ascii_lowercase(), value.trim().to_string()))
+            }
+            None => break,
+        }
+    }
+    let length: usize = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .map_or(0, |(_, value)| value.parse().unwrap());
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+    Request {
+        request_line: request_line.trim_end().to_string(),
+        headers,
+        body: String::from_utf8(body).unwrap(),
+    }
+}
+
+fn influx_config(url: &str) -> InfluxConfig {
+    InfluxConfig {
+        url: format!("{}/", url),
+        org: "home".to_string(),
+        bucket: "weather".to_string(),
+        token: "s3cret".to_string(),
+        ..InfluxConfig::default()
+    }
+}
+
+/// Sends everything in one request when flushed
+fn batched() -> CommitPolicy {
+    CommitPolicy {
+        max_rows: 100,
+        max_delay_ms: 60000,
+    }
+}
+
+#[test]
+fn memory_store_keeps_what_sqlite_stores() {
+    let mut database = WeatherDatabase::new(":memory:").unwrap();
+    database.migrate("default").unwrap();
+    let mut memory = MemoryStore::new();
+    store_messages(&mut database).unwrap();
+    store_messages(&mut memory).unwrap();
+
+    let stored: Vec<Reading> = database
+        .readings_in_range("garden", 0, i64::MAX)
+        .unwrap()
+        .into_iter()
+        .map(without_received_time)
+        .collect();
+    let kept: Vec<Reading> = memory
+        .readings
+        .iter()
+        .cloned()
+        .map(without_received_time)
+        .collect();
+    assert_eq!(kept.len(), 3);
+    assert_eq!(kept, stored);
+
+    let telemetry = database.latest_telemetry("garden").unwrap().unwrap();
+    assert_eq!(memory.telemetry[0].telemetry, telemetry.telemetry);
+    assert_eq!(memory.last_sequence("garden").unwrap(), Some(7));
+    assert_eq!(
+        ReadingStore::last_sequence(&database, "garden").unwrap(),
+        Some(7)
+    );
+    assert_eq!(memory.last_sequence("shed").unwrap(), None);
+}
+
+#[test]
+fn readings_are_written_as_line_protocol() {
+    let server = MockServer::start(&[204]);
+    let mut store = InfluxStore::new(&influx_config(&server.url)).with_commit_policy(batched());
+    store_messages(&mut store).unwrap();
+    assert_eq!(store.pending(), 0);
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1, "the points are sent together");
+    let request = &requests[0];
+    assert_eq!(
+        request.request_line,
+        "POST /api/v2/write?org=home&bucket=weather&precision=s HTTP/1.1"
+    );
+    assert_eq!(request.header("authorization"), Some("Token s3cret"));
+
+    let lines: Vec<&str> = request.body.lines().collect();
+    assert_eq!(lines.len(), 4);
+    let fields: Vec<&str> = lines[0].split(' ').collect();
+    assert_eq!(fields[0], "weather_data,station=garden");
+    assert!(
+        fields[1].starts_with("TemperatureBME=12.3,PressureBME=101300,HumidityBME=50,"),
+        "{}",
+        lines[0]
+    );
+    assert!(fields[1].contains(",eCO2SGP30=450,TVOCSGP30=25,DewPoint="));
+    assert!(fields[1].contains(",PressureSeaLevel="));
+    assert!(!fields[1].contains("DHT22"), "failed sensors are left out");
+    assert!(fields[1].contains(",ReceivedTime="));
+    assert_eq!(fields[2], "1742068800");
+    assert!(lines[2].starts_with("weather_data,station=garden TemperatureBME=-3.2,"));
+    assert_eq!(
+        lines[3].split_once(' ').unwrap().0,
+        "device_telemetry,station=garden"
+    );
+    assert!(lines[3].contains(",BatteryMillivolts=3712i,WifiRssi=-67i,ResetReason=5i,"));
+    assert!(lines[3].ends_with(",FirmwareVersion=\"1.4.0 \\\"beta\\\"\" 1742068800"));
+}
+
+#[test]
+fn names_are_escaped_and_clock_problems_written() {
+    let clock = ClockConfig {
+        policy: ClockPolicy::Substitute,
+        ..CLOCK
+    };
+    let settings = IngestSettings {
+        altitude: None,
+        clock: &clock,
+    };
+    let mut payload = payload(1000, 20.0);
+    payload.valid = VALID_DHT22_TEMPERATURE | VALID_DHT22_HUMIDITY;
+    payload.dht22_temperature = 20.0;
+    payload.dht22_humidity = 50.0;
+    let reading =
+        Reading::from_payload("back garden,west=1", 1742068800, &payload, &settings, false);
+    let line = influx::reading_line("weather data", &reading);
+    assert!(
+        line.starts_with(
+            "weather\\ data,station=back\\ garden\\,west\\=1 TemperatureDHT22=20,HumidityDHT22=50,DewPoint="
+        ),
+        "{}",
+        line
+    );
+    assert!(line.ends_with(",ReceivedTime=1742068800i,ClockStatus=\"unsynced\" 1742068800"));
+
+    // Backslashes are escaped, and line breaks would end the point early so are dropped
+    let reading = Reading::from_payload("shed\\2\r\n", 1742068800, &payload, &settings, false);
+    let line = influx::reading_line("weather_data", &reading);
+    assert!(
+        line.starts_with("weather_data,station=shed\\\\2 TemperatureDHT22=20,"),
+        "{}",
+        line
+    );
+    assert_eq!(line.lines().count(), 1);
+}
+
+#[test]
+fn points_are_kept_until_influxdb_accepts_them() {
+    let server = MockServer::start(&[503, 204]);
+    let mut store = InfluxStore::new(&influx_config(&server.url));
+    let error = store
+        .insert_sensor_data("garden", &SETTINGS, &payload(1742068800, 12.0))
+        .unwrap_err();
+    assert!(matches!(error, StoreError::Rejected { status: 503, .. }));
+    assert_eq!(store.pending(), 1);
+
+    store
+        .insert_sensor_data("garden", &SETTINGS, &payload(1742069400, 12.5))
+        .unwrap();
+    assert_eq!(store.pending(), 0);
+    let requests = server.requests();
+    assert_eq!(requests[0].body.lines().count(), 1);
+    assert_eq!(requests[1].body.lines().count(), 2);
+}
+
+#[test]
+fn points_influxdb_refuses_are_dropped() {
+    let server = MockServer::start(&[400, 204]);
+    let mut store = InfluxStore::new(&influx_config(&server.url));
+    match store.insert_telemetry("garden", &telemetry()) {
+        Err(StoreError::Rejected { status, message }) => {
+            assert_eq!(status, 400);
+            assert_eq!(message, "{\"message\":\"no\"}");
+        }
+        result => panic!("expected a rejection, got {:?}", result),
+    }
+    assert_eq!(store.pending(), 0);
+
+    store
+        .insert_sensor_data("garden", &SETTINGS, &payload(1742068800, 12.0))
+        .unwrap();
+    assert_eq!(server.requests()[1].body.lines().count(), 1);
+}
+
+#[test]
+fn unreachable_servers_are_connection_errors() {
+    // Nothing listens on the port once the listener is dropped
+    let url = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    };
+    let mut store = InfluxStore::new(&influx_config(&url));
+    assert!(matches!(
+        store.insert_telemetry("garden", &telemetry()),
+        Err(StoreError::Connection(_))
+    ));
+    assert_eq!(store.pending(), 1);
+}
@@ -0,0 +1,43 @@
+//! Tests the unit conversions against known values
+
+use message_parser::units::{Celsius, Pascal};
+
+fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+    assert!(
+        (actual - expected).abs() <= tolerance,
+        "{} is not within {} of {}",
+        actual,
+        tolerance,
+        expected
+    );
+}
+
+#[test]
+fn temperatures_convert_to_fahrenheit_and_kelvin() {
+    for (celsius, fahrenheit) in [(0.0, 32.0), (100.0, 212.0), (37.0, 98.6), (-40.0, -40.0)] {
+        assert_close(Celsius(celsius).fahrenheit(), fahrenheit, 0.001);
+        assert_close(Celsius::from_fahrenheit(fahrenheit).0, celsius, 0.001);
+    }
+    assert_close(Celsius(0.0).kelvin(), 273.15, 0.001);
+    assert_close(Celsius(-273.15).kelvin(), 0.0, 0.001);
+}
+
+#[test]
+fn pressures_convert_to_hectopascals_and_inches_of_mercury() {
+    // The standard atmosphere at sea level
+    assert_close(Pascal(101325.0).hectopascals(), 1013.25, 0.001);
+    assert_close(Pascal::from_hectopascals(1013.25).0, 101325.0, 0.01);
+    assert_close(Pascal(101325.0).inches_of_mercury(), 29.92, 0.005);
+    assert_close(Pascal::from_inches_of_mercury(29.92).0, 101325.0, 20.0);
+    // An inch of mercury is 33.8639 hPa
+    assert_close(
+        Pascal::from_inches_of_mercury(1.0).hectopascals(),
+        33.8639,
+        0.0001,
+    );
+    assert_close(
+        Pascal::from_hectopascals(1000.0).inches_of_mercury(),
+        29.53,
+        0.005,
+    );
+}